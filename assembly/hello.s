@@ -1,5 +1,5 @@
 // Print hello
-org 0
+org 0x200
 
 
 main:
@@ -5,6 +5,8 @@ use super::registers::Register;
 pub enum ArgType {
     BCD(&'static str),
     Font(&'static str),
+    HighFont(&'static str),
+    Flags(&'static str),
     IndexRegister(&'static str),
     Register(Register),
     Number(u16),
@@ -22,6 +24,8 @@ impl ArgType {
             "b" => Ok(ArgType::BCD("b")),
             "i" => Ok(ArgType::IndexRegister("i")),
             "f" => Ok(ArgType::Font("f")),
+            "hf" => Ok(ArgType::HighFont("hf")),
+            "r" => Ok(ArgType::Flags("r")),
             "dt" => Ok(ArgType::DelayTimer("dt")),
             "st" => Ok(ArgType::SoundTimer("st")),
             arg if arg.starts_with("v") => {
@@ -32,14 +36,12 @@ impl ArgType {
             },
             _ => {
                 let num = arg.trim_start_matches("0x");
+                // the opcode checks the number fits in its field
                 let number = match u16::from_str_radix(num, 16) {
                     Ok(n) => n,
                     Err(e) => return Err(format!("Error parsing number: {}", e)),
                 };
 
-                if number > 0xFFF {
-                    return Err(format!("Number out of range: 0x{:X}", number));
-                }
                 Ok(ArgType::Number(number))
             },
         };
@@ -78,6 +80,16 @@ mod tests {
                 arg: "f",
                 expected: Ok(ArgType::Font("f")),
             },
+            TestCase {
+                name: "High resolution font",
+                arg: "hf",
+                expected: Ok(ArgType::HighFont("hf")),
+            },
+            TestCase {
+                name: "Flags",
+                arg: "r",
+                expected: Ok(ArgType::Flags("r")),
+            },
             TestCase {
                 name: "Delay timer",
                 arg: "dt",
@@ -99,9 +111,14 @@ mod tests {
                 expected: Ok(ArgType::Number(0x200)),
             },
             TestCase {
-                name: "Invalid number",
+                name: "Number wider than an address",
                 arg: "0x1000",
-                expected: Err("Number out of range: 0x1000".into()),
+                expected: Ok(ArgType::Number(0x1000)),
+            },
+            TestCase {
+                name: "Invalid number",
+                arg: "0x10000",
+                expected: Err("Error parsing number: number too large to fit in target type".into()),
             },
             TestCase {
                 name: "Invalid register",
@@ -1,217 +1,588 @@
 use log::{debug, info};
 
-use std::{fs::File, io::{self, BufRead, Write}};
+use std::{collections::{HashMap, HashSet}, fs::{self, File}, io::{self, BufRead, Write}, path::PathBuf};
 
 use super::token::{Token, TokenType};
 use super::origin::get_origin;
-use super::labels::get_labels;
+use super::labels::{get_labels, scope_labels};
 use super::opcodes;
+use super::data::{data_bytes, is_data};
+use super::expr::{evaluate, is_anonymous, resolve_arg};
+use super::constants::get_constants;
+use super::include::{find_include, incbin_args, include_name};
+use super::macros::expand_macros;
+use super::sprite::expand_sprites;
+use super::image::{load_image, tile_bytes, tile_tokens, tiles_from_args};
+use super::listing::{write_listing, write_symbols, Entry};
+use super::diagnostic::{Code, Diagnostic, Span};
+use super::lexer::{self, lex, Kind};
+use super::octo::{is_octo, parse_octo};
+use super::target::Target;
+use super::conditions::apply_conditions;
+use super::flow::expand_flow;
+use super::optimise::optimise;
+
+const MEMORY_SIZE: usize = 4096;
+
+// the tokens, origin, labels, constants and the tokens that did not resolve
+type Resolved = (Vec<Token>, u16, HashMap<String, u16>, HashMap<String, u16>, HashSet<usize>);
+
+// AssemblerConfig
+pub struct AssemblerConfig {
+    pub source: String,
+    pub target: String,
+    pub full_image: bool,  // write all of memory with the program at its origin
+    pub include_paths: Vec<String>,  // searched for include, incbin and incpng files
+    pub listing: Option<String>,  // file to write a listing of the program to
+    pub symbols: Option<String>,  // file to write labels and source lines to
+    pub platform: Target,  // instructions outside the platform are rejected
+    pub defines: Vec<(String, String)>,  // -D constants for conditional assembly
+    pub optimise: bool,  // run the peephole optimiser over the program
+}
 
-pub fn assemble(source: String, target: String) -> Result<(), String> {
+impl AssemblerConfig {
+
+    pub fn new() -> Self {
+        AssemblerConfig {
+            source: "".to_string(),
+            target: "output.ch8".to_string(),
+            full_image: false,
+            include_paths: Vec::new(),
+            listing: None,
+            symbols: None,
+            platform: Target::Chip8,
+            defines: Vec::new(),
+            optimise: false,
+        }
+    }
 
+}
 
-    let tokens = parse_file(source)?;
+// Build is an assembled program, with the entries of its listing
+pub struct Build {
+    pub origin: u16,
+    pub program: Vec<u8>,
+    pub entries: Vec<Entry>,
+    pub labels: HashMap<String, u16>,
+    pub constants: HashMap<String, u16>,
+    pub diagnostics: Vec<Diagnostic>,  // warnings
+    pub saved: usize,  // bytes removed by -O
+}
 
-    for token in &tokens {
-        debug!("{}", token.to_string())
+// assemble writes the program of config.source to config.target, and returns
+// the warnings and the bytes the optimiser saved, or the errors and warnings,
+// found on the way
+pub fn assemble(config: AssemblerConfig) -> Result<(Vec<Diagnostic>, usize), Vec<Diagnostic>> {
+    let Build { origin, mut program, entries, labels, constants, mut diagnostics, saved } = build(&config, config.platform)?;
+
+    let mut errors: Vec<Diagnostic> = Vec::new();
+
+    if let Some(listing) = &config.listing {
+        info!("Writing listing to file: {}", listing);
+        if let Err(e) = write_listing(listing, &entries, &labels, &constants) {
+            errors.push(Diagnostic::new(Code::File, &e));
+        }
     }
 
-    let origin = get_origin(&tokens)?;
-    debug!("Origin: 0x{:X}", origin);
+    if let Some(symbols) = &config.symbols {
+        info!("Writing symbols to file: {}", symbols);
+        if let Err(e) = write_symbols(symbols, &entries, &labels) {
+            errors.push(Diagnostic::new(Code::File, &e));
+        }
+    }
+
+    if config.full_image {
+        match full_image(origin, &program) {
+            Ok(image) => program = image,
+            Err(e) => errors.push(Diagnostic::new(Code::Origin, &e)),
+        }
+    } else if let Err(e) = check_size(origin, &program) {
+        errors.push(Diagnostic::new(Code::Origin, &e));
+    }
+
+    if errors.is_empty() {
+        info!("Writing to file: {}", config.target);
+        if let Err(e) = save(config.target, program) {
+            errors.push(Diagnostic::new(Code::File, &e));
+        }
+    }
+
+    if !errors.is_empty() {
+        diagnostics.extend(errors);
+        return Err(diagnostics)
+    }
+
+    Ok((diagnostics, saved))
+}
 
+// build assembles the program of config.source for platform, without writing
+// anything
+pub fn build(config: &AssemblerConfig, platform: Target) -> Result<Build, Vec<Diagnostic>> {
+    let tokens = parse_file(&config.source, &config.include_paths)?;
+    let tokens = apply_conditions(tokens, &config.defines)?;
+    let tokens = expand_sprites(tokens)?;
+    let tokens = expand_macros(tokens)?;
+    let tokens = expand_flow(tokens)?;
+    let mut tokens = scope_labels(tokens)?;
+
+    let mut saved = 0;
+    if config.optimise {
+        (tokens, saved) = optimise(tokens);
+    }
 
-    let mut opcodes = new_opcodes(origin)?;
-    debug!("Opcodes: {:?}",
-        opcodes.iter().map(|x| format!("{:X}", x)).collect::<Vec<String>>());
+    for token in &tokens {
+        debug!("{}", token.to_string())
+    }
 
-    let labels = get_labels(&tokens, origin)?;
+    // the lines that do not resolve are reported, the rest are still
+    // assembled so their errors are found too
+    let ((tokens, origin, labels, constants, unresolved), mut diagnostics) = resolve(tokens);
+    debug!("Origin: 0x{:X}", origin);
     for (label, address) in labels.iter() {
         debug!("Label: {} Address: 0x{:X}", label, address);
     }
 
-    
-    let mut errors: Vec<String> = Vec::new();
+    let mut program: Vec<u8> = Vec::new();
+    let mut entries: Vec<Entry> = Vec::new();
+
+    let mut code_started = false;
+    let mut aligned = true;
 
     // parse out 
-   for token in tokens {
+   for (i, token) in tokens.into_iter().enumerate() {
+       let address = origin.wrapping_add(program.len() as u16);
+       let start = program.len();
+       if unresolved.contains(&i) {
+           // keep its room, so the addresses after it are right
+           program.resize(start + token.size().unwrap_or(0) as usize, 0);
+           continue;
+       }
        match  token.token_type {
+           TokenType::Origin if code_started => {
+               diagnostics.push(token.error(Code::LateOrigin,
+                   "org after the start of the program, the whole program is placed at the origin"));
+           },
            TokenType::Instruction => {
+               code_started = true;
+
+               // only the first of a run of instructions at odd addresses is
+               // reported
+               if address % 2 != 0 && aligned {
+                   diagnostics.push(token.error(Code::OddAddress,
+                       &format!("Instruction at odd address 0x{:03X}", address)));
+               }
+               aligned = address % 2 == 0;
+
+               let required = Target::required(&token.name, &token.args);
+               if required > platform {
+                   diagnostics.push(token.error(Code::Target, &format!(
+                       "{} needs --target {} or later, the target is {}",
+                       token.name, required.name(), platform.name())));
+                   continue;
+               }
 
-               let opcode_result = match token.name.to_lowercase().as_str() {
-                   "add" => opcodes::add(&token.args),
-                   "and" => opcodes::and(&token.args),
-                   "call" => opcodes::call(&labels, &token.args),
-                   "cls" => Ok(0x00E0),
-                   "drw" => opcodes::drw(&token.args),
-                   "jmp" => opcodes::jmp(&labels, &token.args),
-                   "ld" => opcodes::ld(&token.args),
-                   "or" => opcodes::or(&token.args),
-                   "ret" => Ok(0x00EE),
-                   "rnd" => opcodes::rnd(&token.args),
-                   "se" => opcodes::se(&token.args),
-                   "shl" => opcodes::shl(&token.args),
-                   "shr" => opcodes::shr(&token.args),
-                   "sknp" => opcodes::sknp(&token.args),
-                   "skp" => opcodes::skp(&token.args),
-                   "sne" => opcodes::sne(&token.args),
-                   "sub" => opcodes::sub(&token.args),
-                   "subn" => opcodes::subn(&token.args),
-                   "wkp" => opcodes::wkp(&token.args),
-                   "xor" => opcodes::xor(&token.args),
-                   _ => Err(format!("Unknown instruction {}", token.name)),
-               };
-
-               match opcode_result {
-                   Ok(opcode) => {
-                       opcodes.push(opcode);
+               match encode(&token, &labels) {
+                   Some(Ok(opcodes)) => {
+                       for opcode in opcodes {
+                           program.extend_from_slice(&opcode.to_be_bytes());
+                       }
+                   },
+                   Some(Err(e)) => {
+                       diagnostics.push(token.error(Code::Operand, &e));
+                   },
+                   None => {
+                       let span = token.spans.first().copied().unwrap_or_default();
+                       diagnostics.push(Diagnostic::new(Code::UnknownInstruction,
+                           &format!("Unknown instruction {}", token.name)).at(&token.file, token.line, span));
+                       continue;
+                   },
+               }
+
+           },
+           TokenType::Data => {
+               code_started = true;
+               match data_bytes(&labels, &token.name, &token.args) {
+                   Ok(bytes) => {
+                       program.extend_from_slice(&bytes);
                    },
                    Err(e) => {
-                       errors.push(format!("Error on line {}: {}", token.line, e));
+                       diagnostics.push(token.error(Code::Operand, &e));
                    }
                }
-
            },
            _ => {}
        }
+       entries.push(Entry { address, bytes: program[start..].to_vec(), token });
    } 
 
-    debug!("Opcodes: {:?}",
-        opcodes.iter().map(|x| format!("{:X}", x)).collect::<Vec<String>>());
+    debug!("Program: {:?}",
+        program.iter().map(|x| format!("{:02X}", x)).collect::<Vec<String>>());
 
-    if errors.len() > 0 {
-        return Err(errors.join("\n"))
+    if diagnostics.iter().any(|diagnostic| !diagnostic.is_warning()) {
+        return Err(diagnostics)
     }
 
-    info!("Writing to file: {}", target);
-    save(target, opcodes)?;
+    Ok(Build { origin, program, entries, labels, constants, diagnostics, saved })
+}
 
-    Ok(())
+// encode returns the opcodes of an instruction, most are one word and ld i
+// long is two. None is an unknown instruction.
+fn encode(token: &Token, labels: &HashMap<String, u16>) -> Option<Result<Vec<u16>, String>> {
+    let args = &token.args;
+    let opcode = match token.name.to_lowercase().as_str() {
+        "add" => opcodes::add(args),
+        "and" => opcodes::and(args),
+        "audio" => Ok(0xF002),
+        "call" => opcodes::call(labels, args),
+        "cls" => Ok(0x00E0),
+        "drw" => opcodes::drw(args),
+        "exit" => Ok(0x00FD),
+        "high" => Ok(0x00FF),
+        "jmp" => opcodes::jmp(labels, args),
+        "ld" if opcodes::is_long(args) => return Some(opcodes::ld_long(labels, args).map(|opcodes| opcodes.to_vec())),
+        "ld" => opcodes::ld(labels, args),
+        "load" => opcodes::save_range("load", args),
+        "low" => Ok(0x00FE),
+        "or" => opcodes::or(args),
+        "plane" => opcodes::plane(args),
+        "ret" => Ok(0x00EE),
+        "rnd" => opcodes::rnd(args),
+        "save" => opcodes::save_range("save", args),
+        "scd" => opcodes::scroll("scd", args),
+        "scl" => Ok(0x00FC),
+        "scr" => Ok(0x00FB),
+        "scu" => opcodes::scroll("scu", args),
+        "se" => opcodes::se(args),
+        "shl" => opcodes::shl(args),
+        "shr" => opcodes::shr(args),
+        "sknp" => opcodes::sknp(args),
+        "skp" => opcodes::skp(args),
+        "sne" => opcodes::sne(args),
+        "sub" => opcodes::sub(args),
+        "subn" => opcodes::subn(args),
+        "wkp" => opcodes::wkp(args),
+        "xor" => opcodes::xor(args),
+        _ => return None,
+    };
+    Some(opcode.map(|opcode| vec![opcode]))
 }
 
-fn save(target: String, opcodes: Vec<u16>) -> Result<(), String> {
+// source_lines returns the address of every instruction in config.source,
+// along with its token, so tools can map addresses back to source lines. It
+// builds the program the way assemble does, so the addresses match.
+pub fn source_lines(config: &AssemblerConfig) -> Result<Vec<(u16, Token)>, Vec<Diagnostic>> {
+    let build = build(config, config.platform)?;
+    Ok(build.entries.into_iter()
+        .filter(|entry| matches!(entry.token.token_type, TokenType::Instruction))
+        .map(|entry| (entry.address, entry.token))
+        .collect())
+}
+
+// resolve evaluates constants and operand expressions, and returns the tokens
+// with every expression replaced by its value, the origin and the labels,
+// along with the errors. Constants that do not use labels are evaluated first,
+// so they can set the origin and the size of reserved data before the labels
+// are placed.
+fn resolve(mut tokens: Vec<Token>) -> (Resolved, Vec<Diagnostic>) {
+    let mut constants: HashMap<String, u16> = HashMap::new();
+    for token in &tokens {
+        if let TokenType::Constant = token.token_type {
+            if let Some(Ok(value)) = token.args.first().map(|arg| evaluate(arg, &constants)) {
+                constants.insert(token.name.clone(), value);
+            }
+        }
+    }
+
+    let mut errors: Vec<Diagnostic> = Vec::new();
+    let no_labels = HashMap::new();
+    for token in tokens.iter_mut() {
+        let sizes = match token.token_type {
+            TokenType::Origin => true,
+            TokenType::Data => matches!(token.name.to_lowercase().as_str(), "ds" | "fill"),
+            _ => false,
+        };
+        if sizes {
+            resolve_token(token, &no_labels, &constants, &mut errors);
+        }
+    }
+
+    let (origin, origin_errors) = get_origin(&tokens);
+    errors.extend(origin_errors);
+    if let Err(e) = check_origin(origin) {
+        errors.push(Diagnostic::new(Code::Origin, &e));
+    }
+
+    let (labels, label_errors) = get_labels(&tokens, origin);
+    errors.extend(label_errors);
+    let (constants, constant_errors) = get_constants(&tokens, &labels);
+    errors.extend(constant_errors);
+
+    let mut unresolved: HashSet<usize> = HashSet::new();
+    for (i, token) in tokens.iter_mut().enumerate() {
+        if let TokenType::Instruction | TokenType::Data = token.token_type {
+            if !resolve_token(token, &labels, &constants, &mut errors) {
+                unresolved.insert(i);
+            }
+        }
+    }
+
+    ((tokens, origin, labels, constants, unresolved), errors)
+}
+
+// resolve_token replaces the expressions in the arguments of token with their
+// values, and returns false if any could not be evaluated
+fn resolve_token(token: &mut Token, labels: &HashMap<String, u16>, constants: &HashMap<String, u16>, errors: &mut Vec<Diagnostic>) -> bool {
+    let mut resolved = true;
+    for i in 0..token.args.len() {
+        match resolve_arg(&token.args[i], labels, constants) {
+            Ok(value) => token.args[i] = value,
+            Err(e) => {
+                errors.push(token.arg_error(i, Code::Expression, &e));
+                resolved = false;
+            },
+        }
+    }
+    resolved
+}
+
+fn save(target: String, program: Vec<u8>) -> Result<(), String> {
     let mut file = match File::create(target) {
         Ok(file) => file,
         Err(e) => return Err(format!("Error creating file: {}", e)),
     };
 
-    for opcode in opcodes {
-        match file.write_all(&opcode.to_be_bytes()) {
-            Ok(_) => {},
-            Err(e) => return Err(format!("Error writing to file: {}", e)),
-        }
+    match file.write_all(&program) {
+        Ok(_) => {},
+        Err(e) => return Err(format!("Error writing to file: {}", e)),
     }
 
     Ok(())
 }
 
-// new_opcodes creates a vector of u16 opcodes with a length of origin
-fn new_opcodes(origin: u16) -> Result<Vec<u16>, String> {
+// check_origin makes sure the program can be loaded at origin
+fn check_origin(origin: u16) -> Result<(), String> {
     if origin % 2 != 0 {
         return Err("Origin must be even".to_string())
     }
-    let num_opcodes = origin / 2;
-    let opcodes = vec![0; num_opcodes as usize];
-    Ok(opcodes)
+    Ok(())
+}
+
+// check_size makes sure the program fits in memory when loaded at origin
+fn check_size(origin: u16, program: &[u8]) -> Result<(), String> {
+    let end = origin as usize + program.len();
+    if end > MEMORY_SIZE {
+        return Err(format!(
+            "Program does not fit in memory: ends at 0x{:X}, memory ends at 0x{:X}",
+            end, MEMORY_SIZE))
+    }
+    Ok(())
+}
+
+// full_image places the program at origin in an image of all of memory
+fn full_image(origin: u16, program: &[u8]) -> Result<Vec<u8>, String> {
+    check_size(origin, program)?;
+    let start = origin as usize;
+    let end = start + program.len();
+
+    let mut image = vec![0; MEMORY_SIZE];
+    image[start..end].copy_from_slice(program);
+    Ok(image)
+}
+
+// Read file into tokens, following includes. Octo sources are translated.
+fn parse_file(source: &str, include_paths: &[String]) -> Result<Vec<Token>, Vec<Diagnostic>> {
+    if is_octo(source) {
+        return parse_octo(source)
+    }
+
+    let mut stack: Vec<(PathBuf, String)> = Vec::new();
+    parse_source(source, include_paths, &mut stack)
 }
 
-// Read file into tokens
-fn parse_file(source: String) -> Result<Vec<Token>, String> {
+// parse_source reads one source file into tokens, stack holds the files being
+// included so include cycles can be found
+fn parse_source(source: &str, include_paths: &[String], stack: &mut Vec<(PathBuf, String)>) -> Result<Vec<Token>, Vec<Diagnostic>> {
+
+    let file = match File::open(source) {
+        Ok(file) => file,
+        Err(e) => return Err(vec![Diagnostic::new(Code::File, &format!("Error opening {}: {}", source, e))]),
+    };
+    let path = fs::canonicalize(source).unwrap_or(PathBuf::from(source));
+    stack.push((path, source.to_string()));
 
-    let file = File::open(source).unwrap();
     let reader = io::BufReader::new(file);
 
-    let mut errors: Vec<String> = Vec::new();
+    let mut errors: Vec<Diagnostic> = Vec::new();
     let mut tokens: Vec<Token> = Vec::new();
 
-    for (i, line) in reader.lines().enumerate() {
+    for (i, text) in reader.lines().enumerate() {
+        let number = i + 1;
 
-        let line = match line {
-            Ok(line) => line,
+        let text = match text {
+            Ok(text) => text,
             Err(e) => {
-                errors.push(format!("Error on line {}: error reading line: {}", i, e));
+                errors.push(Diagnostic::new(Code::File, &format!("error reading line: {}", e))
+                    .at(source, number, Span::default()));
                 continue;
             }
         };
 
-        if errors.len() > 0 {
-            return Err(errors.join("\n"))
-        }
-
-        let line = line.trim();
-
-        // Ignore empty lines and comments
-        if line.is_empty() || line.starts_with("//") {
-            continue;
-        }
-
-
-        // split line on whitespace
-        let parts: Vec<&str> = line.split_whitespace().collect();
-
+        let lexemes = match lex(&text) {
+            Ok(lexemes) => lexemes,
+            Err((e, at)) => {
+                errors.push(Diagnostic::new(Code::Syntax, &e).at(source, number, at));
+                continue;
+            }
+        };
 
-        let mut args: Vec<String> = Vec::new();
-        if parts.len() > 1 {
-            args = parts[1..].iter().map(|s| s.to_string()).collect();
+        // a label, name: or an anonymous + or -, may come before a statement
+        let mut rest = &lexemes[..];
+        let label = match rest {
+            [name, colon, ..] if name.kind == Kind::Word && colon.kind == Kind::Colon => Some((name, 2)),
+            [anonymous, colon, ..] if is_anonymous(anonymous.text) && colon.kind == Kind::Colon => Some((anonymous, 2)),
+            [anonymous] if is_anonymous(anonymous.text) => Some((anonymous, 1)),
+            [anonymous, next, ..] if is_anonymous(anonymous.text) && next.space && next.kind == Kind::Word => Some((anonymous, 1)),
+            _ => None,
+        };
+        if let Some((name, skip)) = label {
+            tokens.push(Token {
+                name: name.text.to_string(),
+                token_type: TokenType::Label,
+                line: number,
+                args: Vec::new(),
+                file: source.to_string(),
+                spans: vec![lexer::span(&text, name.start, name.start + name.text.len())],
+            });
+            rest = &rest[skip..];
         }
 
-        let name;
-        let token_type: TokenType;
-
-        match parts[0] {
-            "org" => {
-                name = "org";
-                token_type = TokenType::Origin;
+        let first = match rest.first() {
+            Some(first) if matches!(first.kind, Kind::Word | Kind::Number) => first,
+            Some(first) => {
+                errors.push(Diagnostic::new(Code::Syntax, &format!("Expected an instruction, got {}", first.text))
+                    .at(source, number, lexer::span(&text, first.start, first.start + first.text.len())));
+                continue;
             },
-            label if parts[0].ends_with(':') => {
-                name = label.trim_end_matches(':');
-                token_type =  TokenType::Label;
+            None => continue,
+        };
+
+        let (name, token_type, operands) = match rest {
+            [directive, ..] if ["include", "incbin", "incpng"].iter().any(|name| directive.text.eq_ignore_ascii_case(name)) => {
+                let end = rest[rest.len() - 1].start + rest[rest.len() - 1].text.len();
+                let at = lexer::span(&text, directive.start, end);
+                let included = lexer::args(&text, &rest[1..])
+                    .map_err(|(e, at)| vec![Diagnostic::new(Code::Syntax, &e).at(source, number, at)])
+                    .and_then(|args| {
+                        let args: Vec<String> = args.into_iter().map(|arg| arg.text).collect();
+                        include(source, number, at, directive.text, &args, include_paths, stack)
+                    });
+                match included {
+                    Ok(included) => tokens.extend(included),
+                    Err(e) => errors.extend(e),
+                }
+                continue;
             },
-            _ => {
-                name = parts[0];
-                token_type = TokenType::Instruction;
+            [org, ..] if org.text.eq_ignore_ascii_case("org") => (org, TokenType::Origin, &rest[1..]),
+            [define, name, ..] if define.text.eq_ignore_ascii_case("define") => (name, TokenType::Constant, &rest[2..]),
+            [name, equ, ..] if equ.text.eq_ignore_ascii_case("equ") => (name, TokenType::Constant, &rest[2..]),
+            [data, ..] if is_data(data.text) => (data, TokenType::Data, &rest[1..]),
+            _ => (first, TokenType::Instruction, &rest[1..]),
+        };
+
+        let args = match lexer::args(&text, operands) {
+            Ok(args) => args,
+            Err((e, at)) => {
+                errors.push(Diagnostic::new(Code::Syntax, &e).at(source, number, at));
+                Vec::new()
             }
-        }
+        };
+
+        let mut spans = vec![lexer::span(&text, name.start, name.start + name.text.len())];
+        spans.extend(args.iter().map(|arg| arg.span));
 
-        let name = name.to_string();
         tokens.push(Token{
-            name,
+            name: name.text.to_string(),
             token_type,
-            line: i,
-            args,
+            line: number,
+            args: args.into_iter().map(|arg| arg.text).collect(),
+            file: source.to_string(),
+            spans,
         });
 
-        if errors.len() > 0 {
-            return Err(errors.join("\n"))
-        }
+    }
 
+    stack.pop();
+
+    if !errors.is_empty() {
+        return Err(errors)
     }
 
     Ok(tokens)
 }
 
+// include returns the tokens for an include, incbin or incpng line of source,
+// at is the columns of the line
+fn include(source: &str, line: usize, at: Span, directive: &str, args: &[String], include_paths: &[String], stack: &mut Vec<(PathBuf, String)>) -> Result<Vec<Token>, Vec<Diagnostic>> {
+    let error = |code: Code| move |e: String| vec![Diagnostic::new(code, &e).at(source, line, at)];
+
+    let image = directive.eq_ignore_ascii_case("incpng");
+    let (file_args, image_args) = args.split_at(if image { args.len().min(1) } else { args.len() });
+    let name = include_name(directive, file_args).map_err(error(Code::Operand))?;
+    let path = find_include(&name, source, include_paths).map_err(error(Code::File))?;
+
+    if image {
+        let tiles = tiles_from_args(image_args).map_err(error(Code::Operand))?;
+        let image = load_image(&path, tiles.planes).map_err(error(Code::File))?;
+        let bytes = tile_bytes(&image, &tiles).map_err(error(Code::Operand))?;
+        return Ok(tile_tokens(&tiles.name, &bytes, line, source))
+    }
 
+    if directive.eq_ignore_ascii_case("incbin") {
+        let args = incbin_args(&path).map_err(error(Code::File))?;
+        if args.is_empty() {
+            return Ok(Vec::new())
+        }
+        return Ok(vec![Token {
+            name: "db".to_string(),
+            token_type: TokenType::Data,
+            line,
+            args,
+            file: source.to_string(),
+            spans: vec![at],
+        }])
+    }
 
+    let canonical = fs::canonicalize(&path).unwrap_or(PathBuf::from(&path));
+    if stack.iter().any(|(included, _)| *included == canonical) {
+        let mut cycle: Vec<String> = stack.iter().map(|(_, name)| name.clone()).collect();
+        cycle.push(path);
+        return Err(error(Code::File)(format!("Include cycle: {}", cycle.join(" -> "))))
+    }
+
+    parse_source(&path, include_paths, stack)
+}
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use super::super::diagnostic::messages;
+    use std::path::Path;
 
     #[test]
-    fn test_new_opcodes() {
+    fn test_check_origin() {
         struct TestCase {
             origin: u16,
-            expected: Result<Vec<u16>, String>,
+            expected: Result<(), String>,
         }
 
         let test_cases = [
             TestCase {
                 origin: 0x200,
-                // 0x200 / 2 = 0x100 -- 2 bytes per opcode
-                expected: Ok(vec![0; 0x100 as usize]),
+                expected: Ok(()),
             },
             TestCase {
                 origin: 0x2,
-                expected: Ok(vec![0; 0x1 as usize]),
+                expected: Ok(()),
             },
             TestCase {
                 origin: 0x201,
@@ -220,13 +591,292 @@ mod tests {
         ];
 
         for case in test_cases.iter() {
-            assert_eq!(new_opcodes(case.origin), case.expected);
+            assert_eq!(check_origin(case.origin), case.expected);
+        }
+
+    }
+
+    #[test]
+    fn test_full_image() {
+        struct TestCase {
+            name: &'static str,
+            origin: u16,
+            program: Vec<u8>,
+            expected: Result<Vec<u8>, String>,
+        }
+
+        let test_cases = [
+            TestCase {
+                name: "Program at 0x200",
+                origin: 0x200,
+                program: vec![0x00, 0xE0, 0x12, 0x00],
+                expected: Ok({
+                    let mut image = vec![0; 0x1000];
+                    image[0x200..0x204].copy_from_slice(&[0x00, 0xE0, 0x12, 0x00]);
+                    image
+                }),
+            },
+            TestCase {
+                name: "Program fills memory",
+                origin: 0xFFE,
+                program: vec![0x1F, 0xFE],
+                expected: Ok({
+                    let mut image = vec![0; 0x1000];
+                    image[0xFFE..].copy_from_slice(&[0x1F, 0xFE]);
+                    image
+                }),
+            },
+            TestCase {
+                name: "Odd sized data",
+                origin: 0x200,
+                program: vec![0x20, 0x70, 0x20],
+                expected: Ok({
+                    let mut image = vec![0; 0x1000];
+                    image[0x200..0x203].copy_from_slice(&[0x20, 0x70, 0x20]);
+                    image
+                }),
+            },
+            TestCase {
+                name: "Program too large",
+                origin: 0xFFE,
+                program: vec![0x00, 0xE0, 0x1F, 0xFE],
+                expected: Err(
+                    "Program does not fit in memory: ends at 0x1002, memory ends at 0x1000".to_string()),
+            },
+        ];
+
+        for case in test_cases.iter() {
+            assert_eq!(full_image(case.origin, &case.program), case.expected,
+                "Failed on test case: {}", case.name);
         }
+    }
+
+    #[test]
+    fn test_parse_file() {
+        let dir = std::env::temp_dir().join(format!("chip8-parse-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("test.s").to_string_lossy().to_string();
+        fs::write(&source, concat!(
+            "; header comment\n",
+            "SPEED equ 2  # pixels a frame\n",
+            "main: cls\n",
+            "loop:  ld v0, (SPEED + 1) * 2 // speed\n",
+            "  drw v3, v4, 0x3 ; draw\n",
+            "- jmp -\n",
+            "text: db \"A; B\", '#', 0\n",
+            "done:\n",
+        )).unwrap();
+
+        let tokens: Vec<(usize, String, Vec<String>)> = parse_file(&source, &[]).unwrap().into_iter()
+            .map(|token| (token.line, token.name, token.args))
+            .collect();
+        let token = |line: usize, name: &str, args: &[&str]| -> (usize, String, Vec<String>) {
+            (line, name.to_string(), args.iter().map(|arg| arg.to_string()).collect())
+        };
+        assert_eq!(tokens, vec![
+            token(2, "SPEED", &["2"]),
+            token(3, "main", &[]),
+            token(3, "cls", &[]),
+            token(4, "loop", &[]),
+            token(4, "ld", &["v0", "(SPEED + 1) * 2"]),
+            token(5, "drw", &["v3", "v4", "0x3"]),
+            token(6, "-", &[]),
+            token(6, "jmp", &["-"]),
+            token(7, "text", &[]),
+            token(7, "db", &["\"A; B\"", "'#'", "0"]),
+            token(8, "done", &[]),
+        ]);
+
+        fs::write(&source, "ORG 0x300\n").unwrap();
+        let tokens = parse_file(&source, &[]).unwrap();
+        assert!(matches!(tokens[0].token_type, TokenType::Origin), "ORG is read as org");
+
+        fs::write(&source, "main: , cls\n  ld v0 $20\n").unwrap();
+        let errors = messages(&parse_file(&source, &[]).err().unwrap());
+        assert_eq!(errors, format!(concat!(
+            "{0}:1:7: error[E001]: Expected an instruction, got ,\n",
+            "{0}:2:9: error[E001]: Unexpected character $"), source));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
 
+    #[test]
+    fn test_parse_file_includes() {
+        let dir = std::env::temp_dir().join(format!("chip8-include-{}", std::process::id()));
+        let lib = dir.join("lib");
+        fs::create_dir_all(&lib).unwrap();
+        fs::write(dir.join("main.s"), "include \"engine.s\"\ninclude \"sprites.s\"\ncls\n").unwrap();
+        fs::write(dir.join("engine.s"), "ret\n").unwrap();
+        fs::write(lib.join("sprites.s"), "sprite:\nincbin \"sprite.bin\"\nincpng \"glyphs.png\" glyph 8 1\n").unwrap();
+        fs::write(lib.join("sprite.bin"), [0x20, 0x70]).unwrap();
+        let mut encoder = png::Encoder::new(fs::File::create(lib.join("glyphs.png")).unwrap(), 8, 2);
+        encoder.set_color(png::ColorType::Grayscale);
+        encoder.set_depth(png::BitDepth::One);
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(&[0xF0, 0x0F]).unwrap();
+        writer.finish().unwrap();
+        fs::write(dir.join("a.s"), "include \"b.s\"\n").unwrap();
+        fs::write(dir.join("b.s"), "cls\ninclude \"a.s\"\n").unwrap();
+
+        let main = dir.join("main.s").to_string_lossy().to_string();
+        let lib_path = lib.to_string_lossy().to_string();
+
+        let tokens = parse_file(&main, &[lib_path]).unwrap();
+        let names: Vec<(String, String, Vec<String>)> = tokens.iter()
+            .map(|token| (
+                Path::new(&token.file).file_name().unwrap().to_string_lossy().to_string(),
+                token.name.clone(),
+                token.args.clone()))
+            .collect();
+        assert_eq!(names, vec![
+            ("engine.s".to_string(), "ret".to_string(), vec![]),
+            ("sprites.s".to_string(), "sprite".to_string(), vec![]),
+            ("sprites.s".to_string(), "db".to_string(), vec!["0x20".to_string(), "0x70".to_string()]),
+            ("sprites.s".to_string(), "glyph".to_string(), vec![]),
+            ("sprites.s".to_string(), "glyph_0".to_string(), vec![]),
+            ("sprites.s".to_string(), "db".to_string(), vec!["0xF0".to_string()]),
+            ("sprites.s".to_string(), "glyph_1".to_string(), vec![]),
+            ("sprites.s".to_string(), "db".to_string(), vec!["0x0F".to_string()]),
+            ("main.s".to_string(), "cls".to_string(), vec![]),
+        ]);
+
+        let missing = messages(&parse_file(&main, &[]).err().unwrap());
+        assert_eq!(missing, format!("{}:2:1: error[E006]: Cannot find included file sprites.s", main));
+
+        let a = dir.join("a.s").to_string_lossy().to_string();
+        let b = dir.join("b.s").to_string_lossy().to_string();
+        let cycle = messages(&parse_file(&a, &[]).err().unwrap());
+        assert_eq!(cycle, format!("{}:2:1: error[E006]: Include cycle: {} -> {} -> {}", b, a, b, a));
+
+        fs::remove_dir_all(&dir).unwrap();
     }
 
-    // #[test]
-    // fn test_parse_file() {
-    // }
+    #[test]
+    fn test_source_lines() {
+        let dir = std::env::temp_dir().join(format!("chip8-lines-{}", std::process::id()));
+        let lib = dir.join("lib");
+        fs::create_dir_all(&lib).unwrap();
+        fs::write(dir.join("main.s"), "ifdef FAST\n  cls\nendif\ninclude \"engine.s\"\nmain: jmp main\n").unwrap();
+        fs::write(lib.join("engine.s"), "  ret\n").unwrap();
+
+        let mut config = AssemblerConfig::new();
+        config.source = dir.join("main.s").to_string_lossy().to_string();
+        config.include_paths = vec![lib.to_string_lossy().to_string()];
+        config.defines = vec![("FAST".to_string(), "1".to_string())];
+
+        let lines: Vec<(u16, String, usize)> = source_lines(&config).unwrap().into_iter()
+            .map(|(address, token)| (address, token.name, token.line))
+            .collect();
+        assert_eq!(lines, vec![
+            (0x200, "cls".to_string(), 2),
+            (0x202, "ret".to_string(), 1),
+            (0x204, "jmp".to_string(), 5),
+        ]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_assemble_diagnostics() {
+        struct TestCase {
+            name: &'static str,
+            source: &'static str,
+            expected: Result<Vec<&'static str>, Vec<&'static str>>,
+        }
+
+        let test_cases = [
+            TestCase {
+                name: "Every syntax error",
+                source: "start:\n  ld v0 (1\n  ld v1 \"a\n",
+                expected: Err(vec![
+                    "2:6: error[E001]: Unbalanced parentheses in v0 (1",
+                    "3:9: error[E001]: Unterminated string \"a",
+                ]),
+            },
+            TestCase {
+                name: "Every undefined name",
+                source: "start:\n  ld v0 nowhere\n  jmp start\n  ld i sprit\n",
+                expected: Err(vec![
+                    "2:9: error[E004]: Undefined label or constant nowhere",
+                    "4:8: error[E004]: Undefined label or constant sprit",
+                ]),
+            },
+            TestCase {
+                name: "Duplicate label and unknown instruction",
+                source: "start:\n  cls\nstart:\n  jump start\n",
+                expected: Err(vec![
+                    "3:1: error[E005]: Label start already defined in {} on line 1",
+                    "4:3: error[E002]: Unknown instruction jump",
+                ]),
+            },
+            TestCase {
+                name: "Errors after an undefined name",
+                source: "  ld v0 nowhere\n  ld v1 0x300\nSPEED equ later\n  jump 0x200\n",
+                expected: Err(vec![
+                    "3:11: error[E004]: Undefined label or constant later",
+                    "1:9: error[E004]: Undefined label or constant nowhere",
+                    "2:3: error[E003]: Invalid NN for ld: expected 0x0 to 0xFF, got 0x300",
+                    "4:3: error[E002]: Unknown instruction jump",
+                ]),
+            },
+            TestCase {
+                name: "Unknown instruction and bad operand",
+                source: "  jump 0x200\n  ld v0 0x300\n",
+                expected: Err(vec![
+                    "1:3: error[E002]: Unknown instruction jump",
+                    "2:3: error[E003]: Invalid NN for ld: expected 0x0 to 0xFF, got 0x300",
+                ]),
+            },
+            TestCase {
+                name: "Instructions the target does not have",
+                source: "  scd 4\n  drw v0 v1 0\n  ld i long 0x1234\n  drw v0 v1 4\n",
+                expected: Err(vec![
+                    "1:3: error[E009]: scd needs --target schip or later, the target is chip8",
+                    "2:3: error[E009]: drw needs --target schip or later, the target is chip8",
+                    "3:3: error[E009]: ld needs --target xochip or later, the target is chip8",
+                ]),
+            },
+            TestCase {
+                name: "Program past the end of memory",
+                source: "  cls\n  ds 5000\n",
+                expected: Err(vec![
+                    "error[E008]: Program does not fit in memory: ends at 0x158A, memory ends at 0x1000",
+                ]),
+            },
+            TestCase {
+                name: "Warnings",
+                source: "  db 0x1\n  cls\n  ret\n  org 0x200\n",
+                expected: Ok(vec![
+                    "2:3: warning[W001]: Instruction at odd address 0x201",
+                    "4:3: warning[W002]: org after the start of the program, the whole program is placed at the origin",
+                ]),
+            },
+        ];
+
+        let dir = std::env::temp_dir().join(format!("chip8-diagnostics-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("test.s").to_string_lossy().to_string();
+
+        for case in test_cases {
+            fs::write(&source, case.source).unwrap();
+            let mut config = AssemblerConfig::new();
+            config.source = source.clone();
+            config.target = dir.join("test.ch8").to_string_lossy().to_string();
+
+            let lines = |diagnostics: Vec<Diagnostic>| -> Vec<String> {
+                diagnostics.iter()
+                    .map(|diagnostic| diagnostic.to_string().replacen(&format!("{}:", source), "", 1))
+                    .collect()
+            };
+            let expected = |lines: Vec<&str>| -> Vec<String> {
+                lines.iter().map(|line| line.replace("{}", &source)).collect()
+            };
+            let result = assemble(config).map(|(warnings, _)| lines(warnings)).map_err(lines);
+            assert_eq!(result, case.expected.map(expected).map_err(expected),
+                "Failed on test case: {}", case.name);
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
 
 }
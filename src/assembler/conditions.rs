@@ -0,0 +1,287 @@
+use super::token::{Token, TokenType};
+use super::expr::{evaluate, is_name};
+use super::diagnostic::{Code, Diagnostic};
+use super::flow::is_flow_if;
+use std::collections::HashMap;
+
+// Conditional assembly
+//
+// ifdef DEBUG
+//   call draw_counters
+// endif
+//
+// if TARGET == VIP
+//   ld v0 0x10
+// else
+//   ld v0 0x20
+// endif
+//
+// Lines between if, ifdef or ifndef and the matching else or endif are only
+// assembled when the condition holds. if takes an expression over constants,
+// true when it is not 0. ifdef and ifndef test whether a constant is defined.
+// Conditions see the constants defined before them, and the -D NAME=value
+// defines of the command line, which the program can use as constants too.
+//
+// An if ending in then tests registers while the program runs instead, it and
+// its else and endif are left to the structured control flow of flow.rs.
+
+// file of the tokens made for command line defines
+const DEFINES: &str = "<command line>";
+
+// Condition is an if waiting for its endif
+struct Condition {
+    token: Token,
+    outer: bool,  // the lines around the if are assembled
+    holds: bool,
+    has_else: bool,
+    runtime: bool,  // an if ... then, kept for expand_flow
+}
+
+// apply_conditions drops the tokens of conditions that do not hold, defines
+// are NAME and value pairs from the command line
+pub fn apply_conditions(tokens: Vec<Token>, defines: &[(String, String)]) -> Result<Vec<Token>, Vec<Diagnostic>> {
+    let mut program: Vec<Token> = Vec::new();
+    let mut errors: Vec<Diagnostic> = Vec::new();
+    let mut constants: HashMap<String, u16> = HashMap::new();
+    let mut conditions: Vec<Condition> = Vec::new();
+
+    for (name, value) in defines {
+        let token = Token {
+            name: name.clone(),
+            token_type: TokenType::Constant,
+            args: vec![value.clone()],
+            line: 0,
+            file: DEFINES.to_string(),
+            spans: Vec::new(),
+        };
+        if !is_name(name) {
+            errors.push(Diagnostic::new(Code::Syntax, &format!("Invalid name for -D: {}", name)));
+            continue;
+        }
+        match evaluate(value, &constants) {
+            Ok(value) => {
+                constants.insert(name.clone(), value);
+                program.push(token);
+            },
+            Err(e) => errors.push(Diagnostic::new(Code::Expression, &format!("Invalid value for -D {}: {}", name, e))),
+        }
+    }
+
+    for token in tokens {
+        let active = conditions.last().map(|condition| condition.outer && condition.holds).unwrap_or(true);
+
+        let directive = match token.token_type {
+            TokenType::Instruction => token.name.to_lowercase(),
+            _ => String::new(),
+        };
+
+        match directive.as_str() {
+            "if" if is_flow_if(&token) => {
+                conditions.push(Condition { token: token.clone(), outer: active, holds: true, has_else: false, runtime: true });
+                if active {
+                    program.push(token);
+                }
+            },
+            "if" | "ifdef" | "ifndef" => {
+                let holds = if active {
+                    match condition(&directive, &token, &constants) {
+                        Ok(holds) => holds,
+                        Err(e) => {
+                            errors.push(e);
+                            false
+                        }
+                    }
+                } else {
+                    false
+                };
+                conditions.push(Condition { token, outer: active, holds, has_else: false, runtime: false });
+            },
+            "else" => match conditions.last_mut() {
+                Some(condition) if condition.runtime => {
+                    if condition.outer {
+                        program.push(token);
+                    }
+                },
+                Some(condition) if !condition.has_else => {
+                    condition.has_else = true;
+                    condition.holds = !condition.holds;
+                },
+                Some(_) => errors.push(token.error(Code::Block, "else after else")),
+                None => errors.push(token.error(Code::Block, "else without if")),
+            },
+            "endif" => match conditions.pop() {
+                Some(condition) if condition.runtime => {
+                    if condition.outer {
+                        program.push(token);
+                    }
+                },
+                Some(_) => {},
+                None => errors.push(token.error(Code::Block, "endif without if")),
+            },
+            _ if !active => {},
+            _ => {
+                if let TokenType::Constant = token.token_type {
+                    if let Some(Ok(value)) = token.args.first().map(|arg| evaluate(arg, &constants)) {
+                        constants.insert(token.name.clone(), value);
+                    }
+                }
+                program.push(token);
+            },
+        }
+    }
+
+    for condition in conditions {
+        errors.push(condition.token.error(Code::Block, &format!("Missing endif for {}", condition.token.name)));
+    }
+
+    if !errors.is_empty() {
+        return Err(errors)
+    }
+
+    Ok(program)
+}
+
+// condition returns whether the if, ifdef or ifndef of token holds
+fn condition(directive: &str, token: &Token, constants: &HashMap<String, u16>) -> Result<bool, Diagnostic> {
+    if token.args.len() != 1 {
+        return Err(token.error(Code::Syntax, &format!(
+            "Invalid number of arguments for {}: expected 1, got {}", token.name, token.args.len())))
+    }
+
+    let arg = &token.args[0];
+    match directive {
+        "if" => evaluate(arg, constants)
+            .map(|value| value != 0)
+            .map_err(|e| token.arg_error(0, Code::Expression, &e)),
+        _ if !is_name(arg) => Err(token.arg_error(0, Code::Syntax, &format!("Invalid name for {}: {}", token.name, arg))),
+        "ifdef" => Ok(constants.contains_key(arg)),
+        _ => Ok(!constants.contains_key(arg)),
+    }
+}
+
+// parse_define reads the NAME=value of -D, a define without a value is 1
+pub fn parse_define(define: &str) -> (String, String) {
+    match define.split_once('=') {
+        Some((name, value)) => (name.trim().to_string(), value.trim().to_string()),
+        None => (define.trim().to_string(), "1".to_string()),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::diagnostic::messages;
+    use super::super::token::fixtures::{instruction, token};
+
+    #[test]
+    fn test_apply_conditions() {
+        struct TestCase {
+            name: &'static str,
+            tokens: Vec<Token>,
+            defines: Vec<(String, String)>,
+            expected: Result<Vec<&'static str>, String>,
+        }
+
+        let test_cases = [
+            TestCase {
+                name: "ifdef with a command line define",
+                tokens: vec![
+                    instruction("ifdef", &["DEBUG"], 1),
+                    instruction("call", &["counters"], 2),
+                    instruction("endif", &[], 3),
+                    instruction("cls", &[], 4),
+                ],
+                defines: vec![parse_define("DEBUG")],
+                expected: Ok(vec!["DEBUG", "call counters", "cls"]),
+            },
+            TestCase {
+                name: "ifdef without the define",
+                tokens: vec![
+                    instruction("ifdef", &["DEBUG"], 1),
+                    instruction("call", &["counters"], 2),
+                    instruction("endif", &[], 3),
+                    instruction("cls", &[], 4),
+                ],
+                defines: vec![],
+                expected: Ok(vec!["cls"]),
+            },
+            TestCase {
+                name: "if else on a constant",
+                tokens: vec![
+                    token("TARGET", TokenType::Constant, &["2"], 1),
+                    instruction("if", &["TARGET == 1"], 2),
+                    instruction("ld", &["v0", "0x10"], 3),
+                    instruction("else", &[], 4),
+                    instruction("ld", &["v0", "0x20"], 5),
+                    instruction("endif", &[], 6),
+                ],
+                defines: vec![],
+                expected: Ok(vec!["TARGET", "ld v0 0x20"]),
+            },
+            TestCase {
+                name: "Nested and ifndef",
+                tokens: vec![
+                    instruction("ifndef", &["LEVEL"], 1),
+                    token("LEVEL", TokenType::Constant, &["1"], 2),
+                    instruction("endif", &[], 3),
+                    instruction("if", &["LEVEL > 5"], 4),
+                    instruction("if", &["UNDEFINED"], 5),
+                    instruction("endif", &[], 6),
+                    instruction("else", &[], 7),
+                    instruction("ret", &[], 8),
+                    instruction("endif", &[], 9),
+                ],
+                defines: vec![parse_define("LEVEL = 3")],
+                expected: Ok(vec!["LEVEL", "ret"]),
+            },
+            TestCase {
+                name: "if ... then is left for flow",
+                tokens: vec![
+                    instruction("ifdef", &["DEBUG"], 1),
+                    instruction("if", &["v0 == 1", "then"], 2),
+                    instruction("cls", &[], 3),
+                    instruction("else", &[], 4),
+                    instruction("ret", &[], 5),
+                    instruction("endif", &[], 6),
+                    instruction("else", &[], 7),
+                    instruction("if", &["v0 == 2", "then"], 8),
+                    instruction("endif", &[], 9),
+                    instruction("endif", &[], 10),
+                ],
+                defines: vec![],
+                expected: Ok(vec!["if v0 == 2 then", "endif"]),
+            },
+            TestCase {
+                name: "Errors",
+                tokens: vec![
+                    instruction("if", &["MISSING"], 1),
+                    instruction("endif", &[], 2),
+                    instruction("else", &[], 3),
+                    instruction("ifdef", &[], 4),
+                ],
+                defines: vec![parse_define("1X=2")],
+                expected: Err([
+                    "error[E001]: Invalid name for -D: 1X",
+                    "test.s:1: error[E004]: Undefined label or constant MISSING",
+                    "test.s:3: error[E007]: else without if",
+                    "test.s:4: error[E001]: Invalid number of arguments for ifdef: expected 1, got 0",
+                    "test.s:4: error[E007]: Missing endif for ifdef",
+                ].join("\n")),
+            },
+        ];
+
+        for case in test_cases {
+            let result = apply_conditions(case.tokens, &case.defines)
+                .map(|tokens| tokens.iter().map(|token| match token.token_type {
+                    TokenType::Instruction if !token.args.is_empty() => format!("{} {}", token.name, token.args.join(" ")),
+                    _ => token.name.clone(),
+                }).collect::<Vec<String>>())
+                .map_err(|errors| messages(&errors));
+            let expected = case.expected
+                .map(|names| names.iter().map(|name| name.to_string()).collect::<Vec<String>>());
+            assert_eq!(result, expected, "Failed on test case: {}", case.name);
+        }
+    }
+
+}
@@ -0,0 +1,110 @@
+use super::token::{Token, TokenType};
+use super::expr::evaluate;
+use super::diagnostic::{Code, Diagnostic};
+use std::collections::HashMap;
+
+// get_constants evaluates the equ/define constants in the order they are
+// defined, a constant may use labels and the constants defined before it. It
+// returns the constants that could be evaluated and the errors for the rest.
+pub fn get_constants(tokens: &Vec<Token>, labels: &HashMap<String, u16>) -> (HashMap<String, u16>, Vec<Diagnostic>) {
+
+    let mut constants: HashMap<String, u16> = HashMap::new();
+    let mut symbols = labels.clone();
+    let mut errors: Vec<Diagnostic> = Vec::new();
+
+    for token in tokens {
+        if let TokenType::Constant = token.token_type {
+            if labels.contains_key(&token.name) || constants.contains_key(&token.name) {
+                errors.push(
+                    token.error(Code::Duplicate, &format!("{} already defined.", token.name)));
+                continue;
+            }
+
+            if token.args.len() != 1 {
+                errors.push(
+                    token.error(Code::Syntax, &format!("expected one value for {}, got {}",
+                    token.name, token.args.len())));
+                continue;
+            }
+
+            match evaluate(&token.args[0], &symbols) {
+                Ok(value) => {
+                    constants.insert(token.name.clone(), value);
+                    symbols.insert(token.name.clone(), value);
+                },
+                Err(e) => errors.push(token.arg_error(0, Code::Expression, &e)),
+            }
+        }
+    }
+
+    (constants, errors)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::diagnostic::messages;
+    use super::super::token::fixtures::token;
+
+    #[test]
+    fn test_get_constants() {
+        struct TestCase {
+            name: &'static str,
+            tokens: Vec<Token>,
+            expected: Result<HashMap<String, u16>, String>,
+        }
+
+        let labels = HashMap::from([("sprite".to_string(), 0x2A4)]);
+
+        let test_cases = [
+            TestCase {
+                name: "No constants",
+                tokens: Vec::new(),
+                expected: Ok(HashMap::new()),
+            },
+            TestCase {
+                name: "Constants using constants and labels",
+                tokens: vec![
+                    token("WIDTH", TokenType::Constant, &["64"], 0),
+                    token("CENTER", TokenType::Constant, &["WIDTH / 2"], 1),
+                    token("FRAME", TokenType::Constant, &["sprite + 5"], 2),
+                ],
+                expected: Ok(HashMap::from([
+                    ("WIDTH".to_string(), 64),
+                    ("CENTER".to_string(), 32),
+                    ("FRAME".to_string(), 0x2A9),
+                ])),
+            },
+            TestCase {
+                name: "Constant used before it is defined",
+                tokens: vec![
+                    token("CENTER", TokenType::Constant, &["WIDTH / 2"], 1),
+                    token("WIDTH", TokenType::Constant, &["64"], 2),
+                ],
+                expected: Err("test.s:1: error[E004]: Undefined label or constant WIDTH".into()),
+            },
+            TestCase {
+                name: "Constant already defined",
+                tokens: vec![
+                    token("WIDTH", TokenType::Constant, &["64"], 1),
+                    token("WIDTH", TokenType::Constant, &["32"], 2),
+                    token("sprite", TokenType::Constant, &["0x300"], 3),
+                ],
+                expected: Err([
+                    "test.s:2: error[E005]: WIDTH already defined.",
+                    "test.s:3: error[E005]: sprite already defined.",
+                ].join("\n")),
+            },
+        ];
+
+        for case in test_cases {
+            let result = match get_constants(&case.tokens, &labels) {
+                (constants, errors) if errors.is_empty() => Ok(constants),
+                (_, errors) => Err(messages(&errors)),
+            };
+            assert_eq!(result, case.expected, "Failed on test case: {}", case.name);
+        }
+    }
+
+}
@@ -0,0 +1,267 @@
+use std::collections::HashMap;
+
+// Data directives
+//
+// db 0x20 0x70 "HI" - bytes, strings are stored as ascii
+// dw 0x1234 label   - big endian words, labels are stored as their address
+// ds 0x10 0xFF      - reserve count bytes, filled with an optional value
+// fill 0x10 0xFF    - same as ds
+pub fn is_data(name: &str) -> bool {
+    matches!(name.to_lowercase().as_str(), "db" | "dw" | "ds" | "fill")
+}
+
+// data_size returns the number of bytes a data directive emits
+pub fn data_size(name: &str, args: &Vec<String>) -> Result<u16, String> {
+    let size = match name.to_lowercase().as_str() {
+        "db" => {
+            let mut size = 0;
+            for arg in args {
+                size += match string_from_arg(arg)? {
+                    Some(s) => s.len(),
+                    None => 1,
+                };
+            }
+            size
+        },
+        "dw" => args.len() * 2,
+        "ds" | "fill" => {
+            check_fill_args(name, args)?;
+            number_from_string(&args[0])? as usize
+        },
+        _ => return Err(format!("Unknown data directive {}", name)),
+    };
+
+    if size > 0xFFFF {
+        return Err(format!("Too much data for {}: {} bytes", name, size))
+    }
+
+    Ok(size as u16)
+}
+
+// data_bytes returns the bytes a data directive emits
+pub fn data_bytes(labels: &HashMap<String, u16>, name: &str, args: &Vec<String>) -> Result<Vec<u8>, String> {
+    let mut bytes: Vec<u8> = Vec::new();
+
+    match name.to_lowercase().as_str() {
+        "db" => {
+            if args.is_empty() {
+                return Err("Invalid number of arguments for db: expected at least 1, got 0".into())
+            }
+            for arg in args {
+                match string_from_arg(arg)? {
+                    Some(s) => bytes.extend_from_slice(s.as_bytes()),
+                    None => {
+                        let byte = number_from_string(arg)?;
+                        if byte > 0xFF {
+                            return Err(format!(
+                                "Invalid byte for db: expected 0xFF or less, got {}", arg))
+                        }
+                        bytes.push(byte as u8);
+                    },
+                }
+            }
+        },
+        "dw" => {
+            if args.is_empty() {
+                return Err("Invalid number of arguments for dw: expected at least 1, got 0".into())
+            }
+            for arg in args {
+                let word = match labels.get(arg) {
+                    Some(address) => *address,
+                    None => number_from_string(arg)?,
+                };
+                bytes.extend_from_slice(&word.to_be_bytes());
+            }
+        },
+        "ds" | "fill" => {
+            check_fill_args(name, args)?;
+            let count = number_from_string(&args[0])?;
+            let value = match args.get(1) {
+                Some(value) => number_from_string(value)?,
+                None => 0,
+            };
+            if value > 0xFF {
+                return Err(format!(
+                    "Invalid fill value for {}: expected 0xFF or less, got {}", name, args[1]))
+            }
+            bytes = vec![value as u8; count as usize];
+        },
+        _ => return Err(format!("Unknown data directive {}", name)),
+    }
+
+    Ok(bytes)
+}
+
+fn check_fill_args(name: &str, args: &[String]) -> Result<(), String> {
+    if args.is_empty() || args.len() > 2 {
+        return Err(format!(
+            "Invalid number of arguments for {}: expected 1 or 2, got {}",
+            name, args.len()))
+    }
+    Ok(())
+}
+
+// number_from_string parses a hex number, with or without 0x
+fn number_from_string(s: &str) -> Result<u16, String> {
+    let number = s.trim_start_matches("0x");
+    match u16::from_str_radix(number, 16) {
+        Ok(number) => Ok(number),
+        Err(e) => Err(format!("Error parsing number {}: {}", s, e)),
+    }
+}
+
+// string_from_arg returns the contents of a quoted string argument, or None
+// if the argument is not a string
+fn string_from_arg(arg: &str) -> Result<Option<String>, String> {
+    if !arg.starts_with('"') {
+        return Ok(None)
+    }
+
+    if arg.len() < 2 || !arg.ends_with('"') {
+        return Err(format!("Unterminated string {}", arg))
+    }
+
+    let mut s = String::new();
+    let mut chars = arg[1..arg.len() - 1].chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            s.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => s.push('\n'),
+            Some('0') => s.push('\0'),
+            Some('"') => s.push('"'),
+            Some('\\') => s.push('\\'),
+            Some(c) => return Err(format!("Unknown escape \\{} in string {}", c, arg)),
+            None => return Err(format!("Unterminated string {}", arg)),
+        }
+    }
+
+    if !s.is_ascii() {
+        return Err(format!("String {} is not ascii", arg))
+    }
+
+    Ok(Some(s))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_data_size() {
+        struct TestCase {
+            name: &'static str,
+            directive: &'static str,
+            args: Vec<String>,
+            expected: Result<u16, String>,
+        }
+
+        let test_cases = [
+            TestCase {
+                name: "Bytes",
+                directive: "db",
+                args: args(&["0x20", "0x70", "0x20"]),
+                expected: Ok(3),
+            },
+            TestCase {
+                name: "Bytes and string",
+                directive: "db",
+                args: args(&["\"HI\"", "0x0"]),
+                expected: Ok(3),
+            },
+            TestCase {
+                name: "Words",
+                directive: "dw",
+                args: args(&["0x1234", "foo"]),
+                expected: Ok(4),
+            },
+            TestCase {
+                name: "Fill",
+                directive: "ds",
+                args: args(&["0x10"]),
+                expected: Ok(0x10),
+            },
+            TestCase {
+                name: "Fill without count",
+                directive: "fill",
+                args: args(&[]),
+                expected: Err("Invalid number of arguments for fill: expected 1 or 2, got 0".into()),
+            },
+        ];
+
+        for case in test_cases.iter() {
+            let result = data_size(case.directive, &case.args);
+            assert_eq!(result, case.expected, "Failed on test case: {}", case.name);
+        }
+    }
+
+    #[test]
+    fn test_data_bytes() {
+        struct TestCase {
+            name: &'static str,
+            directive: &'static str,
+            args: Vec<String>,
+            expected: Result<Vec<u8>, String>,
+        }
+
+        let labels = HashMap::from([("sprite".to_string(), 0x2A4)]);
+
+        let test_cases = [
+            TestCase {
+                name: "Bytes",
+                directive: "db",
+                args: args(&["0x20", "70", "0x20"]),
+                expected: Ok(vec![0x20, 0x70, 0x20]),
+            },
+            TestCase {
+                name: "String",
+                directive: "db",
+                args: args(&["\"H \\\"I\\\"\"", "0x0"]),
+                expected: Ok(vec![b'H', b' ', b'"', b'I', b'"', 0x0]),
+            },
+            TestCase {
+                name: "Byte out of range",
+                directive: "db",
+                args: args(&["0x100"]),
+                expected: Err("Invalid byte for db: expected 0xFF or less, got 0x100".into()),
+            },
+            TestCase {
+                name: "Words and labels",
+                directive: "dw",
+                args: args(&["0x1234", "sprite"]),
+                expected: Ok(vec![0x12, 0x34, 0x02, 0xA4]),
+            },
+            TestCase {
+                name: "Unknown label",
+                directive: "dw",
+                args: args(&["player"]),
+                expected: Err("Error parsing number player: invalid digit found in string".into()),
+            },
+            TestCase {
+                name: "Reserve",
+                directive: "ds",
+                args: args(&["0x3"]),
+                expected: Ok(vec![0x0, 0x0, 0x0]),
+            },
+            TestCase {
+                name: "Fill with value",
+                directive: "fill",
+                args: args(&["0x2", "0xFF"]),
+                expected: Ok(vec![0xFF, 0xFF]),
+            },
+        ];
+
+        for case in test_cases.iter() {
+            let result = data_bytes(&labels, case.directive, &case.args);
+            assert_eq!(result, case.expected, "Failed on test case: {}", case.name);
+        }
+    }
+
+}
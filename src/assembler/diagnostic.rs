@@ -0,0 +1,260 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+
+// Diagnostics
+//
+// error[E004]: Undefined label or constant sprit
+//   --> game.s:12:8
+//    |
+// 12 |   ld i sprit
+//    |        ^^^^^
+//
+// Every error and warning has a code, the file and 1-based line it is about
+// and the columns it covers, so it can be shown under the source line. Each
+// stage of the assembler reports all of its errors before it stops.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Code {
+    Syntax,              // E001 a line that can not be read
+    UnknownInstruction,  // E002
+    Operand,             // E003 operands that do not fit the instruction or data
+    Expression,          // E004 undefined names and values out of range
+    Duplicate,           // E005 a label, constant or macro defined twice
+    File,                // E006 files that can not be found, read or written
+    Block,               // E007 macro and sprite blocks
+    Origin,              // E008 the origin and the size of the program
+    Target,              // E009 an instruction the target platform does not have
+    OddAddress,          // W001 an instruction at an odd address
+    LateOrigin,          // W002 org after the start of the program
+    FlagOverwritten,     // W003 a value in vf lost to a carry, borrow or collision flag
+    IntoData,            // W004 execution reaching data or leaving the program
+    Unbalanced,          // W005 ret without a call
+    StackDepth,          // W006 calls nested deeper than the stack
+    IndexUnset,          // W007 i used before ld i
+    Platform,            // W008 an instruction the target platform does not have
+    SelfModifying,       // W009 a write through i into the code
+}
+
+impl Code {
+
+    pub fn id(&self) -> &'static str {
+        match self {
+            Code::Syntax => "E001",
+            Code::UnknownInstruction => "E002",
+            Code::Operand => "E003",
+            Code::Expression => "E004",
+            Code::Duplicate => "E005",
+            Code::File => "E006",
+            Code::Block => "E007",
+            Code::Origin => "E008",
+            Code::Target => "E009",
+            Code::OddAddress => "W001",
+            Code::LateOrigin => "W002",
+            Code::FlagOverwritten => "W003",
+            Code::IntoData => "W004",
+            Code::Unbalanced => "W005",
+            Code::StackDepth => "W006",
+            Code::IndexUnset => "W007",
+            Code::Platform => "W008",
+            Code::SelfModifying => "W009",
+        }
+    }
+
+    pub fn is_warning(&self) -> bool {
+        self.id().starts_with('W')
+    }
+
+}
+
+// Span is the columns of a line a diagnostic is about, column is 1-based and
+// 0 when the columns are not known
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Span {
+    pub column: usize,
+    pub length: usize,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub code: Code,
+    pub message: String,
+    pub file: String,
+    pub line: usize,  // 1-based, 0 when the diagnostic is not about a line
+    pub span: Span,
+}
+
+impl Diagnostic {
+
+    pub fn new(code: Code, message: &str) -> Self {
+        Diagnostic {
+            code,
+            message: message.to_string(),
+            file: String::new(),
+            line: 0,
+            span: Span::default(),
+        }
+    }
+
+    // at places a diagnostic on a line of a file
+    pub fn at(mut self, file: &str, line: usize, span: Span) -> Self {
+        self.file = file.to_string();
+        self.line = line;
+        self.span = span;
+        self
+    }
+
+    pub fn is_warning(&self) -> bool {
+        self.code.is_warning()
+    }
+
+    // render shows the diagnostic with the source line it is about underlined
+    pub fn render(&self, source_line: Option<&str>) -> String {
+        let severity = if self.is_warning() { "warning" } else { "error" };
+        let mut text = format!("{}[{}]: {}\n", severity, self.code.id(), self.message);
+        if self.file.is_empty() {
+            return text
+        }
+
+        if self.line == 0 {
+            text.push_str(&format!("  --> {}\n", self.file));
+            return text
+        }
+
+        let location = match self.span.column {
+            0 => format!("{}:{}", self.file, self.line),
+            column => format!("{}:{}:{}", self.file, self.line, column),
+        };
+        text.push_str(&format!("  --> {}\n", location));
+
+        if let Some(source_line) = source_line {
+            let number = self.line.to_string();
+            let gutter = " ".repeat(number.len());
+            text.push_str(&format!("{} |\n", gutter));
+            text.push_str(&format!("{} | {}\n", number, source_line));
+            if self.span.column > 0 {
+                text.push_str(&format!("{} | {}{}\n", gutter,
+                    " ".repeat(self.span.column - 1), "^".repeat(self.span.length.max(1))));
+            }
+        }
+
+        text
+    }
+
+}
+
+// Display is the diagnostic on one line, file:line:column: error[code]: message
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let severity = if self.is_warning() { "warning" } else { "error" };
+        if !self.file.is_empty() {
+            write!(f, "{}:", self.file)?;
+            if self.line > 0 {
+                write!(f, "{}:", self.line)?;
+            }
+            if self.span.column > 0 {
+                write!(f, "{}:", self.span.column)?;
+            }
+            write!(f, " ")?;
+        }
+        write!(f, "{}[{}]: {}", severity, self.code.id(), self.message)
+    }
+}
+
+// report renders diagnostics with their source lines, followed by a count of
+// the errors and warnings
+pub fn report(diagnostics: &[Diagnostic]) -> String {
+    let mut sources: HashMap<&str, Vec<String>> = HashMap::new();
+    let mut text = String::new();
+
+    for diagnostic in diagnostics {
+        let lines = sources.entry(&diagnostic.file).or_insert_with(|| {
+            fs::read_to_string(&diagnostic.file).unwrap_or_default()
+                .lines()
+                .map(|line| line.to_string())
+                .collect()
+        });
+        let source_line = diagnostic.line.checked_sub(1)
+            .and_then(|line| lines.get(line))
+            .map(|line| line.as_str());
+        text.push_str(&diagnostic.render(source_line));
+        text.push('\n');
+    }
+
+    let warnings = diagnostics.iter().filter(|diagnostic| diagnostic.is_warning()).count();
+    let errors = diagnostics.len() - warnings;
+    text.push_str(&format!("{} error(s), {} warning(s)\n", errors, warnings));
+    text
+}
+
+// messages lists diagnostics one to a line, without their source lines
+pub fn messages(diagnostics: &[Diagnostic]) -> String {
+    diagnostics.iter()
+        .map(|diagnostic| diagnostic.to_string())
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        struct TestCase {
+            name: &'static str,
+            diagnostic: Diagnostic,
+            source_line: Option<&'static str>,
+            expected: &'static str,
+        }
+
+        let test_cases = [
+            TestCase {
+                name: "Underlined",
+                diagnostic: Diagnostic::new(Code::Expression, "Undefined label or constant sprit")
+                    .at("game.s", 12, Span { column: 8, length: 5 }),
+                source_line: Some("  ld i sprit"),
+                expected: concat!(
+                    "error[E004]: Undefined label or constant sprit\n",
+                    "  --> game.s:12:8\n",
+                    "   |\n",
+                    "12 |   ld i sprit\n",
+                    "   |        ^^^^^\n"),
+            },
+            TestCase {
+                name: "Warning without columns",
+                diagnostic: Diagnostic::new(Code::OddAddress, "Instruction at odd address 0x203")
+                    .at("game.s", 3, Span::default()),
+                source_line: Some("cls"),
+                expected: concat!(
+                    "warning[W001]: Instruction at odd address 0x203\n",
+                    "  --> game.s:3\n",
+                    "  |\n",
+                    "3 | cls\n"),
+            },
+            TestCase {
+                name: "No location",
+                diagnostic: Diagnostic::new(Code::File, "Error creating file: denied"),
+                source_line: None,
+                expected: "error[E006]: Error creating file: denied\n",
+            },
+        ];
+
+        for case in test_cases.iter() {
+            assert_eq!(case.diagnostic.render(case.source_line), case.expected,
+                "Failed on test case: {}", case.name);
+        }
+    }
+
+    #[test]
+    fn test_display() {
+        let diagnostic = Diagnostic::new(Code::Duplicate, "Label foo already defined")
+            .at("test.s", 3, Span { column: 1, length: 3 });
+        assert_eq!(diagnostic.to_string(), "test.s:3:1: error[E005]: Label foo already defined");
+
+        let diagnostic = Diagnostic::new(Code::Origin, "Origin must be even");
+        assert_eq!(diagnostic.to_string(), "error[E008]: Origin must be even");
+    }
+
+}
@@ -0,0 +1,456 @@
+use std::collections::HashMap;
+
+use super::registers::Register;
+
+// Expressions
+//
+// Operands may be expressions over labels and constants:
+//
+//   SPEED equ 4
+//   define WIDTH 64
+//   ld v0 (WIDTH - 8) / 2
+//   ld v1 SPEED * 2
+//   ld i sprite + 4
+//   db lo(table) hi(table)
+//
+// Literals are decimal (10), hex (0x0A), binary (0b1010) or a character ('A').
+// The operators are + - * / % and parentheses, with unary minus, and lo()/hi()
+// return the low and high byte of a value. The comparisons == != < > <= >=
+// are 1 when true and 0 when false, for conditional assembly.
+
+// the deepest nesting of unary minus and parentheses an expression may have
+const MAX_DEPTH: usize = 64;
+
+// evaluate returns the value of an expression, names are looked up in symbols
+pub fn evaluate(expr: &str, symbols: &HashMap<String, u16>) -> Result<u16, String> {
+    let mut parser = Parser {
+        chars: expr.chars().collect(),
+        pos: 0,
+        symbols,
+        depth: 0,
+    };
+
+    let value = parser.comparison()?;
+    parser.skip_whitespace();
+    if parser.pos < parser.chars.len() {
+        return Err(format!("Unexpected {} in expression {}", parser.chars[parser.pos], expr))
+    }
+
+    if !(0..=0xFFFF).contains(&value) {
+        return Err(format!("Expression {} out of range: {}", expr, value))
+    }
+
+    Ok(value as u16)
+}
+
+// resolve_arg replaces an operand expression with its value in hex. Strings,
+// registers and bare labels are returned as they are.
+pub fn resolve_arg(arg: &str, labels: &HashMap<String, u16>, constants: &HashMap<String, u16>) -> Result<String, String> {
+    if arg.starts_with('"') || is_keyword(arg) || (is_name(arg) && labels.contains_key(arg)) {
+        return Ok(arg.to_string())
+    }
+
+    let mut symbols = labels.clone();
+    symbols.extend(constants.iter().map(|(name, value)| (name.clone(), *value)));
+
+    let value = evaluate(arg, &symbols)?;
+    Ok(format!("0x{:X}", value))
+}
+
+// is_keyword returns true for operands that name a register rather than a
+// value, they are passed through to the opcodes untouched
+pub fn is_keyword(arg: &str) -> bool {
+    let arg = arg.to_lowercase();
+    matches!(arg.as_str(), "i" | "dt" | "st" | "f" | "b" | "hf" | "r" | "long")
+        || Register::get_register(&arg).is_ok()
+        || Register::get_range(&arg).is_ok()
+}
+
+// is_name returns true if the operand is a bare label or constant name
+pub fn is_name(arg: &str) -> bool {
+    let mut chars = arg.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' || c == '.' => {},
+        _ => return false,
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+// replace_names replaces the label and constant names in an operand with the
+// names replace returns, leaving numbers and quoted strings alone
+pub fn replace_names(arg: &str, replace: &dyn Fn(&str) -> Option<String>) -> String {
+    let chars: Vec<char> = arg.chars().collect();
+    let mut result = String::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        if c == '"' || c == '\'' {
+            // copy the quoted string up to its closing quote
+            let start = i;
+            i += 1;
+            while i < chars.len() && chars[i] != c {
+                if chars[i] == '\\' {
+                    i += 1;
+                }
+                i += 1;
+            }
+            i = (i + 1).min(chars.len());
+            result.extend(&chars[start..i]);
+        } else if c.is_ascii_alphanumeric() || c == '_' || c == '.' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_' || chars[i] == '.') {
+                i += 1;
+            }
+            let word: String = chars[start..i].iter().collect();
+            match replace(&word) {
+                Some(name) if !c.is_ascii_digit() => result.push_str(&name),
+                _ => result.push_str(&word),
+            }
+        } else {
+            result.push(c);
+            i += 1;
+        }
+    }
+
+    result
+}
+
+fn overflow() -> String {
+    "Expression overflows".to_string()
+}
+
+// is_anonymous returns true for the anonymous labels + and -
+pub fn is_anonymous(name: &str) -> bool {
+    name == "+" || name == "-"
+}
+
+struct Parser<'a> {
+    chars: Vec<char>,
+    pos: usize,
+    symbols: &'a HashMap<String, u16>,
+    depth: usize,  // the factors being parsed
+}
+
+impl<'a> Parser<'a> {
+
+    // comparison = expression (('==' | '!=' | '<' | '>' | '<=' | '>=') expression)?
+    fn comparison(&mut self) -> Result<i64, String> {
+        let value = self.expression()?;
+        let op: String = match self.peek() {
+            Some(c) if "=!<>".contains(c) => {
+                let next = self.chars.get(self.pos + 1).copied();
+                match (c, next) {
+                    (_, Some('=')) => [c, '='].iter().collect(),
+                    ('<', _) | ('>', _) => c.to_string(),
+                    _ => return Ok(value),
+                }
+            },
+            _ => return Ok(value),
+        };
+        self.pos += op.len();
+
+        let other = self.expression()?;
+        let result = match op.as_str() {
+            "==" => value == other,
+            "!=" => value != other,
+            "<" => value < other,
+            ">" => value > other,
+            "<=" => value <= other,
+            _ => value >= other,
+        };
+        Ok(result as i64)
+    }
+
+    // expression = term (('+' | '-') term)*
+    fn expression(&mut self) -> Result<i64, String> {
+        let mut value = self.term()?;
+        loop {
+            match self.peek() {
+                Some('+') => {
+                    self.pos += 1;
+                    value = value.checked_add(self.term()?).ok_or_else(overflow)?;
+                },
+                Some('-') => {
+                    self.pos += 1;
+                    value = value.checked_sub(self.term()?).ok_or_else(overflow)?;
+                },
+                _ => return Ok(value),
+            }
+        }
+    }
+
+    // term = factor (('*' | '/' | '%') factor)*
+    fn term(&mut self) -> Result<i64, String> {
+        let mut value = self.factor()?;
+        loop {
+            match self.peek() {
+                Some('*') => {
+                    self.pos += 1;
+                    value = value.checked_mul(self.factor()?).ok_or_else(overflow)?;
+                },
+                Some(c) if c == '/' || c == '%' => {
+                    self.pos += 1;
+                    let divisor = self.factor()?;
+                    if divisor == 0 {
+                        return Err("Division by zero".into())
+                    }
+                    let result = if c == '/' { value.checked_div(divisor) } else { value.checked_rem(divisor) };
+                    value = result.ok_or_else(overflow)?;
+                },
+                _ => return Ok(value),
+            }
+        }
+    }
+
+    // factor = '-' factor | '(' expression ')' | function | name | literal
+    fn factor(&mut self) -> Result<i64, String> {
+        if self.depth == MAX_DEPTH {
+            return Err("Expression nested too deeply".into())
+        }
+        self.depth += 1;
+        let value = self.operand();
+        self.depth -= 1;
+        value
+    }
+
+    // operand parses a factor once the nesting has been checked
+    fn operand(&mut self) -> Result<i64, String> {
+        match self.peek() {
+            Some('-') => {
+                self.pos += 1;
+                self.factor()?.checked_neg().ok_or_else(overflow)
+            },
+            Some('(') => {
+                self.pos += 1;
+                let value = self.expression()?;
+                self.expect(')')?;
+                Ok(value)
+            },
+            Some('\'') => self.character(),
+            Some(c) if c.is_ascii_digit() => self.number(),
+            Some(c) if c.is_ascii_alphabetic() || c == '_' || c == '.' => {
+                let name = self.name();
+                match name.to_lowercase().as_str() {
+                    "lo" | "hi" if self.peek() == Some('(') => {
+                        self.pos += 1;
+                        let value = self.expression()?;
+                        self.expect(')')?;
+                        if name.eq_ignore_ascii_case("lo") {
+                            Ok(value & 0xFF)
+                        } else {
+                            Ok((value >> 8) & 0xFF)
+                        }
+                    },
+                    _ => match self.symbols.get(&name) {
+                        Some(value) => Ok(*value as i64),
+                        None => Err(format!("Undefined label or constant {}", name)),
+                    },
+                }
+            },
+            Some(c) => Err(format!("Unexpected {} in expression", c)),
+            None => Err("Unexpected end of expression".into()),
+        }
+    }
+
+    fn number(&mut self) -> Result<i64, String> {
+        let start = self.pos;
+        while self.pos < self.chars.len() && self.chars[self.pos].is_ascii_alphanumeric() {
+            self.pos += 1;
+        }
+        let literal: String = self.chars[start..self.pos].iter().collect();
+        let lower = literal.to_lowercase();
+
+        let result = if let Some(hex) = lower.strip_prefix("0x") {
+            i64::from_str_radix(hex, 16)
+        } else if let Some(binary) = lower.strip_prefix("0b") {
+            i64::from_str_radix(binary, 2)
+        } else {
+            lower.parse::<i64>()
+        };
+
+        match result {
+            Ok(value) if value <= 0xFFFF => Ok(value),
+            Ok(_) => Err(format!("Number {} out of range", literal)),
+            Err(e) => Err(format!("Error parsing number {}: {}", literal, e)),
+        }
+    }
+
+    fn character(&mut self) -> Result<i64, String> {
+        // skip the opening quote
+        self.pos += 1;
+        let c = match self.chars.get(self.pos) {
+            Some('\\') => {
+                self.pos += 1;
+                match self.chars.get(self.pos) {
+                    Some('n') => '\n',
+                    Some('0') => '\0',
+                    Some('\'') => '\'',
+                    Some('\\') => '\\',
+                    Some(c) => return Err(format!("Unknown escape \\{} in character", c)),
+                    None => return Err("Unterminated character".into()),
+                }
+            },
+            Some(c) => *c,
+            None => return Err("Unterminated character".into()),
+        };
+        self.pos += 1;
+
+        if self.chars.get(self.pos) != Some(&'\'') {
+            return Err("Unterminated character".into())
+        }
+        self.pos += 1;
+
+        if !c.is_ascii() {
+            return Err(format!("Character {} is not ascii", c))
+        }
+        Ok(c as i64)
+    }
+
+    fn name(&mut self) -> String {
+        let start = self.pos;
+        while self.pos < self.chars.len()
+            && (self.chars[self.pos].is_ascii_alphanumeric() || self.chars[self.pos] == '_'
+                || self.chars[self.pos] == '.') {
+            self.pos += 1;
+        }
+        self.chars[start..self.pos].iter().collect()
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        if self.peek() != Some(c) {
+            return Err(format!("Expected {} in expression", c))
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    // peek returns the next character that is not whitespace
+    fn peek(&mut self) -> Option<char> {
+        self.skip_whitespace();
+        self.chars.get(self.pos).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.pos < self.chars.len() && self.chars[self.pos].is_whitespace() {
+            self.pos += 1;
+        }
+    }
+
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_evaluate() {
+        struct TestCase {
+            expr: &'static str,
+            expected: Result<u16, String>,
+        }
+
+        let symbols = HashMap::from([
+            ("sprite".to_string(), 0x2A4),
+            ("WIDTH".to_string(), 64),
+            ("SPEED".to_string(), 3),
+        ]);
+
+        let test_cases = [
+            TestCase { expr: "10", expected: Ok(10) },
+            TestCase { expr: "0x1F", expected: Ok(0x1F) },
+            TestCase { expr: "0b1010", expected: Ok(0b1010) },
+            TestCase { expr: "'A'", expected: Ok(0x41) },
+            TestCase { expr: "sprite + 4", expected: Ok(0x2A8) },
+            TestCase { expr: "SPEED * 2", expected: Ok(6) },
+            TestCase { expr: "(WIDTH - 8) / 2", expected: Ok(28) },
+            TestCase { expr: "WIDTH - 8 / 2", expected: Ok(60) },
+            TestCase { expr: "WIDTH % 5", expected: Ok(4) },
+            TestCase { expr: "-SPEED + 4", expected: Ok(1) },
+            TestCase { expr: "lo(sprite)", expected: Ok(0xA4) },
+            TestCase { expr: "hi(sprite) + 1", expected: Ok(0x03) },
+            TestCase { expr: "SPEED == 3", expected: Ok(1) },
+            TestCase { expr: "WIDTH / 2 != 32", expected: Ok(0) },
+            TestCase { expr: "SPEED + 1 >= 4", expected: Ok(1) },
+            TestCase { expr: "WIDTH < 64", expected: Ok(0) },
+            TestCase {
+                expr: "player",
+                expected: Err("Undefined label or constant player".into()),
+            },
+            TestCase {
+                expr: "SPEED - 4",
+                expected: Err("Expression SPEED - 4 out of range: -1".into()),
+            },
+            TestCase {
+                expr: "WIDTH / (SPEED - 3)",
+                expected: Err("Division by zero".into()),
+            },
+            TestCase {
+                expr: "(WIDTH - 8",
+                expected: Err("Expected ) in expression".into()),
+            },
+            TestCase {
+                expr: "0x1G",
+                expected: Err("Error parsing number 0x1G: invalid digit found in string".into()),
+            },
+            TestCase {
+                expr: "0x10000",
+                expected: Err("Number 0x10000 out of range".into()),
+            },
+            TestCase {
+                expr: "2 3",
+                expected: Err("Unexpected 3 in expression 2 3".into()),
+            },
+            TestCase {
+                expr: "0xFFFF*0xFFFF*0xFFFF*0xFFFF*0xFFFF",
+                expected: Err("Expression overflows".into()),
+            },
+            TestCase {
+                expr: "--(-(1))",
+                expected: Err("Expression --(-(1)) out of range: -1".into()),
+            },
+        ];
+
+        for case in test_cases.iter() {
+            let result = evaluate(case.expr, &symbols);
+            assert_eq!(result, case.expected, "Failed on expression: {}", case.expr);
+        }
+
+        let nested = format!("{}1", "-".repeat(100_000));
+        assert_eq!(evaluate(&nested, &symbols), Err("Expression nested too deeply".into()));
+        let nested = format!("{}1{}", "(".repeat(100_000), ")".repeat(100_000));
+        assert_eq!(evaluate(&nested, &symbols), Err("Expression nested too deeply".into()));
+    }
+
+    #[test]
+    fn test_replace_names() {
+        struct TestCase {
+            arg: &'static str,
+            expected: &'static str,
+        }
+
+        let names = HashMap::from([
+            ("x".to_string(), "10".to_string()),
+            ("sprite".to_string(), "player".to_string()),
+            (".loop".to_string(), "main.loop".to_string()),
+        ]);
+
+        let test_cases = [
+            TestCase { arg: "x", expected: "10" },
+            TestCase { arg: "(x + 8) / 2", expected: "(10 + 8) / 2" },
+            TestCase { arg: "lo(sprite)", expected: "lo(player)" },
+            TestCase { arg: ".loop+2", expected: "main.loop+2" },
+            TestCase { arg: "0x10", expected: "0x10" },
+            TestCase { arg: "xy", expected: "xy" },
+            TestCase { arg: "\"x\"", expected: "\"x\"" },
+            TestCase { arg: "'x'", expected: "'x'" },
+        ];
+
+        for case in test_cases.iter() {
+            let result = replace_names(case.arg, &|name| names.get(name).cloned());
+            assert_eq!(result, case.expected, "Failed on arg: {}", case.arg);
+        }
+    }
+
+}
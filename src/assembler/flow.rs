@@ -0,0 +1,518 @@
+use super::token::{Token, TokenType};
+use super::registers::Register;
+use super::diagnostic::{Code, Diagnostic};
+
+// Structured control flow
+//
+// if v0 == 5 then      while v1 != 0      loop                 for v2 from 0 to 9
+//   ...                  ...                ...                  ...
+// else                 endwhile           until v0 >= 10       endfor
+//   ...
+// endif
+//
+// The blocks expand into skips and jumps to labels of their own. Conditions
+// compare a register with a register or a value, == and != with se and sne,
+// < > <= >= by the borrow of a subtraction, which changes vf. Against a
+// register it subtracts and adds back, leaving the carry of the add in vf,
+// against a value it loads the value into a scratch register and subtracts
+// there. Here the scratch register is ve, so ve is changed too, unlike Octo
+// which uses vf: interpreters differ on whether 8XY5 and 8XY7 write the flag
+// or the result last, so vf is never an operand. A for loop counts its
+// register up from the first value to the last, both included.
+
+// SCRATCH is the register a comparison with a value is made in
+pub const SCRATCH: &str = "ve";
+
+// Condition is vx op y, op is one of == != < > <= >= key -key
+#[derive(Clone, Debug, PartialEq)]
+pub struct Condition {
+    pub x: String,
+    pub op: String,
+    pub y: String,
+}
+
+impl Condition {
+
+    // parse reads a condition such as v0 == 5
+    pub fn parse(condition: &str) -> Result<Condition, String> {
+        let invalid = || format!("Invalid condition {}: expected vx == value, or != < > <= >=", condition);
+
+        let (at, op) = ["==", "!=", "<=", ">=", "<", ">"].iter()
+            .find_map(|op| condition.find(op).map(|at| (at, *op)))
+            .ok_or_else(invalid)?;
+        let x = condition[..at].trim();
+        let y = condition[at + op.len()..].trim();
+        if y.is_empty() || Register::get_register(&x.to_lowercase()).is_err() {
+            return Err(invalid())
+        }
+
+        let condition = Condition { x: x.to_string(), op: op.to_string(), y: y.to_string() };
+        condition.check(SCRATCH)?;
+        Ok(condition)
+    }
+
+    // check returns an error for the registers a comparison can not use, vf
+    // which < > <= >= set, and scratch which they load a value into
+    pub fn check(&self, scratch: &str) -> Result<(), String> {
+        if !["<", ">", "<=", ">="].contains(&self.op.as_str()) {
+            return Ok(())
+        }
+        let (x, y) = (self.x.to_lowercase(), self.y.to_lowercase());
+        if x == "vf" || y == "vf" {
+            return Err(format!("Invalid condition {} {} {}: vf is set by the comparison", self.x, self.op, self.y))
+        }
+        if x == scratch && Register::get_register(&y).is_err() {
+            return Err(format!("Invalid condition {} {} {}: {} is set by a comparison with a value", self.x, self.op, self.y, scratch))
+        }
+        Ok(())
+    }
+
+    // negate returns the opposite condition
+    pub fn negate(&self) -> Condition {
+        let op = match self.op.as_str() {
+            "==" => "!=",
+            "!=" => "==",
+            "key" => "-key",
+            "-key" => "key",
+            "<" => ">=",
+            ">=" => "<",
+            ">" => "<=",
+            _ => ">",
+        };
+        Condition { x: self.x.clone(), op: op.to_string(), y: self.y.clone() }
+    }
+
+    // skip_unless returns the instructions that skip the next instruction
+    // unless the condition holds, a value is compared in scratch
+    pub fn skip_unless(&self, scratch: &str) -> Vec<(&'static str, Vec<String>)> {
+        let (x, y) = (self.x.clone(), self.y.clone());
+        match self.op.as_str() {
+            "==" => vec![("sne", vec![x, y])],
+            "!=" => vec![("se", vec![x, y])],
+            "key" => vec![("sknp", vec![x])],
+            "-key" => vec![("skp", vec![x])],
+            // the same register is never less than itself
+            op if x.eq_ignore_ascii_case(&y) => match op {
+                "<" | ">" => vec![("se", vec![x.clone(), x])],
+                _ => vec![("sne", vec![x.clone(), x])],
+            },
+            op if Register::get_register(&y.to_lowercase()).is_ok() => {
+                // a - b + b carries when a - b borrowed, leaving vf set when
+                // a < b and a as it was
+                let (a, b) = if op == "<" || op == ">=" { (x, y) } else { (y, x) };
+                let borrow = if op == "<" || op == ">" { "0" } else { "1" };
+                vec![
+                    ("sub", vec![a.clone(), b.clone()]),
+                    ("add", vec![a, b]),
+                    ("se", vec!["vf".to_string(), borrow.to_string()]),
+                ]
+            },
+            op => {
+                // subn sets vf when x >= y, sub when y >= x
+                let (subtract, flag) = match op {
+                    ">=" => ("subn", "0"),
+                    "<" => ("subn", "1"),
+                    "<=" => ("sub", "0"),
+                    _ => ("sub", "1"),
+                };
+                vec![
+                    ("ld", vec![scratch.to_string(), y]),
+                    (subtract, vec![scratch.to_string(), x]),
+                    ("se", vec!["vf".to_string(), flag.to_string()]),
+                ]
+            },
+        }
+    }
+
+}
+
+// Block is a structured block waiting for its end
+enum Block {
+    If { token: Token, otherwise: String, end: Option<String> },
+    While { token: Token, top: String, end: String },
+    Loop { token: Token, top: String },
+    For { token: Token, register: String, last: String, top: String, end: String },
+}
+
+// is_flow_if returns true for an if ... then, rather than an if of
+// conditional assembly
+pub fn is_flow_if(token: &Token) -> bool {
+    token.is_directive("if") && token.args.last().is_some_and(|arg| arg.eq_ignore_ascii_case("then"))
+}
+
+// expand_flow replaces the structured blocks in tokens with skips, jumps and
+// labels
+pub fn expand_flow(tokens: Vec<Token>) -> Result<Vec<Token>, Vec<Diagnostic>> {
+    let mut flow = Flow { program: Vec::new(), blocks: Vec::new(), labels: 0 };
+    let mut errors: Vec<Diagnostic> = Vec::new();
+
+    for token in tokens {
+        if let Err(e) = flow.token(token) {
+            errors.push(e);
+        }
+    }
+
+    for block in flow.blocks {
+        let (token, end) = match block {
+            Block::If { token, .. } => (token, "endif"),
+            Block::While { token, .. } => (token, "endwhile"),
+            Block::Loop { token, .. } => (token, "until"),
+            Block::For { token, .. } => (token, "endfor"),
+        };
+        errors.push(token.error(Code::Block, &format!("Missing {} for {}", end, token.name)));
+    }
+
+    if !errors.is_empty() {
+        return Err(errors)
+    }
+
+    Ok(flow.program)
+}
+
+struct Flow {
+    program: Vec<Token>,
+    blocks: Vec<Block>,
+    labels: usize,
+}
+
+impl Flow {
+
+    fn token(&mut self, token: Token) -> Result<(), Diagnostic> {
+        let directive = match token.token_type {
+            TokenType::Instruction => token.name.to_lowercase(),
+            _ => String::new(),
+        };
+
+        match directive.as_str() {
+            "if" if is_flow_if(&token) => {
+                let condition = self.condition(&token, 2)?;
+                let otherwise = self.new_label();
+                self.skip_unless(&condition.negate(), &token);
+                self.instruction("jmp", vec![otherwise.clone()], &token);
+                self.blocks.push(Block::If { token, otherwise, end: None });
+            },
+            "else" => {
+                let end = self.new_label();
+                match self.blocks.last_mut() {
+                    Some(Block::If { otherwise, end: block_end @ None, .. }) => {
+                        *block_end = Some(end.clone());
+                        let otherwise = otherwise.clone();
+                        self.instruction("jmp", vec![end], &token);
+                        self.label(&otherwise, &token);
+                    },
+                    _ => return Err(token.error(Code::Block, "else without if ... then")),
+                }
+            },
+            "endif" => match self.blocks.pop() {
+                Some(Block::If { otherwise, end, .. }) => self.label(&end.unwrap_or(otherwise), &token),
+                block => {
+                    self.blocks.extend(block);
+                    return Err(token.error(Code::Block, "endif without if ... then"))
+                },
+            },
+            "while" => {
+                let condition = self.condition(&token, 1)?;
+                let top = self.new_label();
+                let end = self.new_label();
+                self.label(&top, &token);
+                self.skip_unless(&condition.negate(), &token);
+                self.instruction("jmp", vec![end.clone()], &token);
+                self.blocks.push(Block::While { token, top, end });
+            },
+            "endwhile" => match self.blocks.pop() {
+                Some(Block::While { top, end, .. }) => {
+                    self.instruction("jmp", vec![top], &token);
+                    self.label(&end, &token);
+                },
+                block => {
+                    self.blocks.extend(block);
+                    return Err(token.error(Code::Block, "endwhile without while"))
+                },
+            },
+            "loop" if token.args.is_empty() => {
+                let top = self.new_label();
+                self.label(&top, &token);
+                self.blocks.push(Block::Loop { token, top });
+            },
+            "until" => match self.blocks.pop() {
+                Some(Block::Loop { top, .. }) => {
+                    let condition = self.condition(&token, 1)?;
+                    self.skip_unless(&condition.negate(), &token);
+                    self.instruction("jmp", vec![top], &token);
+                },
+                block => {
+                    self.blocks.extend(block);
+                    return Err(token.error(Code::Block, "until without loop"))
+                },
+            },
+            "for" => {
+                let args: Vec<String> = token.args.iter().map(|arg| arg.to_lowercase()).collect();
+                if args.len() != 5 || args[1] != "from" || args[3] != "to" || Register::get_register(&args[0]).is_err() {
+                    return Err(token.error(Code::Syntax, "Invalid for: expected for vx from first to last"))
+                }
+                let register = token.args[0].clone();
+                let last = token.args[4].clone();
+                let top = self.new_label();
+                let end = self.new_label();
+                self.instruction("ld", vec![register.clone(), token.args[2].clone()], &token);
+                self.label(&top, &token);
+                self.blocks.push(Block::For { token, register, last, top, end });
+            },
+            "endfor" => match self.blocks.pop() {
+                Some(Block::For { register, last, top, end, .. }) => {
+                    // leave after the pass with the last value, so a loop up
+                    // to 255 does not wrap around
+                    self.instruction("sne", vec![register.clone(), last], &token);
+                    self.instruction("jmp", vec![end.clone()], &token);
+                    self.instruction("add", vec![register, "1".to_string()], &token);
+                    self.instruction("jmp", vec![top], &token);
+                    self.label(&end, &token);
+                },
+                block => {
+                    self.blocks.extend(block);
+                    return Err(token.error(Code::Block, "endfor without for"))
+                },
+            },
+            _ => self.program.push(token),
+        }
+
+        Ok(())
+    }
+
+    // condition reads the condition of token, which has count arguments
+    fn condition(&self, token: &Token, count: usize) -> Result<Condition, Diagnostic> {
+        if token.args.len() != count {
+            return Err(token.error(Code::Syntax, &format!(
+                "Invalid number of arguments for {}: expected a condition", token.name)))
+        }
+        Condition::parse(&token.args[0]).map_err(|e| token.arg_error(0, Code::Syntax, &e))
+    }
+
+    fn skip_unless(&mut self, condition: &Condition, at: &Token) {
+        for (name, args) in condition.skip_unless(SCRATCH) {
+            self.instruction(name, args, at);
+        }
+    }
+
+    fn new_label(&mut self) -> String {
+        self.labels += 1;
+        format!("__flow_{}", self.labels)
+    }
+
+    fn label(&mut self, name: &str, at: &Token) {
+        self.program.push(Token {
+            name: name.to_string(),
+            token_type: TokenType::Label,
+            args: Vec::new(),
+            ..at.clone()
+        });
+    }
+
+    fn instruction(&mut self, name: &str, args: Vec<String>, at: &Token) {
+        self.program.push(Token {
+            name: name.to_string(),
+            token_type: TokenType::Instruction,
+            args,
+            ..at.clone()
+        });
+    }
+
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::diagnostic::messages;
+    use super::super::assembler::{build, AssemblerConfig};
+    use crate::chip8::{Chip8, Chip8Config};
+    use super::super::token::fixtures::instruction;
+    use std::fs;
+
+    #[test]
+    fn test_parse_condition() {
+        let condition = |x: &str, op: &str, y: &str| Condition { x: x.into(), op: op.into(), y: y.into() };
+
+        assert_eq!(Condition::parse("v0 == 5"), Ok(condition("v0", "==", "5")));
+        assert_eq!(Condition::parse("va<=vb"), Ok(condition("va", "<=", "vb")));
+        assert_eq!(Condition::parse("v1 > SPEED + 1"), Ok(condition("v1", ">", "SPEED + 1")));
+        assert_eq!(Condition::parse("5 == v0"),
+            Err("Invalid condition 5 == v0: expected vx == value, or != < > <= >=".into()));
+        assert_eq!(Condition::parse("v0"),
+            Err("Invalid condition v0: expected vx == value, or != < > <= >=".into()));
+    }
+
+    #[test]
+    fn test_expand_flow() {
+        struct TestCase {
+            name: &'static str,
+            tokens: Vec<Token>,
+            expected: Result<Vec<&'static str>, String>,
+        }
+
+        let test_cases = [
+            TestCase {
+                name: "if else endif",
+                tokens: vec![
+                    instruction("if", &["v0 == 5", "then"], 1),
+                    instruction("cls", &[], 2),
+                    instruction("else", &[], 3),
+                    instruction("ret", &[], 4),
+                    instruction("endif", &[], 5),
+                ],
+                expected: Ok(vec![
+                    "se v0 5", "jmp __flow_1", "cls", "jmp __flow_2", "__flow_1:", "ret", "__flow_2:",
+                ]),
+            },
+            TestCase {
+                name: "while with a comparison",
+                tokens: vec![
+                    instruction("while", &["v1 < 10"], 1),
+                    instruction("add", &["v1", "1"], 2),
+                    instruction("endwhile", &[], 3),
+                ],
+                expected: Ok(vec![
+                    "__flow_1:", "ld ve 10", "subn ve v1", "se vf 0", "jmp __flow_2",
+                    "add v1 1", "jmp __flow_1", "__flow_2:",
+                ]),
+            },
+            TestCase {
+                name: "Comparisons of registers",
+                tokens: vec![
+                    instruction("if", &["v0 <= v1", "then"], 1),
+                    instruction("endif", &[], 2),
+                    instruction("if", &["v2 < v2", "then"], 3),
+                    instruction("endif", &[], 4),
+                ],
+                expected: Ok(vec![
+                    "sub v1 v0", "add v1 v0", "se vf 0", "jmp __flow_1", "__flow_1:",
+                    "sne v2 v2", "jmp __flow_2", "__flow_2:",
+                ]),
+            },
+            TestCase {
+                name: "loop until",
+                tokens: vec![
+                    instruction("loop", &[], 1),
+                    instruction("wkp", &["v0"], 2),
+                    instruction("until", &["v0 != 0"], 3),
+                ],
+                expected: Ok(vec!["__flow_1:", "wkp v0", "sne v0 0", "jmp __flow_1"]),
+            },
+            TestCase {
+                name: "for",
+                tokens: vec![
+                    instruction("for", &["v2", "from", "0", "to", "9"], 1),
+                    instruction("drw", &["v2", "v3", "1"], 2),
+                    instruction("endfor", &[], 3),
+                ],
+                expected: Ok(vec![
+                    "ld v2 0", "__flow_1:", "drw v2 v3 1", "sne v2 9", "jmp __flow_2",
+                    "add v2 1", "jmp __flow_1", "__flow_2:",
+                ]),
+            },
+            TestCase {
+                name: "Errors",
+                tokens: vec![
+                    instruction("endwhile", &[], 1),
+                    instruction("if", &["v0 = 5", "then"], 2),
+                    instruction("for", &["v2", "0", "9"], 3),
+                    instruction("while", &["v0 != 0"], 4),
+                    instruction("endfor", &[], 5),
+                    instruction("while", &["vf > v0"], 6),
+                    instruction("if", &["ve < 3", "then"], 7),
+                ],
+                expected: Err([
+                    "test.s:1: error[E007]: endwhile without while",
+                    "test.s:2: error[E001]: Invalid condition v0 = 5: expected vx == value, or != < > <= >=",
+                    "test.s:3: error[E001]: Invalid for: expected for vx from first to last",
+                    "test.s:5: error[E007]: endfor without for",
+                    "test.s:6: error[E001]: Invalid condition vf > v0: vf is set by the comparison",
+                    "test.s:7: error[E001]: Invalid condition ve < 3: ve is set by a comparison with a value",
+                    "test.s:4: error[E007]: Missing endwhile for while",
+                ].join("\n")),
+            },
+        ];
+
+        for case in test_cases {
+            let result = expand_flow(case.tokens)
+                .map(|tokens| tokens.iter().map(|token| match token.token_type {
+                    TokenType::Label => format!("{}:", token.name),
+                    _ => format!("{} {}", token.name, token.args.join(" ")).trim_end().to_string(),
+                }).collect::<Vec<String>>())
+                .map_err(|errors| messages(&errors));
+            let expected = case.expected
+                .map(|lines| lines.iter().map(|line| line.to_string()).collect::<Vec<String>>());
+            assert_eq!(result, expected, "Failed on test case: {}", case.name);
+        }
+    }
+
+    // run assembles source, runs it on the emulator for steps instructions
+    // and returns the registers
+    fn run(dir: &std::path::Path, name: &str, source: &str, steps: usize) -> [u8; 16] {
+        let path = dir.join(name).to_string_lossy().to_string();
+        let program = dir.join("test.ch8").to_string_lossy().to_string();
+        fs::write(&path, source).unwrap();
+
+        let mut config = AssemblerConfig::new();
+        config.source = path;
+        let build = build(&config, config.platform).unwrap_or_else(|errors| panic!("{}", messages(&errors)));
+        fs::write(&program, build.program).unwrap();
+
+        let mut chip8_config = Chip8Config::new();
+        chip8_config.program = program;
+        chip8_config.use_rom_database = false;
+        let mut chip8 = Chip8::headless(chip8_config).unwrap();
+        for _ in 0..steps {
+            chip8.step().unwrap();
+        }
+        *chip8.registers()
+    }
+
+    #[test]
+    fn test_comparisons_on_emulator() {
+        let dir = std::env::temp_dir().join(format!("chip8-flow-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let pairs: [(u8, u8); 6] = [(3, 5), (5, 3), (5, 5), (0, 255), (255, 0), (0, 0)];
+        let holds = |x: u8, op: &str, y: u8| match op {
+            "<" => x < y,
+            ">" => x > y,
+            "<=" => x <= y,
+            _ => x >= y,
+        };
+        for (x, y) in pairs {
+            for op in ["<", ">", "<=", ">="] {
+                let source = format!(concat!(
+                    "ld v1 {x}\nld v2 {y}\nld v3 0\n",
+                    "if v1 {op} v2 then\nadd v3 1\nendif\n",
+                    "if v1 {op} {y} then\nadd v3 2\nendif\n",
+                    "if v2 {op} v2 then\nadd v3 4\nendif\n",
+                    "done: jmp done\n",
+                ), x = x, y = y, op = op);
+                let v = run(&dir, "test.s", &source, 30);
+
+                let same = if holds(y, op, y) { 4 } else { 0 };
+                let expected = if holds(x, op, y) { 3 + same } else { same };
+                assert_eq!((v[1], v[2], v[3]), (x, y, expected), "Failed on {} {} {}", x, op, y);
+            }
+        }
+
+        // a while loop runs until its comparison fails
+        let v = run(&dir, "test.s", "ld v1 0\nld v2 0\nwhile v1 < 10\nadd v1 1\nwhile v2 <= v1\nadd v2 1\nendwhile\nendwhile\ndone: jmp done\n", 500);
+        assert_eq!((v[1], v[2]), (10, 11));
+
+        // Octo compares a value in vf instead, leaving ve as it was
+        for (x, y) in pairs {
+            for op in ["<", ">", "<=", ">="] {
+                let source = format!(": main v1 := {x} ve := {y} v3 := 0 if v1 {op} {y} then v3 := 1 loop again",
+                    x = x, y = y, op = op);
+                let v = run(&dir, "test.8o", &source, 30);
+                let expected = if holds(x, op, y) { 1 } else { 0 };
+                assert_eq!((v[1], v[0xE], v[3]), (x, y, expected), "Failed on Octo {} {} {}", x, op, y);
+            }
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+}
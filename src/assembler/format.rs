@@ -0,0 +1,286 @@
+use super::lexer::{self, lex, Kind, Lexeme};
+use super::expr::is_anonymous;
+use super::diagnostic::{Code, Diagnostic, Span};
+
+// Formatter
+//
+// // draw the player
+// define SPEED 2
+//
+// main:
+//   ld   v0 0x1F               ; x
+//   drw  v0 v1 5
+// loop: jmp  loop
+//
+// Labels and the directives that shape the file, org, define, equ, include,
+// macro and endm and the if, ifdef, ifndef, else and endif of conditional
+// assembly, start at the first column. Other lines are indented, with the
+// instruction padded so the operands line up. Instructions and directives are
+// lower case, hex numbers are 0x with upper case digits and operands are
+// separated by a space. The lines inside if ... then, while, loop and for
+// blocks are indented a step further. Comments after code line up in one
+// column, comments on lines of their own are indented like the code that
+// follows them. No more than two blank lines are kept in a row.
+
+const INDENT: usize = 2;
+const MNEMONIC_WIDTH: usize = 4;
+const COMMENT_COLUMN: usize = 32;
+
+// the instructions and directives that are written in lower case
+const KEYWORDS: [&str; 55] = [
+    "add", "and", "audio", "call", "cls", "drw", "exit", "high", "jmp", "ld", "load", "low", "or",
+    "plane", "ret", "rnd", "save", "scd", "scl", "scr", "scu", "se", "shl", "shr", "sknp", "skp",
+    "sne", "sub", "subn", "wkp", "xor",
+    "org", "define", "db", "dw", "ds", "fill", "include", "incbin", "incpng", "macro", "endm",
+    "sprite", "endsprite", "if", "ifdef", "ifndef", "else", "endif", "while", "endwhile", "loop",
+    "until", "for", "endfor",
+];
+
+// the directives written at the first column
+const TOP_LEVEL: [&str; 7] = ["org", "define", "include", "macro", "endm", "ifdef", "ifndef"];
+
+// Blocks is the blocks open at a line
+#[derive(Default)]
+struct Blocks {
+    conditions: Vec<bool>,  // the open ifs, true for conditional assembly
+    depth: usize,  // the structured blocks open
+}
+
+// Line is a formatted line of source
+struct Line {
+    code: String,
+    indent: Option<usize>,  // None for blank and comment lines
+    comment: Option<String>,
+}
+
+// format_source returns text formatted, file is where text is from
+pub fn format_source(file: &str, text: &str) -> Result<String, Vec<Diagnostic>> {
+    let mut lines: Vec<Line> = Vec::new();
+    let mut errors: Vec<Diagnostic> = Vec::new();
+    let mut blocks = Blocks::default();
+
+    for (i, text) in text.lines().enumerate() {
+        match format_line(text, &mut blocks) {
+            Ok(line) => lines.push(line),
+            Err((e, at)) => errors.push(Diagnostic::new(Code::Syntax, &e).at(file, i + 1, at)),
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(errors)
+    }
+
+    let mut formatted = String::new();
+    let mut blank = 0;
+    for (i, line) in lines.iter().enumerate() {
+        if line.code.is_empty() && line.comment.is_none() {
+            blank += 1;
+            if blank > 2 || formatted.is_empty() {
+                continue;
+            }
+            formatted.push('\n');
+            continue;
+        }
+        blank = 0;
+
+        // comments on their own line go with the code after them
+        let indent = line.indent.unwrap_or_else(|| {
+            lines[i..].iter().find_map(|line| line.indent).unwrap_or(0)
+        });
+        let mut text = format!("{}{}", " ".repeat(indent), line.code);
+        if let Some(comment) = &line.comment {
+            if !line.code.is_empty() {
+                let width = text.chars().count();
+                text.push_str(&" ".repeat(if width < COMMENT_COLUMN { COMMENT_COLUMN - width } else { 1 }));
+            }
+            text.push_str(comment);
+        }
+        formatted.push_str(&text);
+        formatted.push('\n');
+    }
+
+    while formatted.ends_with("\n\n") {
+        formatted.pop();
+    }
+    Ok(formatted)
+}
+
+// format_line formats one line of source
+fn format_line(text: &str, blocks: &mut Blocks) -> Result<Line, (String, Span)> {
+    let lexemes = lex(text)?;
+
+    // the comment is whatever follows the last lexeme
+    let end = lexemes.last().map(|last| last.start + last.text.len()).unwrap_or(0);
+    let comment = Some(text[end..].trim()).filter(|comment| !comment.is_empty()).map(|comment| comment.to_string());
+
+    // hex numbers in upper case, which keeps every lexeme where it is
+    let mut normal = text.to_string();
+    for lexeme in lexemes.iter().filter(|lexeme| lexeme.kind == Kind::Number) {
+        if lexeme.text.len() > 2 && lexeme.text[..2].eq_ignore_ascii_case("0x") {
+            let hex = format!("0x{}", lexeme.text[2..].to_uppercase());
+            normal.replace_range(lexeme.start..lexeme.start + lexeme.text.len(), &hex);
+        }
+    }
+    let lexemes = lex(&normal)?;
+
+    let mut rest = &lexemes[..];
+    let label = match rest {
+        [name, colon, ..] if (name.kind == Kind::Word || is_anonymous(name.text)) && colon.kind == Kind::Colon => {
+            rest = &rest[2..];
+            Some(format!("{}:", name.text))
+        },
+        [anonymous, ..] if is_anonymous(anonymous.text) => {
+            rest = &rest[1..];
+            Some(anonymous.text.to_string())
+        },
+        _ => None,
+    };
+
+    let statement = match rest {
+        [] => None,
+        _ => Some(statement(&normal, rest, blocks)?),
+    };
+
+    let (code, indent) = match (label, statement) {
+        (Some(label), Some((code, _))) => (format!("{} {}", label, code), Some(0)),
+        (Some(label), None) => (label, Some(0)),
+        (None, Some((code, indent))) => (code, Some(indent)),
+        (None, None) => (String::new(), None),
+    };
+
+    Ok(Line { code, indent, comment })
+}
+
+// statement formats an instruction or directive, and returns it with its
+// indent
+fn statement(text: &str, lexemes: &[Lexeme], blocks: &mut Blocks) -> Result<(String, usize), (String, Span)> {
+    let joined = |lexemes: &[Lexeme]| -> Result<String, (String, Span)> {
+        let args = lexer::args(text, lexemes)?;
+        Ok(args.into_iter().map(|arg| arg.text).collect::<Vec<String>>().join(" "))
+    };
+
+    // NAME equ value
+    if let [name, equ, ..] = lexemes {
+        if equ.text.eq_ignore_ascii_case("equ") {
+            return Ok((format!("{} equ {}", name.text, joined(&lexemes[2..])?).trim_end().to_string(), 0))
+        }
+    }
+
+    let first = &lexemes[0];
+    let lower = first.text.to_lowercase();
+    let name = if KEYWORDS.contains(&lower.as_str()) { lower.clone() } else { first.text.to_string() };
+    let args = joined(&lexemes[1..])?;
+
+    // the depth of the line, and of the lines after it
+    let depth = blocks.depth;
+    let (top_level, line_depth, after) = match lower.as_str() {
+        "if" => {
+            let assembly = !args.to_lowercase().ends_with(" then");
+            blocks.conditions.push(assembly);
+            (assembly, depth, if assembly { depth } else { depth + 1 })
+        },
+        "ifdef" | "ifndef" => {
+            blocks.conditions.push(true);
+            (true, depth, depth)
+        },
+        "else" => match blocks.conditions.last() {
+            Some(false) => (false, depth.saturating_sub(1), depth),
+            _ => (true, depth, depth),
+        },
+        "endif" => match blocks.conditions.pop() {
+            Some(false) => (false, depth.saturating_sub(1), depth.saturating_sub(1)),
+            _ => (true, depth, depth),
+        },
+        "while" | "for" => (false, depth, depth + 1),
+        "loop" if args.is_empty() => (false, depth, depth + 1),
+        "endwhile" | "until" | "endfor" => (false, depth.saturating_sub(1), depth.saturating_sub(1)),
+        name => (TOP_LEVEL.contains(&name), depth, depth),
+    };
+    blocks.depth = after;
+
+    let code = match (args.is_empty(), top_level) {
+        (true, _) => name,
+        (false, true) => format!("{} {}", name, args),
+        (false, false) => format!("{:<width$} {}", name, args, width = MNEMONIC_WIDTH),
+    };
+    let indent = if top_level { 0 } else { INDENT * (line_depth + 1) };
+    Ok((code, indent))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::diagnostic::messages;
+
+    #[test]
+    fn test_format_source() {
+        struct TestCase {
+            name: &'static str,
+            source: &'static str,
+            expected: Result<&'static str, String>,
+        }
+
+        let test_cases = [
+            TestCase {
+                name: "Already formatted",
+                source: "// Print hello\norg 0x200\n\nmain:\n  ld   v0 0x20\n  cls\n",
+                expected: Ok("// Print hello\norg 0x200\n\nmain:\n  ld   v0 0x20\n  cls\n"),
+            },
+            TestCase {
+                name: "Case, operands and hex",
+                source: "Main:\n\tLD V0,0x1f\n  DRW v0 v1 0X5\n    SKNP    v2\n  draw_at 10, 20\n  db 0xab,'a', \"0xab\"\n",
+                expected: Ok("Main:\n  ld   V0 0x1F\n  drw  v0 v1 0x5\n  sknp v2\n  draw_at 10 20\n  db   0xAB 'a' \"0xab\"\n"),
+            },
+            TestCase {
+                name: "Labels and comments",
+                source: "  main: cls ; clear\n    ; the loop\n  loop:\n jmp loop # forever\n\n\n\n\n+ ret\n",
+                expected: Ok(concat!(
+                    "main: cls                       ; clear\n",
+                    "; the loop\n",
+                    "loop:\n",
+                    "  jmp  loop                     # forever\n",
+                    "\n",
+                    "\n",
+                    "+ ret\n",
+                )),
+            },
+            TestCase {
+                name: "Directives",
+                source: "  ORG 0x200\n  SPEED equ 2\nifdef DEBUG\n  define LEVEL 3\n  endif\nmacro draw x\nld v0 x\n  endm\nif v0 == 1 then\ncls\nelse\nfor v1 from 0 to 3\n; draw\ndrw v0 v1 1\nendfor\nendif\n",
+                expected: Ok(concat!(
+                    "org 0x200\n",
+                    "SPEED equ 2\n",
+                    "ifdef DEBUG\n",
+                    "define LEVEL 3\n",
+                    "endif\n",
+                    "macro draw x\n",
+                    "  ld   v0 x\n",
+                    "endm\n",
+                    "  if   v0 == 1 then\n",
+                    "    cls\n",
+                    "  else\n",
+                    "    for  v1 from 0 to 3\n",
+                    "      ; draw\n",
+                    "      drw  v0 v1 1\n",
+                    "    endfor\n",
+                    "  endif\n",
+                )),
+            },
+            TestCase {
+                name: "Errors",
+                source: "main:\n  ld v0 \"a\n  cls\n  ld v1 $2\n",
+                expected: Err([
+                    "test.s:2:9: error[E001]: Unterminated string \"a",
+                    "test.s:4:9: error[E001]: Unexpected character $",
+                ].join("\n")),
+            },
+        ];
+
+        for case in test_cases {
+            let result = format_source("test.s", case.source).map_err(|errors| messages(&errors));
+            assert_eq!(result, case.expected.map(|text| text.to_string()), "Failed on test case: {}", case.name);
+        }
+    }
+
+}
@@ -0,0 +1,301 @@
+use super::expr::{evaluate, is_name};
+use super::token::{Token, TokenType};
+use std::collections::HashMap;
+use std::fs::File;
+
+// Images
+//
+// incpng "tiles.png" tile 8 8      - 8x8 tiles labelled tile_0, tile_1, ...
+// incpng "hero.png" hero 16 16 2   - 16x16 tiles in two XO-CHIP planes
+//
+// A PNG is cut into tiles left to right, top to bottom. The name labels the
+// first tile and name_n labels tile n. Tiles are 8 pixels wide and 1 to 15
+// rows, or 16x16, as for sprite blocks.
+//
+// With one plane a bright, opaque pixel is set. With two planes each pixel is
+// one of four grey levels, black is 0 and white is 3, and a tile is the bytes
+// of plane 1 (bit 0) followed by the bytes of plane 2 (bit 1).
+// Transparent pixels are always 0.
+
+// Image is a decoded PNG, one level a pixel, row by row
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u8>,
+}
+
+// Tiles is how an image is cut up, from the arguments of incpng
+#[derive(Debug, PartialEq)]
+pub struct Tiles {
+    pub name: String,
+    pub width: usize,
+    pub height: usize,
+    pub planes: usize,
+}
+
+// tiles_from_args reads the arguments following the file name of incpng
+pub fn tiles_from_args(args: &[String]) -> Result<Tiles, String> {
+    if args.len() < 3 || args.len() > 4 {
+        return Err(format!(
+            "Invalid number of arguments for incpng: expected file, name, width, height and planes, got {}",
+            args.len() + 1))
+    }
+
+    if !is_name(&args[0]) {
+        return Err(format!("Invalid name for incpng: {}", args[0]))
+    }
+
+    let symbols = HashMap::new();
+    let number = |arg: &String| evaluate(arg, &symbols).map(|value| value as usize);
+    let tiles = Tiles {
+        name: args[0].clone(),
+        width: number(&args[1])?,
+        height: number(&args[2])?,
+        planes: match args.get(3) {
+            Some(planes) => number(planes)?,
+            None => 1,
+        },
+    };
+
+    match (tiles.width, tiles.height) {
+        (8, 1..=15) | (16, 16) => {},
+        (width, height) => return Err(format!("Invalid tile size {}x{}: expected 8x1 to 8x15 or 16x16", width, height)),
+    }
+
+    if tiles.planes != 1 && tiles.planes != 2 {
+        return Err(format!("Invalid number of planes: expected 1 or 2, got {}", tiles.planes))
+    }
+
+    Ok(tiles)
+}
+
+// load_image decodes a PNG to levels of 0 or 1 for one plane, 0 to 3 for two
+pub fn load_image(path: &str, planes: usize) -> Result<Image, String> {
+    let error = |e: png::DecodingError| format!("Error reading {}: {}", path, e);
+
+    let file = File::open(path).map_err(|e| format!("Error reading {}: {}", path, e))?;
+    let mut decoder = png::Decoder::new(file);
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info().map_err(error)?;
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buffer).map_err(error)?;
+
+    let channels = info.color_type.samples();
+    let mut pixels = Vec::with_capacity(info.width as usize * info.height as usize);
+    for row in buffer[..info.buffer_size()].chunks(info.line_size) {
+        for pixel in row.chunks(channels).take(info.width as usize) {
+            let (grey, alpha) = match pixel {
+                [grey] => (*grey as u32, 255),
+                [grey, alpha] => (*grey as u32, *alpha),
+                [r, g, b] => (luminance(*r, *g, *b), 255),
+                [r, g, b, alpha] => (luminance(*r, *g, *b), *alpha),
+                _ => return Err(format!("Error reading {}: unsupported color type", path)),
+            };
+            pixels.push(level(grey, alpha, planes));
+        }
+    }
+
+    Ok(Image {
+        width: info.width as usize,
+        height: info.height as usize,
+        pixels,
+    })
+}
+
+fn luminance(r: u8, g: u8, b: u8) -> u32 {
+    (299 * r as u32 + 587 * g as u32 + 114 * b as u32) / 1000
+}
+
+// level is the value of a pixel with grey 0 to 255
+fn level(grey: u32, alpha: u8, planes: usize) -> u8 {
+    if alpha < 128 {
+        return 0
+    }
+    match planes {
+        1 => (grey >= 128) as u8,
+        _ => ((grey + 42) / 85) as u8,
+    }
+}
+
+// tile_bytes cuts an image into the sprite bytes of each tile
+pub fn tile_bytes(image: &Image, tiles: &Tiles) -> Result<Vec<Vec<u8>>, String> {
+    if image.width == 0 || !image.width.is_multiple_of(tiles.width) || image.height == 0 || !image.height.is_multiple_of(tiles.height) {
+        return Err(format!("Image of {}x{} can not be cut into {}x{} tiles",
+            image.width, image.height, tiles.width, tiles.height))
+    }
+
+    let mut result = Vec::new();
+    for top in (0..image.height).step_by(tiles.height) {
+        for left in (0..image.width).step_by(tiles.width) {
+            let mut bytes = Vec::new();
+            for plane in 0..tiles.planes {
+                for y in top..top + tiles.height {
+                    let row = &image.pixels[y * image.width + left..y * image.width + left + tiles.width];
+                    for byte in row.chunks(8) {
+                        bytes.push(byte.iter().fold(0, |bits, pixel| bits << 1 | (pixel >> plane & 1)));
+                    }
+                }
+            }
+            result.push(bytes);
+        }
+    }
+
+    Ok(result)
+}
+
+// tile_tokens labels and stores each tile
+pub fn tile_tokens(name: &str, tiles: &[Vec<u8>], line: usize, file: &str) -> Vec<Token> {
+    let token = |name: String, token_type: TokenType, args: Vec<String>| Token {
+        name,
+        token_type,
+        line,
+        args,
+        file: file.to_string(),
+        spans: Vec::new(),
+    };
+
+    let mut tokens = vec![token(name.to_string(), TokenType::Label, Vec::new())];
+    for (i, bytes) in tiles.iter().enumerate() {
+        tokens.push(token(format!("{}_{}", name, i), TokenType::Label, Vec::new()));
+        tokens.push(token("db".to_string(), TokenType::Data,
+            bytes.iter().map(|byte| format!("0x{:02X}", byte)).collect()));
+    }
+    tokens
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_tiles_from_args() {
+        struct TestCase {
+            name: &'static str,
+            args: Vec<String>,
+            expected: Result<Tiles, String>,
+        }
+
+        let test_cases = [
+            TestCase {
+                name: "8x8 tiles",
+                args: args(&["tile", "8", "8"]),
+                expected: Ok(Tiles { name: "tile".to_string(), width: 8, height: 8, planes: 1 }),
+            },
+            TestCase {
+                name: "16x16 tiles in two planes",
+                args: args(&["hero", "16", "0x10", "2"]),
+                expected: Ok(Tiles { name: "hero".to_string(), width: 16, height: 16, planes: 2 }),
+            },
+            TestCase {
+                name: "Too few arguments",
+                args: args(&["tile", "8"]),
+                expected: Err(
+                    "Invalid number of arguments for incpng: expected file, name, width, height and planes, got 3".into()),
+            },
+            TestCase {
+                name: "Invalid tile size",
+                args: args(&["tile", "16", "8"]),
+                expected: Err("Invalid tile size 16x8: expected 8x1 to 8x15 or 16x16".into()),
+            },
+            TestCase {
+                name: "Invalid planes",
+                args: args(&["tile", "8", "8", "3"]),
+                expected: Err("Invalid number of planes: expected 1 or 2, got 3".into()),
+            },
+        ];
+
+        for case in test_cases {
+            let result = tiles_from_args(&case.args);
+            assert_eq!(result, case.expected, "Failed on test case: {}", case.name);
+        }
+    }
+
+    #[test]
+    fn test_tile_bytes() {
+        struct TestCase {
+            name: &'static str,
+            image: Image,
+            tiles: Tiles,
+            expected: Result<Vec<Vec<u8>>, String>,
+        }
+
+        fn image(width: usize, rows: &[&str]) -> Image {
+            Image {
+                width,
+                height: rows.len(),
+                pixels: rows.concat().bytes().map(|c| c - b'0').collect(),
+            }
+        }
+
+        fn tiles(width: usize, height: usize, planes: usize) -> Tiles {
+            Tiles { name: "tile".to_string(), width, height, planes }
+        }
+
+        let test_cases = [
+            TestCase {
+                name: "Two 8x2 tiles side by side",
+                image: image(16, &["1000000011111111", "0100000000000001"]),
+                tiles: tiles(8, 2, 1),
+                expected: Ok(vec![vec![0x80, 0x40], vec![0xFF, 0x01]]),
+            },
+            TestCase {
+                name: "8x1 tiles top to bottom",
+                image: image(8, &["00000001", "00000010"]),
+                tiles: tiles(8, 1, 1),
+                expected: Ok(vec![vec![0x01], vec![0x02]]),
+            },
+            TestCase {
+                name: "Two planes",
+                image: image(8, &["01230000", "33000000"]),
+                tiles: tiles(8, 2, 2),
+                expected: Ok(vec![vec![0x50, 0xC0, 0x30, 0xC0]]),
+            },
+            TestCase {
+                name: "16x16 tile",
+                image: image(16, &["1000000000000001"; 16]),
+                tiles: tiles(16, 16, 1),
+                expected: Ok(vec![[0x80, 0x01].repeat(16)]),
+            },
+            TestCase {
+                name: "Image not a multiple of the tile size",
+                image: image(8, &["00000001", "00000010", "00000100"]),
+                tiles: tiles(8, 2, 1),
+                expected: Err("Image of 8x3 can not be cut into 8x2 tiles".into()),
+            },
+        ];
+
+        for case in test_cases {
+            let result = tile_bytes(&case.image, &case.tiles);
+            assert_eq!(result, case.expected, "Failed on test case: {}", case.name);
+        }
+    }
+
+    #[test]
+    fn test_load_image() {
+        let path = std::env::temp_dir().join(format!("chip8_image_{}.png", std::process::id()));
+        let file = File::create(&path).unwrap();
+        let mut encoder = png::Encoder::new(file, 4, 2);
+        encoder.set_color(png::ColorType::GrayscaleAlpha);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(&[
+            0, 255, 90, 255, 170, 255, 255, 255,
+            255, 0, 255, 255, 0, 255, 200, 255,
+        ]).unwrap();
+        writer.finish().unwrap();
+
+        let path = path.to_string_lossy().to_string();
+        let one_plane = load_image(&path, 1).unwrap();
+        assert_eq!((one_plane.width, one_plane.height), (4, 2));
+        assert_eq!(one_plane.pixels, vec![0, 0, 1, 1, 0, 1, 0, 1]);
+        let two_planes = load_image(&path, 2).unwrap();
+        assert_eq!(two_planes.pixels, vec![0, 1, 2, 3, 0, 3, 0, 2]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+}
@@ -0,0 +1,85 @@
+use std::fs;
+use std::path::Path;
+
+// Includes
+//
+// include "engine.s"     - assemble another source file in place
+// incbin "sprites.bin"   - splice in the bytes of a binary file
+// incpng "tiles.png" ... - cut a PNG into sprite tiles, see image.rs
+//
+// Files are looked up next to the file that includes them first, then in each
+// include path (assemble -I <dir>) in order.
+
+// include_name returns the file name of an include or incbin line
+pub fn include_name(directive: &str, args: &[String]) -> Result<String, String> {
+    if args.len() != 1 || args[0].len() < 2 || !args[0].starts_with('"') || !args[0].ends_with('"') {
+        return Err(format!("Invalid argument for {}: expected a quoted file name", directive))
+    }
+    Ok(args[0][1..args[0].len() - 1].to_string())
+}
+
+// find_include returns the path of an included file
+pub fn find_include(name: &str, from: &str, include_paths: &[String]) -> Result<String, String> {
+    let mut candidates = Vec::new();
+    if Path::new(name).is_absolute() {
+        candidates.push(Path::new(name).to_path_buf());
+    } else {
+        let dir = Path::new(from).parent().unwrap_or(Path::new(""));
+        candidates.push(dir.join(name));
+        for include_path in include_paths {
+            candidates.push(Path::new(include_path).join(name));
+        }
+    }
+
+    match candidates.iter().find(|path| path.is_file()) {
+        Some(path) => Ok(path.to_string_lossy().to_string()),
+        None => Err(format!("Cannot find included file {}", name)),
+    }
+}
+
+// incbin_args reads a binary file as the arguments of a db directive
+pub fn incbin_args(path: &str) -> Result<Vec<String>, String> {
+    match fs::read(path) {
+        Ok(bytes) => Ok(bytes.iter().map(|byte| format!("0x{:02X}", byte)).collect()),
+        Err(e) => Err(format!("Error reading {}: {}", path, e)),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_include_name() {
+        struct TestCase {
+            name: &'static str,
+            args: Vec<String>,
+            expected: Result<String, String>,
+        }
+
+        let test_cases = [
+            TestCase {
+                name: "Quoted file name",
+                args: vec!["\"lib/sprites.s\"".to_string()],
+                expected: Ok("lib/sprites.s".to_string()),
+            },
+            TestCase {
+                name: "Unquoted file name",
+                args: vec!["sprites.s".to_string()],
+                expected: Err("Invalid argument for include: expected a quoted file name".into()),
+            },
+            TestCase {
+                name: "No file name",
+                args: vec![],
+                expected: Err("Invalid argument for include: expected a quoted file name".into()),
+            },
+        ];
+
+        for case in test_cases.iter() {
+            let result = include_name("include", &case.args);
+            assert_eq!(result, case.expected, "Failed on test case: {}", case.name);
+        }
+    }
+
+}
@@ -1,39 +1,123 @@
 use super::token::{Token, TokenType};
+use super::expr::{is_anonymous, replace_names};
+use super::diagnostic::{Code, Diagnostic};
 use std::collections::HashMap;
 
-pub fn get_labels(tokens: &Vec<Token>, _origin: u16) -> Result<HashMap<String, u16>, String> {
+// get_labels returns the address of every label and the errors found, a label
+// defined twice keeps its first address
+pub fn get_labels(tokens: &Vec<Token>, origin: u16) -> (HashMap<String, u16>, Vec<Diagnostic>) {
 
     let mut labels: HashMap<String, u16> = HashMap::new();
+    let mut defined: HashMap<&str, &Token> = HashMap::new();
 
-    // let mut pc = origin;
-    let mut pc = 0x200;
-    let mut errors: Vec<String> = Vec::new();
+    let mut pc = origin;
+    let mut errors: Vec<Diagnostic> = Vec::new();
 
     for token in tokens {
         match token.token_type {
             TokenType::Label => {
-                if labels.contains_key(&token.name) {
-                    errors.push(
-                        format!("Error on line {}: Label {} already defined.", 
-                        token.line, token.name));
-                    break
+                if let Some(first) = defined.get(token.name.as_str()) {
+                    errors.push(token.error(Code::Duplicate, &format!(
+                        "Label {} already defined in {} on line {}", token.name, first.file, first.line)));
+                    continue;
                 }
+                defined.insert(&token.name, token);
                 labels.insert(token.name.clone(), pc);
             },
-            TokenType::Instruction => {
-                pc += 2;
+            TokenType::Instruction | TokenType::Data => {
+                // size errors are reported when the data is assembled
+                pc = pc.wrapping_add(token.size().unwrap_or(0));
             },
             _=>{}
         }
     }
 
-    Ok(labels)
+    (labels, errors)
+}
+
+// scope_labels gives local and anonymous labels names of their own
+//
+// .loop  - a local label, scoped to the global label before it. After main:
+//          .loop is main.loop, and can be used from anywhere as main.loop
+// + / -  - anonymous labels, an operand of + jumps to the next + label and -
+//          to the previous - label, ++ and -- go one label further
+pub fn scope_labels(mut tokens: Vec<Token>) -> Result<Vec<Token>, Vec<Diagnostic>> {
+
+    // anonymous labels are named by position so they can be used before they
+    // are defined
+    let mut forward: Vec<(usize, String)> = Vec::new();
+    let mut backward: Vec<(usize, String)> = Vec::new();
+    for (i, token) in tokens.iter().enumerate() {
+        if let TokenType::Label = token.token_type {
+            match token.name.as_str() {
+                "+" => forward.push((i, format!("__anonymous{}", i))),
+                "-" => backward.push((i, format!("__anonymous{}", i))),
+                _ => {},
+            }
+        }
+    }
+
+    let mut scope = String::new();
+    let mut errors: Vec<Diagnostic> = Vec::new();
+
+    for (i, token) in tokens.iter_mut().enumerate() {
+        match token.token_type {
+            TokenType::Label => {
+                if is_anonymous(&token.name) {
+                    token.name = format!("__anonymous{}", i);
+                } else if token.name.starts_with('.') {
+                    token.name = format!("{}{}", scope, token.name);
+                } else if !token.name.starts_with("__") {
+                    // labels made by macro expansion do not start a scope
+                    scope = token.name.clone();
+                }
+            },
+            TokenType::Instruction | TokenType::Data | TokenType::Constant => {
+                let mut args: Vec<String> = Vec::new();
+                for (n, arg) in token.args.iter().enumerate() {
+                    let count = arg.len();
+                    let anonymous = if !arg.is_empty() && arg.chars().all(|c| c == '+') {
+                        Some(forward.iter().filter(|(at, _)| *at > i).nth(count - 1))
+                    } else if !arg.is_empty() && arg.chars().all(|c| c == '-') {
+                        Some(backward.iter().rev().filter(|(at, _)| *at < i).nth(count - 1))
+                    } else {
+                        None
+                    };
+
+                    args.push(match anonymous {
+                        Some(Some((_, name))) => name.clone(),
+                        Some(None) => {
+                            errors.push(token.arg_error(n, Code::Expression, &format!("No anonymous label for {}", arg)));
+                            arg.clone()
+                        },
+                        None => replace_names(arg, &|name| {
+                            if name.starts_with('.') {
+                                Some(format!("{}{}", scope, name))
+                            } else {
+                                None
+                            }
+                        }),
+                    });
+                }
+                token.args = args;
+            },
+            _ => {},
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(errors)
+    }
+
+    Ok(tokens)
 }
 
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use super::super::diagnostic::messages;
+    use super::super::token::fixtures::token;
 
     #[test]
     fn test_get_labels() {
@@ -61,6 +145,8 @@ mod tests {
                         token_type: TokenType::Origin,
                         line: 0,
                         args: vec!["0x200".to_string()],
+                        file: "test.s".to_string(),
+                        spans: Vec::new(),
                     }
                 ],
                 origin: 0x200,
@@ -75,12 +161,14 @@ mod tests {
                         token_type: TokenType::Label,
                         line: 0,
                         args: Vec::new(),
+                        file: "test.s".to_string(),
+                        spans: Vec::new(),
                     }
                 ],
                 origin: 0x200,
                 expected: Result::Ok(
                     HashMap::<String, u16>::from_iter(
-                        vec![("foo".to_string(), 0x200)].into_iter())),
+                        vec![("foo".to_string(), 0x200)])),
             },
 
             TestCase {
@@ -91,34 +179,251 @@ mod tests {
                         token_type: TokenType::Label,
                         line: 0,
                         args: Vec::new(),
+                        file: "test.s".to_string(),
+                        spans: Vec::new(),
                     },
                     Token {
                         name: "ld".to_string(),
                         token_type: TokenType::Instruction,
                         line: 1,
                         args: vec!["v0".to_string(), "0x22".to_string()],
+                        file: "test.s".to_string(),
+                        spans: Vec::new(),
                     },
                     Token {
                         name: "foobar".to_string(),
                         token_type: TokenType::Label,
                         line: 2,
                         args: Vec::new(),
+                        file: "test.s".to_string(),
+                        spans: Vec::new(),
                     }
                 ],
                 origin: 0x200,
                 expected: Result::Ok(
                     HashMap::<String, u16>::from_iter(
-                        vec![("foo".to_string(), 0x200), ("foobar".to_string(), 0x202)].into_iter())),
+                        vec![("foo".to_string(), 0x200), ("foobar".to_string(), 0x202)])),
+            },
+
+            TestCase {
+                name: "Labels after origin",
+                tokens: vec![
+                    Token {
+                        name: "org".to_string(),
+                        token_type: TokenType::Origin,
+                        line: 0,
+                        args: vec!["0x300".to_string()],
+                        file: "test.s".to_string(),
+                        spans: Vec::new(),
+                    },
+                    Token {
+                        name: "foo".to_string(),
+                        token_type: TokenType::Label,
+                        line: 1,
+                        args: Vec::new(),
+                        file: "test.s".to_string(),
+                        spans: Vec::new(),
+                    },
+                    Token {
+                        name: "cls".to_string(),
+                        token_type: TokenType::Instruction,
+                        line: 2,
+                        args: Vec::new(),
+                        file: "test.s".to_string(),
+                        spans: Vec::new(),
+                    },
+                    Token {
+                        name: "bar".to_string(),
+                        token_type: TokenType::Label,
+                        line: 3,
+                        args: Vec::new(),
+                        file: "test.s".to_string(),
+                        spans: Vec::new(),
+                    }
+                ],
+                origin: 0x300,
+                expected: Result::Ok(
+                    HashMap::<String, u16>::from_iter(
+                        vec![("foo".to_string(), 0x300), ("bar".to_string(), 0x302)])),
+            },
+
+            TestCase {
+                name: "Labels after data",
+                tokens: vec![
+                    Token {
+                        name: "db".to_string(),
+                        token_type: TokenType::Data,
+                        line: 0,
+                        args: vec!["\"HI\"".to_string(), "0x0".to_string()],
+                        file: "test.s".to_string(),
+                        spans: Vec::new(),
+                    },
+                    Token {
+                        name: "foo".to_string(),
+                        token_type: TokenType::Label,
+                        line: 1,
+                        args: Vec::new(),
+                        file: "test.s".to_string(),
+                        spans: Vec::new(),
+                    },
+                    Token {
+                        name: "dw".to_string(),
+                        token_type: TokenType::Data,
+                        line: 2,
+                        args: vec!["foo".to_string()],
+                        file: "test.s".to_string(),
+                        spans: Vec::new(),
+                    },
+                    Token {
+                        name: "bar".to_string(),
+                        token_type: TokenType::Label,
+                        line: 3,
+                        args: Vec::new(),
+                        file: "test.s".to_string(),
+                        spans: Vec::new(),
+                    }
+                ],
+                origin: 0x200,
+                expected: Result::Ok(
+                    HashMap::from([("foo".to_string(), 0x203), ("bar".to_string(), 0x205)])),
+            },
+
+            TestCase {
+                name: "Duplicate labels",
+                tokens: vec![
+                    Token {
+                        name: "foo".to_string(),
+                        token_type: TokenType::Label,
+                        line: 1,
+                        args: Vec::new(),
+                        file: "test.s".to_string(),
+                        spans: Vec::new(),
+                    },
+                    Token {
+                        name: "foo".to_string(),
+                        token_type: TokenType::Label,
+                        line: 4,
+                        args: Vec::new(),
+                        file: "test.s".to_string(),
+                        spans: Vec::new(),
+                    },
+                    Token {
+                        name: "foo".to_string(),
+                        token_type: TokenType::Label,
+                        line: 7,
+                        args: Vec::new(),
+                        file: "test.s".to_string(),
+                        spans: Vec::new(),
+                    }
+                ],
+                origin: 0x200,
+                expected: Err([
+                    "test.s:4: error[E005]: Label foo already defined in test.s on line 1",
+                    "test.s:7: error[E005]: Label foo already defined in test.s on line 1",
+                ].join("\n")),
             }
 
         ];
 
         for case in test_cases {
-            let result = get_labels(&case.tokens, case.origin);
+            let result = match get_labels(&case.tokens, case.origin) {
+                (labels, errors) if errors.is_empty() => Ok(labels),
+                (_, errors) => Err(messages(&errors)),
+            };
             assert_eq!(result, case.expected, "Failed on test case: {}", case.name);
         }
 
     }
 
 
+
+    #[test]
+    fn test_scope_labels() {
+        struct TestCase {
+            name: &'static str,
+            tokens: Vec<Token>,
+            expected: Result<Vec<(String, Vec<String>)>, String>,
+        }
+
+        fn expected(tokens: &[(&str, &[&str])]) -> Result<Vec<(String, Vec<String>)>, String> {
+            Ok(tokens.iter()
+                .map(|(name, args)| (name.to_string(), args.iter().map(|arg| arg.to_string()).collect()))
+                .collect())
+        }
+
+        let test_cases = [
+            TestCase {
+                name: "Local labels",
+                tokens: vec![
+                    token("main", TokenType::Label, &[], 0),
+                    token(".loop", TokenType::Label, &[], 1),
+                    token("jmp", TokenType::Instruction, &[".loop"], 2),
+                    token("draw", TokenType::Label, &[], 3),
+                    token(".loop", TokenType::Label, &[], 4),
+                    token("ld", TokenType::Instruction, &["i", ".loop + 2"], 5),
+                    token("jmp", TokenType::Instruction, &["main.loop"], 6),
+                ],
+                expected: expected(&[
+                    ("main", &[]),
+                    ("main.loop", &[]),
+                    ("jmp", &["main.loop"]),
+                    ("draw", &[]),
+                    ("draw.loop", &[]),
+                    ("ld", &["i", "draw.loop + 2"]),
+                    ("jmp", &["main.loop"]),
+                ]),
+            },
+            TestCase {
+                name: "Macro labels do not start a scope",
+                tokens: vec![
+                    token("main", TokenType::Label, &[], 0),
+                    token("__wait1_loop", TokenType::Label, &[], 1),
+                    token("jmp", TokenType::Instruction, &[".done"], 2),
+                ],
+                expected: expected(&[
+                    ("main", &[]),
+                    ("__wait1_loop", &[]),
+                    ("jmp", &["main.done"]),
+                ]),
+            },
+            TestCase {
+                name: "Anonymous labels",
+                tokens: vec![
+                    token("-", TokenType::Label, &[], 0),
+                    token("se", TokenType::Instruction, &["v0", "0x0"], 1),
+                    token("jmp", TokenType::Instruction, &["+"], 2),
+                    token("jmp", TokenType::Instruction, &["++"], 3),
+                    token("+", TokenType::Label, &[], 4),
+                    token("jmp", TokenType::Instruction, &["-"], 5),
+                    token("+", TokenType::Label, &[], 6),
+                ],
+                expected: expected(&[
+                    ("__anonymous0", &[]),
+                    ("se", &["v0", "0x0"]),
+                    ("jmp", &["__anonymous4"]),
+                    ("jmp", &["__anonymous6"]),
+                    ("__anonymous4", &[]),
+                    ("jmp", &["__anonymous0"]),
+                    ("__anonymous6", &[]),
+                ]),
+            },
+            TestCase {
+                name: "Missing anonymous label",
+                tokens: vec![
+                    token("jmp", TokenType::Instruction, &["-"], 1),
+                ],
+                expected: Err("test.s:1: error[E004]: No anonymous label for -".into()),
+            },
+        ];
+
+        for case in test_cases {
+            let result = scope_labels(case.tokens).map(|tokens| {
+                tokens.into_iter()
+                    .map(|token| (token.name, token.args))
+                    .collect::<Vec<(String, Vec<String>)>>()
+            }).map_err(|errors| messages(&errors));
+            assert_eq!(result, case.expected, "Failed on test case: {}", case.name);
+        }
+    }
+
 }
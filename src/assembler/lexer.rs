@@ -0,0 +1,317 @@
+use super::diagnostic::Span;
+
+// Lexer
+//
+// loop: ld v0, (WIDTH - 8) / 2   ; centre the sprite
+//
+// A line is read into lexemes: words, numbers, strings, characters and
+// punctuation, each with where it is in the line. Comments start with ;, # or
+// // anywhere outside a string and run to the end of the line.
+//
+// Operands are separated by commas or whitespace. Whitespace next to an
+// operator or inside parentheses does not separate them, so sprite + 4 is one
+// operand, but a - with whitespace only before it starts a negative operand,
+// so db 5 -1 is two.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    Word,      // names, keywords and sprite rows, like ld, main.loop or ..XX....
+    Number,    // 12, 0x1F, 0b1010
+    Str,       // "HELLO"
+    Char,      // 'A'
+    Comma,
+    Colon,
+    Operator,  // + - * / % == != < > <= >=
+    Open,
+    Close,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Lexeme<'a> {
+    pub kind: Kind,
+    pub text: &'a str,
+    pub start: usize,  // byte offset in the line
+    pub space: bool,   // whitespace comes before it
+}
+
+// Arg is an operand, the text of one or more lexemes
+#[derive(Clone, Debug, PartialEq)]
+pub struct Arg {
+    pub text: String,
+    pub span: Span,
+}
+
+// lex reads a line into lexemes, up to any comment
+pub fn lex(line: &str) -> Result<Vec<Lexeme<'_>>, (String, Span)> {
+    let mut lexemes: Vec<Lexeme> = Vec::new();
+    let mut chars = line.char_indices().peekable();
+    let mut space = false;
+
+    while let Some((start, c)) = chars.next() {
+        let kind = match c {
+            c if c.is_whitespace() => {
+                space = true;
+                continue;
+            },
+            ';' | '#' => break,
+            '/' if matches!(chars.peek(), Some((_, '/'))) => break,
+            '"' | '\'' => {
+                let mut escaped = false;
+                let mut closed = false;
+                for (_, s) in chars.by_ref() {
+                    if escaped {
+                        escaped = false;
+                    } else if s == '\\' {
+                        escaped = true;
+                    } else if s == c {
+                        closed = true;
+                        break;
+                    }
+                }
+                if !closed {
+                    return Err((format!("Unterminated string {}", &line[start..]),
+                        span(line, start, line.len())))
+                }
+                if c == '"' { Kind::Str } else { Kind::Char }
+            },
+            c if c.is_alphanumeric() || c == '_' || c == '.' => {
+                while let Some((_, s)) = chars.peek() {
+                    if !(s.is_alphanumeric() || *s == '_' || *s == '.') {
+                        break;
+                    }
+                    chars.next();
+                }
+                if c.is_ascii_digit() { Kind::Number } else { Kind::Word }
+            },
+            '+' | '-' | '*' | '/' | '%' => Kind::Operator,
+            '=' | '!' | '<' | '>' => {
+                match chars.peek() {
+                    Some((_, '=')) => {
+                        chars.next();
+                    },
+                    _ if c == '<' || c == '>' => {},
+                    _ => return Err((format!("Unexpected character {}", c), span(line, start, start + 1))),
+                }
+                Kind::Operator
+            },
+            '(' => Kind::Open,
+            ')' => Kind::Close,
+            ',' => Kind::Comma,
+            ':' => Kind::Colon,
+            c => return Err((format!("Unexpected character {}", c), span(line, start, start + c.len_utf8()))),
+        };
+
+        let end = chars.peek().map(|(end, _)| *end).unwrap_or(line.len());
+        lexemes.push(Lexeme {
+            kind,
+            text: &line[start..end],
+            start,
+            space,
+        });
+        space = false;
+    }
+
+    Ok(lexemes)
+}
+
+// args groups the lexemes following an instruction into operands
+pub fn args(line: &str, lexemes: &[Lexeme]) -> Result<Vec<Arg>, (String, Span)> {
+    let mut args: Vec<Arg> = Vec::new();
+    let mut first: Option<&Lexeme> = None;
+    let mut last: Option<&Lexeme> = None;
+    let mut depth = 0;
+
+    let operands = match (lexemes.first(), lexemes.last()) {
+        (Some(first), Some(last)) => span(line, first.start, last.start + last.text.len()),
+        _ => return Ok(args),
+    };
+    let unbalanced = || {
+        let start = lexemes[0].start;
+        let end = lexemes[lexemes.len() - 1].start + lexemes[lexemes.len() - 1].text.len();
+        (format!("Unbalanced parentheses in {}", &line[start..end]), operands)
+    };
+
+    for (i, lexeme) in lexemes.iter().enumerate() {
+        if lexeme.kind == Kind::Comma && depth == 0 {
+            if let (Some(first), Some(last)) = (first.take(), last.take()) {
+                args.push(arg(line, first, last));
+            }
+            continue;
+        }
+
+        // whitespace ends an operand unless an operator joins the two
+        if let (Some(start), Some(end)) = (first, last) {
+            let negative = lexeme.text == "-"
+                && lexemes.get(i + 1).is_some_and(|next| !next.space)
+                && matches!(end.kind, Kind::Word | Kind::Number | Kind::Str | Kind::Char | Kind::Close);
+            let joined = depth > 0
+                || matches!(end.kind, Kind::Operator | Kind::Open)
+                || (matches!(lexeme.kind, Kind::Operator | Kind::Close) && !negative);
+            if lexeme.space && !joined {
+                args.push(arg(line, start, end));
+                first = None;
+            }
+        }
+
+        match lexeme.kind {
+            Kind::Open => depth += 1,
+            Kind::Close if depth == 0 => return Err(unbalanced()),
+            Kind::Close => depth -= 1,
+            _ => {},
+        }
+
+        if first.is_none() {
+            first = Some(lexeme);
+        }
+        last = Some(lexeme);
+    }
+
+    if depth != 0 {
+        return Err(unbalanced())
+    }
+
+    if let (Some(first), Some(last)) = (first, last) {
+        args.push(arg(line, first, last));
+    }
+
+    Ok(args)
+}
+
+fn arg(line: &str, first: &Lexeme, last: &Lexeme) -> Arg {
+    let end = last.start + last.text.len();
+    Arg {
+        text: line[first.start..end].to_string(),
+        span: span(line, first.start, end),
+    }
+}
+
+// span returns the columns of the bytes start to end of a line
+pub fn span(line: &str, start: usize, end: usize) -> Span {
+    Span {
+        column: line[..start].chars().count() + 1,
+        length: line[start..end].chars().count(),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lex() {
+        struct TestCase {
+            line: &'static str,
+            expected: Result<Vec<(Kind, &'static str)>, String>,
+        }
+
+        let test_cases = [
+            TestCase {
+                line: "loop: ld v0, 0x20",
+                expected: Ok(vec![
+                    (Kind::Word, "loop"), (Kind::Colon, ":"), (Kind::Word, "ld"),
+                    (Kind::Word, "v0"), (Kind::Comma, ","), (Kind::Number, "0x20"),
+                ]),
+            },
+            TestCase {
+                line: "  db \"A; B # C // D\", 'x' ; comment",
+                expected: Ok(vec![
+                    (Kind::Word, "db"), (Kind::Str, "\"A; B # C // D\""), (Kind::Comma, ","),
+                    (Kind::Char, "'x'"),
+                ]),
+            },
+            TestCase {
+                line: "ld v0 (WIDTH-8)/2 # centre",
+                expected: Ok(vec![
+                    (Kind::Word, "ld"), (Kind::Word, "v0"), (Kind::Open, "("), (Kind::Word, "WIDTH"),
+                    (Kind::Operator, "-"), (Kind::Number, "8"), (Kind::Close, ")"),
+                    (Kind::Operator, "/"), (Kind::Number, "2"),
+                ]),
+            },
+            TestCase {
+                line: "if LEVEL>=2",
+                expected: Ok(vec![(Kind::Word, "if"), (Kind::Word, "LEVEL"), (Kind::Operator, ">="), (Kind::Number, "2")]),
+            },
+            TestCase {
+                line: "if LEVEL = 2",
+                expected: Err("Unexpected character =".into()),
+            },
+            TestCase {
+                line: "..XX.... // sprite row",
+                expected: Ok(vec![(Kind::Word, "..XX....")]),
+            },
+            TestCase {
+                line: "db \"HELLO",
+                expected: Err("Unterminated string \"HELLO".into()),
+            },
+            TestCase {
+                line: "ld v0 $20",
+                expected: Err("Unexpected character $".into()),
+            },
+        ];
+
+        for case in test_cases.iter() {
+            let result = lex(case.line)
+                .map(|lexemes| lexemes.iter().map(|lexeme| (lexeme.kind, lexeme.text)).collect())
+                .map_err(|(message, _)| message);
+            assert_eq!(result, case.expected, "Failed on line: {}", case.line);
+        }
+    }
+
+    #[test]
+    fn test_args() {
+        struct TestCase {
+            line: &'static str,
+            expected: Result<Vec<&'static str>, String>,
+        }
+
+        let test_cases = [
+            TestCase { line: "v0 0x20", expected: Ok(vec!["v0", "0x20"]) },
+            TestCase { line: "v0, 0x20", expected: Ok(vec!["v0", "0x20"]) },
+            TestCase { line: "v0,0x20", expected: Ok(vec!["v0", "0x20"]) },
+            TestCase { line: "i sprite + 4", expected: Ok(vec!["i", "sprite + 4"]) },
+            TestCase { line: "v0 (WIDTH - 8) / 2", expected: Ok(vec!["v0", "(WIDTH - 8) / 2"]) },
+            TestCase { line: "lo(table) hi(table)", expected: Ok(vec!["lo(table)", "hi(table)"]) },
+            TestCase { line: "v0 ' '", expected: Ok(vec!["v0", "' '"]) },
+            TestCase { line: "',' 0x0", expected: Ok(vec!["','", "0x0"]) },
+            TestCase { line: "++", expected: Ok(vec!["++"]) },
+            TestCase { line: "5 -1", expected: Ok(vec!["5", "-1"]) },
+            TestCase { line: "8 -2 3", expected: Ok(vec!["8", "-2", "3"]) },
+            TestCase { line: "8 - 2 8-2 (8 -2)", expected: Ok(vec!["8 - 2", "8-2", "(8 -2)"]) },
+            TestCase { line: "DEBUG == 1", expected: Ok(vec!["DEBUG == 1"]) },
+            TestCase {
+                line: "\"HELLO, WORLD\" 0x0",
+                expected: Ok(vec!["\"HELLO, WORLD\"", "0x0"]),
+            },
+            TestCase { line: "\"\\\"\"", expected: Ok(vec!["\"\\\"\""]) },
+            TestCase {
+                line: "\"HELLO",
+                expected: Err("Unterminated string \"HELLO".into()),
+            },
+            TestCase {
+                line: "v0 (WIDTH - 8",
+                expected: Err("Unbalanced parentheses in v0 (WIDTH - 8".into()),
+            },
+            TestCase {
+                line: "v0 8)",
+                expected: Err("Unbalanced parentheses in v0 8)".into()),
+            },
+        ];
+
+        for case in test_cases.iter() {
+            let result = lex(case.line)
+                .and_then(|lexemes| args(case.line, &lexemes))
+                .map(|args| args.into_iter().map(|arg| arg.text).collect::<Vec<String>>())
+                .map_err(|(message, _)| message);
+            let expected = case.expected.clone()
+                .map(|args| args.iter().map(|s| s.to_string()).collect::<Vec<String>>());
+            assert_eq!(result, expected, "Failed on line: {}", case.line);
+        }
+
+        let line = "ld i, sprite + 4";
+        let spans: Vec<Span> = args(line, &lex(line).unwrap()[1..]).unwrap()
+            .into_iter().map(|arg| arg.span).collect();
+        assert_eq!(spans, vec![Span { column: 4, length: 1 }, Span { column: 7, length: 10 }]);
+    }
+
+}
@@ -0,0 +1,389 @@
+use super::assembler::{build, AssemblerConfig, Build};
+use super::diagnostic::{Code, Diagnostic};
+use super::target::Target;
+use super::token::TokenType;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+// Lint
+//
+// chip8 lint game.s --target schip
+//
+// The program is assembled and then followed from its origin through every
+// jump, call, return and skip, keeping the return addresses on the stack,
+// what i points at and whether vf holds a value of the program. It warns
+// about:
+//
+//   W003  a value put in vf lost to the flag of add, sub, shr, shl or drw
+//   W004  execution running or jumping into data, or out of the program
+//   W005  ret without a call
+//   W006  calls nested deeper than the stack, 16 levels on chip8 and 32 on
+//         schip and xochip
+//   W007  drw, bcd, save and load through i before any ld i
+//   W008  instructions the target does not have, such as drw with height 0
+//   W009  writes through i into the instructions of the program
+//
+// jmp v0 goes where v0 says, so it is not followed.
+
+// the most states followed, so a large program can not run the lint forever
+const MAX_STATES: usize = 1 << 16;
+
+// Index is what is known of i
+#[derive(Clone, Copy, Hash, PartialEq, Eq)]
+enum Index {
+    Unset,
+    Known(u16),
+    Unknown,
+}
+
+// State is one way of reaching an instruction
+#[derive(Clone, Hash, PartialEq, Eq)]
+struct State {
+    pc: u16,
+    stack: Vec<u16>,
+    i: Index,
+    vf: Option<u16>,  // the instruction that put a value not read yet in vf
+}
+
+// lint assembles config.source and returns its warnings, or the errors that
+// stopped it assembling
+pub fn lint(config: &AssemblerConfig) -> Result<Vec<Diagnostic>, Vec<Diagnostic>> {
+    // every instruction is assembled so the whole program can be followed,
+    // the ones the target does not have are warned about
+    let build = build(config, Target::XoChip)?;
+    let mut diagnostics = build.diagnostics.clone();
+    diagnostics.extend(analyse(&build, config.platform));
+    Ok(diagnostics)
+}
+
+// analyse returns the warnings of an assembled program for platform
+fn analyse(build: &Build, platform: Target) -> Vec<Diagnostic> {
+    let mut lint = Lint {
+        build,
+        code: HashMap::new(),
+        limit: if platform == Target::Chip8 { 16 } else { 32 },
+        warnings: BTreeMap::new(),
+    };
+
+    for (n, entry) in build.entries.iter().enumerate() {
+        if !matches!(entry.token.token_type, TokenType::Instruction) || entry.bytes.is_empty() {
+            continue;
+        }
+        lint.code.insert(entry.address, n);
+
+        let token = &entry.token;
+        let required = Target::required(&token.name, &token.args);
+        if required > platform {
+            let message = if token.is_directive("drw") {
+                format!("drw with height 0 draws a 16x16 sprite, which needs --target {}, the target is {}",
+                    required.name(), platform.name())
+            } else {
+                format!("{} needs --target {}, the target is {}", token.name, required.name(), platform.name())
+            };
+            lint.warn(entry.address, Code::Platform, message);
+        }
+    }
+
+    if lint.code.contains_key(&build.origin) {
+        let mut states = vec![State { pc: build.origin, stack: Vec::new(), i: Index::Unset, vf: None }];
+        let mut seen: HashSet<State> = HashSet::new();
+        while let Some(state) = states.pop() {
+            if seen.len() >= MAX_STATES {
+                break;
+            }
+            if seen.insert(state.clone()) {
+                states.extend(lint.step(state));
+            }
+        }
+    }
+
+    lint.warnings.into_values()
+        .map(|(address, code, message)| build.entries[lint.code[&address]].token.error(code, &message))
+        .collect()
+}
+
+struct Lint<'a> {
+    build: &'a Build,
+    code: HashMap<u16, usize>,  // the entry of the instruction at each address
+    limit: usize,  // levels of the stack
+    warnings: BTreeMap<(u16, &'static str), (u16, Code, String)>,
+}
+
+impl Lint<'_> {
+
+    // step follows the instruction of state and returns the states after it
+    fn step(&mut self, mut state: State) -> Vec<State> {
+        let pc = state.pc;
+        let opcode = self.opcode(pc);
+        let next = pc.wrapping_add(self.size(pc));
+        let name = self.build.entries[self.code[&pc]].token.name.to_lowercase();
+        let (x, y, n, nn, nnn) = (
+            opcode >> 8 & 0xF,
+            opcode >> 4 & 0xF,
+            opcode & 0xF,
+            opcode & 0xFF,
+            opcode & 0xFFF,
+        );
+
+        // vf
+        let (reads, writes, flag) = registers(opcode);
+        if reads & 0x8000 != 0 {
+            state.vf = None;
+        }
+        if flag {
+            if let Some(set) = state.vf.take() {
+                let line = self.build.entries[self.code[&set]].token.line;
+                self.warn(pc, Code::FlagOverwritten, format!(
+                    "{} overwrites vf with a flag, vf still holds the value set on line {}", name, line));
+            }
+        } else if writes & 0x8000 != 0 {
+            state.vf = Some(pc);
+        }
+
+        // i
+        let uses_i = match opcode >> 12 {
+            0xD => true,
+            0x5 => n == 2 || n == 3,
+            0xF => matches!(nn, 0x1E | 0x33 | 0x55 | 0x65),
+            _ => false,
+        };
+        if uses_i && state.i == Index::Unset {
+            self.warn(pc, Code::IndexUnset, format!("{} uses i before any ld i", name));
+            state.i = Index::Unknown;
+        }
+        let written = match opcode >> 12 {
+            0xF if nn == 0x33 => 3,
+            0xF if nn == 0x55 => x + 1,
+            0x5 if n == 2 => x.abs_diff(y) + 1,
+            _ => 0,
+        };
+        if let Index::Known(address) = state.i {
+            if let Some(at) = (address..address.saturating_add(written)).find_map(|at| self.instruction_at(at)) {
+                let line = self.build.entries[self.code[&at]].token.line;
+                self.warn(pc, Code::SelfModifying, format!(
+                    "{} writes to 0x{:03X}, the instruction on line {}", name, address, line));
+            }
+        }
+        state.i = match opcode >> 12 {
+            0xA => Index::Known(nnn),
+            0xF if opcode == 0xF000 => Index::Known(self.opcode(pc.wrapping_add(2))),
+            0xF if matches!(nn, 0x1E | 0x29 | 0x30 | 0x55 | 0x65) => Index::Unknown,
+            _ => state.i,
+        };
+
+        // where it goes next
+        let skip = match opcode >> 12 {
+            0x3 | 0x4 => true,
+            0x5 | 0x9 => n == 0,
+            0xE => nn == 0x9E || nn == 0xA1,
+            _ => false,
+        };
+        match opcode {
+            0x00EE => match state.stack.pop() {
+                Some(address) => self.goto(pc, address, "ret returns", state).into_iter().collect(),
+                None => {
+                    self.warn(pc, Code::Unbalanced, "ret without a call".to_string());
+                    Vec::new()
+                },
+            },
+            0x00FD => Vec::new(),
+            _ if opcode >> 12 == 0x1 => self.goto(pc, nnn, "jmp jumps", state).into_iter().collect(),
+            _ if opcode >> 12 == 0x2 => {
+                if state.stack.len() >= self.limit {
+                    self.warn(pc, Code::StackDepth, format!(
+                        "call nests deeper than the {} levels of the stack", self.limit));
+                    return Vec::new()
+                }
+                state.stack.push(next);
+                self.goto(pc, nnn, "call jumps", state).into_iter().collect()
+            },
+            _ if opcode >> 12 == 0xB => Vec::new(),
+            _ if skip => {
+                let over = match self.code.get(&next) {
+                    Some(_) => next.wrapping_add(self.size(next)),
+                    None => next.wrapping_add(2),
+                };
+                let mut states: Vec<State> = self.goto(pc, next, "Execution runs", state.clone()).into_iter().collect();
+                states.extend(self.goto(pc, over, &format!("{} skips", name), state));
+                states
+            },
+            _ => self.goto(pc, next, "Execution runs", state).into_iter().collect(),
+        }
+    }
+
+    // goto returns state moved to the instruction at address, or warns when
+    // there is no instruction there
+    fn goto(&mut self, from: u16, address: u16, how: &str, mut state: State) -> Option<State> {
+        if self.code.contains_key(&address) {
+            state.pc = address;
+            return Some(state)
+        }
+
+        let end = self.build.origin as usize + self.build.program.len();
+        let entry = self.build.entries.iter()
+            .filter(|entry| !entry.bytes.is_empty())
+            .find(|entry| spans(entry.address, entry.bytes.len(), address));
+        let message = match entry {
+            Some(entry) if matches!(entry.token.token_type, TokenType::Data) => {
+                format!("{} into data at 0x{:03X}, line {}", how, address, entry.token.line)
+            },
+            Some(entry) => format!("{} into the middle of the instruction on line {}", how, entry.token.line),
+            None if address as usize == end => format!("{} past the end of the program", how),
+            None => format!("{} out of the program to 0x{:03X}", how, address),
+        };
+        self.warn(from, Code::IntoData, message);
+        None
+    }
+
+    // instruction_at returns the address of the instruction with a byte at
+    // address
+    fn instruction_at(&self, address: u16) -> Option<u16> {
+        self.code.iter()
+            .find(|(at, n)| spans(**at, self.build.entries[**n].bytes.len(), address))
+            .map(|(at, _)| *at)
+    }
+
+    fn opcode(&self, address: u16) -> u16 {
+        let at = address.wrapping_sub(self.build.origin) as usize;
+        match self.build.program.get(at..at + 2) {
+            Some(bytes) => u16::from_be_bytes([bytes[0], bytes[1]]),
+            None => 0,
+        }
+    }
+
+    fn size(&self, address: u16) -> u16 {
+        self.code.get(&address)
+            .map(|n| self.build.entries[*n].bytes.len() as u16)
+            .unwrap_or(2)
+    }
+
+    // warn keeps the first warning of each code for an address
+    fn warn(&mut self, address: u16, code: Code, message: String) {
+        self.warnings.entry((address, code.id())).or_insert((address, code, message));
+    }
+
+}
+
+// spans returns true if the size bytes from start hold address, the sum is
+// taken in usize as data may run up to the end of memory
+fn spans(start: u16, size: usize, address: u16) -> bool {
+    (start as usize..start as usize + size).contains(&(address as usize))
+}
+
+// registers returns the registers an opcode reads and writes, one bit each
+// from v0, and whether it sets vf to a flag afterwards
+fn registers(opcode: u16) -> (u16, u16, bool) {
+    let x = 1 << (opcode >> 8 & 0xF);
+    let y = 1 << (opcode >> 4 & 0xF);
+    let range = |from: u16, to: u16| (from.min(to)..=from.max(to)).fold(0u16, |bits, r| bits | 1 << r);
+    let (vx, vy) = (opcode >> 8 & 0xF, opcode >> 4 & 0xF);
+
+    match (opcode >> 12, opcode & 0xF, opcode & 0xFF) {
+        (0x3 | 0x4 | 0xE, _, _) => (x, 0, false),
+        (0x5 | 0x9, 0, _) => (x | y, 0, false),
+        (0x5, 2, _) => (range(vx, vy), 0, false),
+        (0x5, 3, _) => (0, range(vx, vy), false),
+        (0x6 | 0xC, _, _) => (0, x, false),
+        (0x7, _, _) => (x, x, false),
+        (0x8, 0, _) => (y, x, false),
+        (0x8, 1..=3, _) => (x | y, x, false),
+        (0x8, 4..=7 | 0xE, _) => (x | y, x, true),
+        (0xB, _, _) => (1, 0, false),
+        (0xD, _, _) => (x | y, 0, true),
+        (0xF, _, 0x07 | 0x0A) => (0, x, false),
+        (0xF, _, 0x15 | 0x18 | 0x1E | 0x29 | 0x30 | 0x33) => (x, 0, false),
+        (0xF, _, 0x55 | 0x75) => (range(0, vx), 0, false),
+        (0xF, _, 0x65 | 0x85) => (0, range(0, vx), false),
+        _ => (0, 0, false),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_lint() {
+        struct TestCase {
+            name: &'static str,
+            source: &'static str,
+            target: Target,
+            expected: Vec<&'static str>,
+        }
+
+        let test_cases = [
+            TestCase {
+                name: "No warnings",
+                source: "main:\n  ld i sprite\n  call draw\n  if v0 < 3 then\n    add v0 1\n  endif\nloop: jmp loop\ndraw:\n  drw v0 v1 1\n  ret\nsprite:\n  db 0x80\n",
+                target: Target::Chip8,
+                expected: vec![],
+            },
+            TestCase {
+                name: "vf lost to a carry",
+                source: "ld vf 1\nadd v0 v1\nse vf 1\ncls\nloop: jmp loop\n",
+                target: Target::Chip8,
+                expected: vec!["2:1: warning[W003]: add overwrites vf with a flag, vf still holds the value set on line 1"],
+            },
+            TestCase {
+                name: "Into data",
+                source: "main:\n  se v0 1\n  jmp table\n  cls\ntable:\n  db 0x12, 0x34\n",
+                target: Target::Chip8,
+                expected: vec![
+                    "3:3: warning[W004]: jmp jumps into data at 0x206, line 6",
+                    "4:3: warning[W004]: Execution runs into data at 0x206, line 6",
+                ],
+            },
+            TestCase {
+                name: "Calls and returns",
+                source: "main:\n  se v0 0\n  call deep\n  ret\ndeep:\n  call deep\n",
+                target: Target::Chip8,
+                expected: vec![
+                    "4:3: warning[W005]: ret without a call",
+                    "6:3: warning[W006]: call nests deeper than the 16 levels of the stack",
+                ],
+            },
+            TestCase {
+                name: "i",
+                source: "drw v0 v1 0\nld i main\nld i v3\nmain: jmp main\n",
+                target: Target::Chip8,
+                expected: vec![
+                    "1:1: warning[W007]: drw uses i before any ld i",
+                    "1:1: warning[W008]: drw with height 0 draws a 16x16 sprite, which needs --target schip, the target is chip8",
+                    "3:1: warning[W009]: ld writes to 0x206, the instruction on line 4",
+                ],
+            },
+            TestCase {
+                name: "Data up to the end of memory",
+                source: "main: jmp 0x400\nds 0xFE00\n",
+                target: Target::Chip8,
+                expected: vec!["1:7: warning[W004]: jmp jumps into data at 0x400, line 2"],
+            },
+            TestCase {
+                name: "drw with height 0 on schip",
+                source: "ld i main\ndrw v0 v1 0\nmain: jmp main\n",
+                target: Target::Schip,
+                expected: vec![],
+            },
+        ];
+
+        let dir = std::env::temp_dir().join(format!("chip8-lint-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("test.s").to_string_lossy().to_string();
+
+        for case in test_cases {
+            fs::write(&source, case.source).unwrap();
+            let mut config = AssemblerConfig::new();
+            config.source = source.clone();
+            config.platform = case.target;
+
+            let result: Vec<String> = lint(&config).unwrap_or_else(|errors| errors).iter()
+                .map(|diagnostic| diagnostic.to_string().replacen(&format!("{}:", source), "", 1))
+                .collect();
+            assert_eq!(result, case.expected, "Failed on test case: {}", case.name);
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+}
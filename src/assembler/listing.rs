@@ -0,0 +1,218 @@
+use super::token::{Token, TokenType};
+use std::collections::HashMap;
+use std::fs;
+
+// Listings
+//
+// assemble game.s game.ch8 --listing game.lst
+//
+// 0200  00 E0        game.s:3     cls
+// 0202  A2 0A        game.s:4     ld i sprite
+// 020A  30 78 30     game.s:9     db 0x30 0x78 0x30
+//
+// Every token is listed in program order with its address, the bytes it
+// assembled to and the source line it came from. Labels and constants
+// follow in a symbol table.
+//
+// A symbol file (assemble --symbols) holds the same labels and the source line
+// of each instruction, for the emulator to name addresses with.
+
+// bytes shown on a line of the listing, longer data carries on below
+const BYTES_PER_LINE: usize = 8;
+
+// Entry is one token of the program, at its address with its bytes
+pub struct Entry {
+    pub address: u16,
+    pub bytes: Vec<u8>,
+    pub token: Token,
+}
+
+// write_listing writes the listing of entries to target
+pub fn write_listing(target: &str, entries: &[Entry], labels: &HashMap<String, u16>, constants: &HashMap<String, u16>) -> Result<(), String> {
+    let mut sources: HashMap<String, Vec<String>> = HashMap::new();
+    for entry in entries {
+        if !sources.contains_key(&entry.token.file) {
+            let text = fs::read_to_string(&entry.token.file).unwrap_or_default();
+            sources.insert(entry.token.file.clone(), text.lines().map(|line| line.trim().to_string()).collect());
+        }
+    }
+
+    match fs::write(target, listing(entries, &sources, labels, constants)) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Error writing listing {}: {}", target, e)),
+    }
+}
+
+// listing returns the text of a listing, sources holds the lines of each
+// source file
+pub fn listing(entries: &[Entry], sources: &HashMap<String, Vec<String>>, labels: &HashMap<String, u16>, constants: &HashMap<String, u16>) -> String {
+    let mut text = String::new();
+    let mut last: Option<(&str, usize)> = None;
+
+    for entry in entries {
+        let token = &entry.token;
+        // command line defines have no source line, they are in the symbols
+        if matches!(token.token_type, TokenType::Origin) || token.line == 0 {
+            continue;
+        }
+
+        // tokens from the same line, like a sprite label and its data, show
+        // the line once
+        let here = Some((token.file.as_str(), token.line));
+        let source = if last == here {
+            String::new()
+        } else {
+            let line = sources.get(&token.file)
+                .and_then(|lines| lines.get(token.line.wrapping_sub(1)))
+                .cloned()
+                .unwrap_or_else(|| token.to_string());
+            format!("{:<12} {}", format!("{}:{}", file_name(&token.file), token.line), line)
+        };
+        last = here;
+
+        let mut chunks = entry.bytes.chunks(BYTES_PER_LINE);
+        let first = chunks.next().unwrap_or(&[]);
+        text.push_str(&listing_line(entry.address, first, &source));
+        let mut address = entry.address.wrapping_add(first.len() as u16);
+        for chunk in chunks {
+            text.push_str(&listing_line(address, chunk, ""));
+            address = address.wrapping_add(chunk.len() as u16);
+        }
+    }
+
+    text.push_str("\nSymbols\n\n");
+    let mut symbols: Vec<(&String, &u16, &str)> = labels.iter()
+        .map(|(name, value)| (name, value, "label"))
+        .chain(constants.iter().map(|(name, value)| (name, value, "constant")))
+        .filter(|(name, _, _)| !name.starts_with("__"))
+        .collect();
+    symbols.sort_by(|a, b| a.1.cmp(b.1).then(a.0.cmp(b.0)));
+    for (name, value, kind) in symbols {
+        text.push_str(&format!("{:04X}  {:<8}  {}\n", value, kind, name));
+    }
+
+    text
+}
+
+// write_symbols writes the symbol file of entries to target
+pub fn write_symbols(target: &str, entries: &[Entry], labels: &HashMap<String, u16>) -> Result<(), String> {
+    match fs::write(target, symbols(entries, labels)) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Error writing symbols {}: {}", target, e)),
+    }
+}
+
+// symbols returns the text of a symbol file for emulate --symbols, the
+// labels and the source line of each instruction ordered by address
+//
+//   0x0200 main
+//   0x0200 game.s:3
+pub fn symbols(entries: &[Entry], labels: &HashMap<String, u16>) -> String {
+    let mut symbols: Vec<(u16, usize, String)> = labels.iter()
+        .filter(|(name, _)| !name.starts_with("__"))
+        .map(|(name, address)| (*address, 0, name.clone()))
+        .collect();
+    for entry in entries {
+        if let TokenType::Instruction = entry.token.token_type {
+            symbols.push((entry.address, 1, format!("{}:{}", entry.token.file, entry.token.line)));
+        }
+    }
+    symbols.sort();
+
+    symbols.iter()
+        .map(|(address, _, symbol)| format!("0x{:04X} {}\n", address, symbol))
+        .collect()
+}
+
+fn listing_line(address: u16, bytes: &[u8], source: &str) -> String {
+    let bytes: Vec<String> = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+    format!("{:04X}  {:<width$}  {}", address, bytes.join(" "), source,
+        width = BYTES_PER_LINE * 3 - 1).trim_end().to_string() + "\n"
+}
+
+fn file_name(path: &str) -> String {
+    std::path::Path::new(path).file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| path.to_string())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(address: u16, bytes: &[u8], name: &str, token_type: TokenType, line: usize) -> Entry {
+        Entry {
+            address,
+            bytes: bytes.to_vec(),
+            token: Token {
+                name: name.to_string(),
+                token_type,
+                line,
+                args: Vec::new(),
+                file: "src/game.s".to_string(),
+                spans: Vec::new(),
+            },
+        }
+    }
+
+    #[test]
+    fn test_listing() {
+        let sources = HashMap::from([("src/game.s".to_string(), vec![
+            "org 0x200".to_string(),
+            "cls".to_string(),
+            "sprite player".to_string(),
+            "ret".to_string(),
+        ])]);
+        let entries = [
+            entry(0x200, &[], "org", TokenType::Origin, 1),
+            entry(0x200, &[0x00, 0xE0], "cls", TokenType::Instruction, 2),
+            entry(0x202, &[], "player", TokenType::Label, 3),
+            entry(0x202, &[1, 2, 3, 4, 5, 6, 7, 8, 9], "db", TokenType::Data, 3),
+            entry(0x20B, &[0x00, 0xEE], "ret", TokenType::Instruction, 4),
+        ];
+        let labels = HashMap::from([
+            ("player".to_string(), 0x202),
+            ("__anonymous0".to_string(), 0x200),
+        ]);
+        let constants = HashMap::from([("SPEED".to_string(), 2)]);
+
+        assert_eq!(listing(&entries, &sources, &labels, &constants), [
+            "0200  00 E0                    game.s:2     cls",
+            "0202                           game.s:3     sprite player",
+            "0202  01 02 03 04 05 06 07 08",
+            "020A  09",
+            "020B  00 EE                    game.s:4     ret",
+            "",
+            "Symbols",
+            "",
+            "0002  constant  SPEED",
+            "0202  label     player",
+            "",
+        ].join("\n"));
+    }
+
+    #[test]
+    fn test_symbols() {
+        let entries = [
+            entry(0x200, &[], "main", TokenType::Label, 1),
+            entry(0x200, &[0x00, 0xE0], "cls", TokenType::Instruction, 2),
+            entry(0x202, &[0x30, 0x78], "db", TokenType::Data, 3),
+            entry(0x204, &[0x00, 0xEE], "ret", TokenType::Instruction, 4),
+        ];
+        let labels = HashMap::from([
+            ("main".to_string(), 0x200),
+            ("player".to_string(), 0x202),
+            ("__anonymous0".to_string(), 0x204),
+        ]);
+
+        assert_eq!(symbols(&entries, &labels), [
+            "0x0200 main",
+            "0x0200 src/game.s:2",
+            "0x0202 player",
+            "0x0204 src/game.s:4",
+            "",
+        ].join("\n"));
+    }
+
+}
@@ -0,0 +1,316 @@
+use super::token::{Token, TokenType};
+use super::expr::{is_anonymous, is_keyword, is_name, replace_names};
+use super::diagnostic::{Code, Diagnostic};
+use std::collections::HashMap;
+
+// Macros
+//
+// macro draw_at x y sprite
+//   ld v0 x
+//   ld v1 y
+//   ld i sprite
+//   drw v0 v1 0x5
+// endm
+//
+//   draw_at 10 20 player
+//
+// A macro is expanded in place of every line that uses its name, with its
+// parameters replaced by the arguments. Labels and constants defined inside a
+// macro are local to each expansion, so a macro can hold its own loops.
+
+// deepest a macro can expand other macros, to stop runaway recursion
+const MAX_DEPTH: usize = 32;
+
+struct Macro {
+    params: Vec<String>,
+    body: Vec<Token>,
+}
+
+// expand_macros removes the macro definitions from tokens and expands every
+// use of a macro
+pub fn expand_macros(tokens: Vec<Token>) -> Result<Vec<Token>, Vec<Diagnostic>> {
+    let mut macros: HashMap<String, Macro> = HashMap::new();
+    let mut program: Vec<Token> = Vec::new();
+    let mut errors: Vec<Diagnostic> = Vec::new();
+
+    let mut tokens = tokens.into_iter();
+    while let Some(token) = tokens.next() {
+        if token.is_directive("endm") {
+            errors.push(token.error(Code::Block, "endm without macro"));
+            continue;
+        }
+
+        if !token.is_directive("macro") {
+            program.push(token);
+            continue;
+        }
+
+        let mut body: Vec<Token> = Vec::new();
+        let mut closed = false;
+        for body_token in tokens.by_ref() {
+            if body_token.is_directive("endm") {
+                closed = true;
+                break;
+            }
+            if body_token.is_directive("macro") {
+                errors.push(body_token.error(Code::Block, "Macros can not be defined inside a macro"));
+                continue;
+            }
+            body.push(body_token);
+        }
+
+        let name = match token.args.first() {
+            Some(name) => name.clone(),
+            None => {
+                errors.push(token.error(Code::Block, "Missing name for macro"));
+                continue;
+            }
+        };
+
+        if !closed {
+            errors.push(token.error(Code::Block, &format!("Missing endm for macro {}", name)));
+            continue;
+        }
+
+        if macros.contains_key(&name) {
+            errors.push(token.error(Code::Duplicate, &format!("Macro {} already defined.", name)));
+            continue;
+        }
+
+        macros.insert(name, Macro {
+            params: token.args[1..].to_vec(),
+            body,
+        });
+    }
+
+    let mut expansions = 0;
+    let program = expand(program, &macros, &mut expansions, 0, &mut errors);
+
+    if !errors.is_empty() {
+        return Err(errors)
+    }
+
+    Ok(program)
+}
+
+fn expand(tokens: Vec<Token>, macros: &HashMap<String, Macro>, expansions: &mut usize, depth: usize, errors: &mut Vec<Diagnostic>) -> Vec<Token> {
+    let mut program: Vec<Token> = Vec::new();
+
+    for token in tokens {
+        let definition = match token.token_type {
+            TokenType::Instruction => macros.get(&token.name),
+            _ => None,
+        };
+
+        let definition = match definition {
+            Some(definition) => definition,
+            None => {
+                program.push(token);
+                continue;
+            }
+        };
+
+        if depth >= MAX_DEPTH {
+            errors.push(token.error(Code::Block, &format!(
+                "Macro {} expands more than {} levels deep", token.name, MAX_DEPTH)));
+            continue;
+        }
+
+        if token.args.len() != definition.params.len() {
+            errors.push(token.error(Code::Operand, &format!(
+                "Invalid number of arguments for macro {}: expected {}, got {}",
+                token.name, definition.params.len(), token.args.len())));
+            continue;
+        }
+
+        *expansions += 1;
+
+        // parameters become the arguments, local names become unique names
+        let mut names: HashMap<String, String> = definition.params.iter().cloned()
+            .zip(token.args.iter().map(|arg| group(arg)))
+            .collect();
+        for body_token in &definition.body {
+            // anonymous labels are found by position so they need no renaming
+            if is_anonymous(&body_token.name) {
+                continue;
+            }
+            if let TokenType::Label | TokenType::Constant = body_token.token_type {
+                names.insert(body_token.name.clone(),
+                    format!("__{}{}_{}", token.name, expansions, body_token.name));
+            }
+        }
+
+        let body: Vec<Token> = definition.body.iter()
+            .map(|body_token| {
+                let mut body_token = body_token.clone();
+                if let Some(name) = names.get(&body_token.name) {
+                    if let TokenType::Label | TokenType::Constant = body_token.token_type {
+                        body_token.name = name.clone();
+                    }
+                }
+                body_token.args = body_token.args.iter()
+                    .map(|arg| replace_names(arg, &|name| names.get(name).cloned()))
+                    .collect();
+                body_token
+            })
+            .collect();
+
+        program.extend(expand(body, macros, expansions, depth + 1, errors));
+    }
+
+    program
+}
+
+// group wraps an expression argument in parentheses so it keeps its own
+// precedence inside the body, e.g. x * 2 with x = 1+1 becomes (1+1) * 2
+fn group(arg: &str) -> String {
+    let atom = is_name(arg) || is_keyword(arg)
+        || arg.chars().all(|c| c.is_ascii_alphanumeric())
+        || arg.starts_with('"') || arg.starts_with('\'');
+    if atom {
+        arg.to_string()
+    } else {
+        format!("({})", arg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::diagnostic::messages;
+    use super::super::token::fixtures::{instruction, token};
+
+    #[test]
+    fn test_expand_macros() {
+        struct TestCase {
+            name: &'static str,
+            tokens: Vec<Token>,
+            expected: Result<Vec<(String, Vec<String>)>, String>,
+        }
+
+        fn expected(tokens: &[(&str, &[&str])]) -> Result<Vec<(String, Vec<String>)>, String> {
+            Ok(tokens.iter()
+                .map(|(name, args)| (name.to_string(), args.iter().map(|arg| arg.to_string()).collect()))
+                .collect())
+        }
+
+        let test_cases = [
+            TestCase {
+                name: "No macros",
+                tokens: vec![instruction("cls", &[], 0)],
+                expected: expected(&[("cls", &[])]),
+            },
+            TestCase {
+                name: "Parameters",
+                tokens: vec![
+                    instruction("macro", &["draw_at", "x", "y", "sprite"], 0),
+                    instruction("ld", &["v0", "x"], 1),
+                    instruction("ld", &["v1", "y + 1"], 2),
+                    instruction("ld", &["i", "sprite"], 3),
+                    instruction("endm", &[], 4),
+                    instruction("draw_at", &["10", "20", "player"], 5),
+                ],
+                expected: expected(&[
+                    ("ld", &["v0", "10"]),
+                    ("ld", &["v1", "20 + 1"]),
+                    ("ld", &["i", "player"]),
+                ]),
+            },
+            TestCase {
+                name: "Expression arguments keep their precedence",
+                tokens: vec![
+                    instruction("macro", &["dbl", "x"], 0),
+                    instruction("ld", &["v0", "x * 2"], 1),
+                    instruction("endm", &[], 2),
+                    instruction("dbl", &["1+1"], 3),
+                    instruction("dbl", &["-SIZE"], 4),
+                    instruction("dbl", &["0x10"], 5),
+                ],
+                expected: expected(&[
+                    ("ld", &["v0", "(1+1) * 2"]),
+                    ("ld", &["v0", "(-SIZE) * 2"]),
+                    ("ld", &["v0", "0x10 * 2"]),
+                ]),
+            },
+            TestCase {
+                name: "Local labels are unique per expansion",
+                tokens: vec![
+                    instruction("macro", &["wait", "r"], 0),
+                    token("loop", TokenType::Label, &[], 1),
+                    instruction("sne", &["r", "0x0"], 2),
+                    instruction("jmp", &["loop"], 3),
+                    instruction("endm", &[], 4),
+                    instruction("wait", &["v1"], 5),
+                    instruction("wait", &["v2"], 6),
+                ],
+                expected: expected(&[
+                    ("__wait1_loop", &[]),
+                    ("sne", &["v1", "0x0"]),
+                    ("jmp", &["__wait1_loop"]),
+                    ("__wait2_loop", &[]),
+                    ("sne", &["v2", "0x0"]),
+                    ("jmp", &["__wait2_loop"]),
+                ]),
+            },
+            TestCase {
+                name: "Macro using a macro",
+                tokens: vec![
+                    instruction("macro", &["inc", "r"], 0),
+                    instruction("add", &["r", "0x1"], 1),
+                    instruction("endm", &[], 2),
+                    instruction("macro", &["inc16", "hi", "lo"], 3),
+                    instruction("inc", &["lo"], 4),
+                    instruction("se", &["lo", "0x0"], 5),
+                    instruction("inc", &["hi"], 6),
+                    instruction("endm", &[], 7),
+                    instruction("inc16", &["va", "vb"], 8),
+                ],
+                expected: expected(&[
+                    ("add", &["vb", "0x1"]),
+                    ("se", &["vb", "0x0"]),
+                    ("add", &["va", "0x1"]),
+                ]),
+            },
+            TestCase {
+                name: "Wrong number of arguments",
+                tokens: vec![
+                    instruction("macro", &["inc", "r"], 0),
+                    instruction("add", &["r", "0x1"], 1),
+                    instruction("endm", &[], 2),
+                    instruction("inc", &[], 3),
+                ],
+                expected: Err(
+                    "test.s:3: error[E003]: Invalid number of arguments for macro inc: expected 1, got 0".into()),
+            },
+            TestCase {
+                name: "Missing endm",
+                tokens: vec![
+                    instruction("macro", &["inc", "r"], 1),
+                    instruction("add", &["r", "0x1"], 2),
+                ],
+                expected: Err("test.s:1: error[E007]: Missing endm for macro inc".into()),
+            },
+            TestCase {
+                name: "Recursive macro",
+                tokens: vec![
+                    instruction("macro", &["forever"], 0),
+                    instruction("forever", &[], 1),
+                    instruction("endm", &[], 2),
+                    instruction("forever", &[], 3),
+                ],
+                expected: Err(
+                    "test.s:1: error[E007]: Macro forever expands more than 32 levels deep".into()),
+            },
+        ];
+
+        for case in test_cases {
+            let result = expand_macros(case.tokens).map(|tokens| {
+                tokens.into_iter()
+                    .map(|token| (token.name, token.args))
+                    .collect::<Vec<(String, Vec<String>)>>()
+            }).map_err(|errors| messages(&errors));
+            assert_eq!(result, case.expected, "Failed on test case: {}", case.name);
+        }
+    }
+
+}
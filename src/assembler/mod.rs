@@ -6,8 +6,30 @@ pub mod labels;
 pub mod opcodes;
 pub mod registers;
 pub mod arg;
+pub mod data;
+pub mod expr;
+pub mod constants;
+pub mod include;
+pub mod macros;
+pub mod sprite;
+pub mod image;
+pub mod listing;
+pub mod diagnostic;
+pub mod lexer;
+pub mod octo;
+pub mod target;
+pub mod conditions;
+pub mod flow;
+pub mod optimise;
+pub mod lint;
+pub mod format;
 
-pub use assembler::assemble;
+pub use assembler::{assemble, source_lines, AssemblerConfig};
+pub use lint::lint;
+pub use format::format_source;
+pub use target::Target;
+pub use conditions::parse_define;
+pub use diagnostic::{messages, report};
 pub use token::{Token, TokenType};
 pub use origin::get_origin;
 pub use utils::address_from_string;
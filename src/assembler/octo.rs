@@ -0,0 +1,720 @@
+use super::token::{Token, TokenType};
+use super::diagnostic::{Code, Diagnostic, Span};
+use super::registers::Register;
+use super::flow::Condition;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+
+// Octo
+//
+// : main
+//   v0 := 10
+//   loop
+//     v0 += -1
+//     if v0 == 0 then jump done
+//   again
+// : done
+//   clear
+//
+// Sources ending in .8o are read as Octo and translated into the same tokens
+// as the assembler's own syntax, so they are encoded by the same opcodes. The
+// program starts at main, with a jump to it if main is not first.
+//
+// Supported: : labels, :call, :const, :alias, :macro, :calc, :byte, :next,
+// the := += -= =- |= &= ^= >>= <<= assignments to registers, i, delay and
+// buzzer, if ... then, if ... begin ... else ... end, loop ... while ... again,
+// the < > <= >= comparisons through vf, and bare numbers as bytes. The
+// SUPER-CHIP and XO-CHIP hires, lores, exit, scroll-*, plane, audio,
+// saveflags, loadflags, i := bighex, i := long and save/load vx - vy need
+// assemble --target.
+//
+// Octo names may hold characters such as - that are operators here, they are
+// replaced by _. :calc expressions are evaluated by the assembler, with its
+// operators + - * / % and parentheses.
+
+// SCRATCH is the register Octo compares a value in, vf := y then vf -= x or
+// vf =- x, leaving the flag in vf
+const SCRATCH: &str = "vf";
+
+// most macro expansions in one source, to stop runaway recursion
+const MAX_EXPANSIONS: usize = 10000;
+
+// Word is one whitespace separated word of Octo source
+#[derive(Clone)]
+struct Word {
+    text: String,
+    line: usize,
+    span: Span,
+}
+
+struct Macro {
+    params: Vec<String>,
+    body: Vec<Word>,
+}
+
+// Block is an if ... begin or loop waiting for its end or again
+enum Block {
+    If { at: Word, otherwise: String, end: Option<String> },
+    Loop { at: Word, start: String, end: String },
+}
+
+// is_octo returns true for sources written in Octo
+pub fn is_octo(source: &str) -> bool {
+    source.to_lowercase().ends_with(".8o")
+}
+
+// parse_octo reads an Octo source file into tokens
+pub fn parse_octo(source: &str) -> Result<Vec<Token>, Vec<Diagnostic>> {
+    match fs::read_to_string(source) {
+        Ok(text) => translate(source, &text),
+        Err(e) => Err(vec![Diagnostic::new(Code::File, &format!("Error opening {}: {}", source, e))]),
+    }
+}
+
+// translate returns the tokens for the Octo text of file
+fn translate(file: &str, text: &str) -> Result<Vec<Token>, Vec<Diagnostic>> {
+    let mut octo = Octo {
+        file,
+        words: words(text),
+        tokens: Vec::new(),
+        errors: Vec::new(),
+        aliases: HashMap::new(),
+        constants: HashSet::new(),
+        macros: HashMap::new(),
+        blocks: Vec::new(),
+        labels: 0,
+        expansions: 0,
+    };
+
+    while let Some(word) = octo.words.pop_front() {
+        if let Err(e) = octo.statement(word) {
+            octo.errors.push(e);
+        }
+    }
+
+    for block in octo.blocks.drain(..) {
+        let (at, message) = match block {
+            Block::If { at, .. } => (at, "Missing end for if ... begin"),
+            Block::Loop { at, .. } => (at, "Missing again for loop"),
+        };
+        octo.errors.push(Diagnostic::new(Code::Block, message).at(file, at.line, at.span));
+    }
+
+    let main = octo.tokens.iter().position(|token| matches!(token.token_type, TokenType::Label) && token.name == "main");
+    match main {
+        None => octo.errors.push(Diagnostic::new(Code::Syntax, "Octo programs start at main, which is not defined")
+            .at(file, 0, Span::default())),
+        Some(main) => {
+            let first = octo.tokens.iter().position(|token| token.size() != Ok(0) || matches!(token.token_type, TokenType::Label));
+            if first != Some(main) {
+                let at = octo.tokens[main].clone();
+                octo.tokens.insert(0, Token {
+                    name: "jmp".to_string(),
+                    token_type: TokenType::Instruction,
+                    args: vec!["main".to_string()],
+                    ..at
+                });
+            }
+        },
+    }
+
+    if !octo.errors.is_empty() {
+        return Err(octo.errors)
+    }
+
+    Ok(octo.tokens)
+}
+
+// words splits text on whitespace, dropping # comments
+fn words(text: &str) -> VecDeque<Word> {
+    let mut words = VecDeque::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = match line.find('#') {
+            Some(comment) => &line[..comment],
+            None => line,
+        };
+        let mut rest = line;
+        while let Some(start) = rest.find(|c: char| !c.is_whitespace()) {
+            let end = rest[start..].find(char::is_whitespace).map(|end| start + end).unwrap_or(rest.len());
+            let offset = line.len() - rest.len() + start;
+            words.push_back(Word {
+                text: rest[start..end].to_string(),
+                line: i + 1,
+                span: Span {
+                    column: line[..offset].chars().count() + 1,
+                    length: rest[start..end].chars().count(),
+                },
+            });
+            rest = &rest[end..];
+        }
+    }
+    words
+}
+
+// name makes an Octo name into a label or constant name
+fn name(text: &str) -> String {
+    text.chars().map(|c| if c.is_ascii_alphanumeric() || c == '_' || c == '.' { c } else { '_' }).collect()
+}
+
+struct Octo<'a> {
+    file: &'a str,
+    words: VecDeque<Word>,
+    tokens: Vec<Token>,
+    errors: Vec<Diagnostic>,
+    aliases: HashMap<String, String>,  // :alias names for registers
+    constants: HashSet<String>,
+    macros: HashMap<String, Macro>,
+    blocks: Vec<Block>,
+    labels: usize,  // labels made for control flow
+    expansions: usize,
+}
+
+impl Octo<'_> {
+
+    fn statement(&mut self, word: Word) -> Result<(), Diagnostic> {
+        match word.text.as_str() {
+            ":" => {
+                let label = self.next(&word, "a name")?;
+                self.label(&name(&label.text), &label);
+            },
+            ":const" => {
+                let constant = self.next(&word, "a name")?;
+                let value = self.value(&word)?;
+                self.constants.insert(constant.text.clone());
+                self.push(name(&constant.text), TokenType::Constant, vec![value], &constant);
+            },
+            ":calc" => {
+                let constant = self.next(&word, "a name")?;
+                let open = self.next(&word, "{")?;
+                let value = self.calc(&open)?;
+                self.constants.insert(constant.text.clone());
+                self.push(name(&constant.text), TokenType::Constant, vec![value], &constant);
+            },
+            ":alias" => {
+                let alias = self.next(&word, "a name")?;
+                let register = self.register(&word)?;
+                self.aliases.insert(alias.text, register);
+            },
+            ":byte" => {
+                let value = self.value(&word)?;
+                self.push("db".to_string(), TokenType::Data, vec![value], &word);
+            },
+            ":call" => {
+                let target = self.value(&word)?;
+                self.instruction("call", &[&target], &word);
+            },
+            ":next" => {
+                // the label names the second byte of the next instruction,
+                // for code that changes its own operands
+                let next = self.next(&word, "a name")?;
+                let label = self.new_label();
+                self.label(&label, &word);
+                self.push(name(&next.text), TokenType::Constant, vec![format!("{} + 1", label)], &next);
+            },
+            ":macro" => self.define_macro(&word)?,
+            ";" | "return" => self.instruction("ret", &[], &word),
+            "clear" => self.instruction("cls", &[], &word),
+            "jump" => {
+                let target = self.value(&word)?;
+                self.instruction("jmp", &[&target], &word);
+            },
+            "jump0" => {
+                let target = self.value(&word)?;
+                self.instruction("jmp", &["v0", &target], &word);
+            },
+            "hires" => self.instruction("high", &[], &word),
+            "lores" => self.instruction("low", &[], &word),
+            "exit" => self.instruction("exit", &[], &word),
+            "scroll-left" => self.instruction("scl", &[], &word),
+            "scroll-right" => self.instruction("scr", &[], &word),
+            "scroll-down" | "scroll-up" => {
+                let rows = self.value(&word)?;
+                let instruction = if word.text == "scroll-down" { "scd" } else { "scu" };
+                self.instruction(instruction, &[&rows], &word);
+            },
+            "plane" => {
+                let mask = self.value(&word)?;
+                self.instruction("plane", &[&mask], &word);
+            },
+            "audio" => self.instruction("audio", &[], &word),
+            "saveflags" => {
+                let register = self.register(&word)?;
+                self.instruction("ld", &["r", &register], &word);
+            },
+            "loadflags" => {
+                let register = self.register(&word)?;
+                self.instruction("ld", &[&register, "r"], &word);
+            },
+            "bcd" => {
+                let register = self.register(&word)?;
+                self.instruction("ld", &["b", &register], &word);
+            },
+            "save" | "load" => {
+                let register = self.register(&word)?;
+                // save vx - vy is the XO-CHIP range
+                if self.words.front().is_some_and(|next| next.text == "-") {
+                    self.words.pop_front();
+                    let last = self.register(&word)?;
+                    self.instruction(&word.text, &[&format!("{}-{}", register, last)], &word);
+                } else if word.text == "save" {
+                    self.instruction("ld", &["i", &register], &word);
+                } else {
+                    self.instruction("ld", &[&register, "i"], &word);
+                }
+            },
+            "sprite" => {
+                let x = self.register(&word)?;
+                let y = self.register(&word)?;
+                let rows = self.value(&word)?;
+                self.instruction("drw", &[&x, &y, &rows], &word);
+            },
+            "delay" | "buzzer" => {
+                self.expect(&word, ":=")?;
+                let register = self.register(&word)?;
+                let timer = if word.text == "delay" { "dt" } else { "st" };
+                self.instruction("ld", &[timer, &register], &word);
+            },
+            "i" => {
+                let op = self.next(&word, "an assignment")?;
+                match op.text.as_str() {
+                    ":=" => {
+                        let value = self.next(&op, "a value")?;
+                        if value.text == "hex" || value.text == "bighex" {
+                            let register = self.register(&value)?;
+                            let font = if value.text == "hex" { "f" } else { "hf" };
+                            self.instruction("ld", &[font, &register], &word);
+                        } else if value.text == "long" {
+                            let address = self.value(&value)?;
+                            self.instruction("ld", &["i", "long", &address], &word);
+                        } else {
+                            let value = self.operand(&value)?;
+                            self.instruction("ld", &["i", &value], &word);
+                        }
+                    },
+                    "+=" => {
+                        let register = self.register(&op)?;
+                        self.instruction("add", &["i", &register], &word);
+                    },
+                    _ => return Err(self.error(Code::Syntax, &format!("Invalid assignment to i: {}", op.text), &op)),
+                }
+            },
+            "if" => {
+                let condition = self.condition(&word)?;
+                let then = self.next(&word, "then or begin")?;
+                match then.text.as_str() {
+                    "then" => self.skip_unless(&condition, &word),
+                    "begin" => {
+                        let otherwise = self.new_label();
+                        self.skip_unless(&condition.negate(), &word);
+                        self.instruction("jmp", &[&otherwise], &word);
+                        self.blocks.push(Block::If { at: word, otherwise, end: None });
+                    },
+                    _ => return Err(self.error(Code::Syntax, &format!("Expected then or begin, got {}", then.text), &then)),
+                }
+            },
+            "else" => {
+                let end = self.new_label();
+                match self.blocks.last_mut() {
+                    Some(Block::If { otherwise, end: block_end @ None, .. }) => {
+                        *block_end = Some(end.clone());
+                        let otherwise = otherwise.clone();
+                        self.instruction("jmp", &[&end], &word);
+                        self.label(&otherwise, &word);
+                    },
+                    _ => return Err(self.error(Code::Block, "else without if ... begin", &word)),
+                }
+            },
+            "end" => match self.blocks.pop() {
+                Some(Block::If { otherwise, end, .. }) => self.label(&end.unwrap_or(otherwise), &word),
+                block => {
+                    self.blocks.extend(block);
+                    return Err(self.error(Code::Block, "end without if ... begin", &word))
+                },
+            },
+            "loop" => {
+                let start = self.new_label();
+                let end = self.new_label();
+                self.label(&start, &word);
+                self.blocks.push(Block::Loop { at: word, start, end });
+            },
+            "while" => {
+                let condition = self.condition(&word)?;
+                let end = self.blocks.iter().rev().find_map(|block| match block {
+                    Block::Loop { end, .. } => Some(end.clone()),
+                    _ => None,
+                });
+                match end {
+                    Some(end) => {
+                        self.skip_unless(&condition.negate(), &word);
+                        self.instruction("jmp", &[&end], &word);
+                    },
+                    None => return Err(self.error(Code::Block, "while outside of a loop", &word)),
+                }
+            },
+            "again" => match self.blocks.pop() {
+                Some(Block::Loop { start, end, .. }) => {
+                    self.instruction("jmp", &[&start], &word);
+                    self.label(&end, &word);
+                },
+                block => {
+                    self.blocks.extend(block);
+                    return Err(self.error(Code::Block, "again without loop", &word))
+                },
+            },
+            text if self.macros.contains_key(text) => self.expand_macro(&word)?,
+            text if self.is_register(text) => self.assignment(&word)?,
+            text if text.starts_with(|c: char| c.is_ascii_digit() || c == '-') || self.constants.contains(text) => {
+                let value = self.operand(&word)?;
+                self.push("db".to_string(), TokenType::Data, vec![value], &word);
+            },
+            text if text.starts_with(':') => {
+                return Err(self.error(Code::UnknownInstruction, &format!("Unsupported Octo directive {}", text), &word))
+            },
+            text => self.instruction("call", &[&name(text)], &word),
+        }
+
+        Ok(())
+    }
+
+    // assignment reads vX op value
+    fn assignment(&mut self, word: &Word) -> Result<(), Diagnostic> {
+        let x = self.operand(word)?;
+        let op = self.next(word, "an assignment")?;
+        let value = self.next(&op, "a value")?;
+
+        match (op.text.as_str(), value.text.as_str()) {
+            (":=", "random") => {
+                let mask = self.value(&value)?;
+                self.instruction("rnd", &[&x, &mask], word);
+            },
+            (":=", "key") => self.instruction("wkp", &[&x], word),
+            (":=", "delay") => self.instruction("ld", &[&x, "dt"], word),
+            (":=", _) => {
+                let value = self.operand(&value)?;
+                self.instruction("ld", &[&x, &value], word);
+            },
+            ("+=", _) => {
+                let value = self.operand(&value)?;
+                self.instruction("add", &[&x, &value], word);
+            },
+            ("-=", _) if !self.is_register(&value.text) => {
+                let value = self.operand(&value)?;
+                self.instruction("add", &[&x, &format!("(0x100 - ({})) % 0x100", value)], word);
+            },
+            (op, _) => {
+                let instruction = match op {
+                    "-=" => "sub",
+                    "=-" => "subn",
+                    "|=" => "or",
+                    "&=" => "and",
+                    "^=" => "xor",
+                    ">>=" => "shr",
+                    "<<=" => "shl",
+                    _ => return Err(self.error(Code::Syntax, &format!("Invalid assignment {}", op), word)),
+                };
+                let y = self.operand(&value)?;
+                if !self.is_register(&y) {
+                    return Err(self.error(Code::Operand, &format!("Expected a register after {}, got {}", op, value.text), &value))
+                }
+                self.instruction(instruction, &[&x, &y], word);
+            },
+        }
+
+        Ok(())
+    }
+
+    // condition reads vX op value, or vX key and vX -key
+    fn condition(&mut self, word: &Word) -> Result<Condition, Diagnostic> {
+        let x = self.register(word)?;
+        let op = self.next(word, "a comparison")?;
+        match op.text.as_str() {
+            "key" | "-key" => Ok(Condition { x, op: op.text, y: String::new() }),
+            "==" | "!=" | "<" | ">" | "<=" | ">=" => {
+                let y = self.value(&op)?;
+                let condition = Condition { x, op: op.text.clone(), y };
+                condition.check(SCRATCH).map_err(|e| self.error(Code::Syntax, &e, &op))?;
+                Ok(condition)
+            },
+            _ => Err(self.error(Code::Syntax, &format!("Invalid comparison {}", op.text), &op)),
+        }
+    }
+
+    // skip_unless skips the next instruction unless the condition holds
+    fn skip_unless(&mut self, condition: &Condition, at: &Word) {
+        for (name, args) in condition.skip_unless(SCRATCH) {
+            self.push(name.to_string(), TokenType::Instruction, args, at);
+        }
+    }
+
+    fn define_macro(&mut self, word: &Word) -> Result<(), Diagnostic> {
+        let macro_name = self.next(word, "a name")?;
+        let mut params = Vec::new();
+        loop {
+            let param = self.next(word, "{")?;
+            if param.text == "{" {
+                break;
+            }
+            params.push(param.text);
+        }
+
+        let mut body = Vec::new();
+        let mut depth = 1;
+        loop {
+            let body_word = match self.words.pop_front() {
+                Some(body_word) => body_word,
+                None => return Err(self.error(Code::Block, &format!("Missing }} for macro {}", macro_name.text), word)),
+            };
+            match body_word.text.as_str() {
+                "{" => depth += 1,
+                "}" if depth == 1 => break,
+                "}" => depth -= 1,
+                _ => {},
+            }
+            body.push(body_word);
+        }
+
+        if self.macros.contains_key(&macro_name.text) {
+            return Err(self.error(Code::Duplicate, &format!("Macro {} already defined.", macro_name.text), &macro_name))
+        }
+        self.macros.insert(macro_name.text, Macro { params, body });
+        Ok(())
+    }
+
+    // expand_macro puts the body of a macro, with its parameters replaced by
+    // the words following its name, in front of the words still to read
+    fn expand_macro(&mut self, word: &Word) -> Result<(), Diagnostic> {
+        self.expansions += 1;
+        if self.expansions > MAX_EXPANSIONS {
+            return Err(self.error(Code::Block, &format!("Too many macro expansions at {}", word.text), word))
+        }
+
+        let count = self.macros[&word.text].params.len();
+        let mut args: HashMap<String, Word> = HashMap::new();
+        for i in 0..count {
+            let arg = self.next(word, "a macro argument")?;
+            args.insert(self.macros[&word.text].params[i].clone(), arg);
+        }
+
+        let body: Vec<Word> = self.macros[&word.text].body.iter()
+            .map(|body_word| args.get(&body_word.text).cloned().unwrap_or_else(|| body_word.clone()))
+            .collect();
+        for body_word in body.into_iter().rev() {
+            self.words.push_front(body_word);
+        }
+        Ok(())
+    }
+
+    // calc reads an expression up to } as an assembler expression
+    fn calc(&mut self, open: &Word) -> Result<String, Diagnostic> {
+        if open.text != "{" {
+            return Err(self.error(Code::Syntax, &format!("Expected {{, got {}", open.text), open))
+        }
+
+        let mut expr = Vec::new();
+        loop {
+            let word = self.next(open, "}")?;
+            if word.text == "}" {
+                break;
+            }
+            expr.push(self.operand(&word)?);
+        }
+        Ok(expr.join(" "))
+    }
+
+    // value reads the next word as an operand
+    fn value(&mut self, at: &Word) -> Result<String, Diagnostic> {
+        let word = self.next(at, "a value")?;
+        self.operand(&word)
+    }
+
+    // operand is a word as an assembler operand: a register, a number or a
+    // name, or a { } expression
+    fn operand(&mut self, word: &Word) -> Result<String, Diagnostic> {
+        if word.text == "{" {
+            return self.calc(word).map(|expr| format!("({})", expr))
+        }
+        if let Some(register) = self.aliases.get(&word.text) {
+            return Ok(register.clone())
+        }
+        if let Some(number) = word.text.strip_prefix('-').filter(|number| number.starts_with(|c: char| c.is_ascii_digit())) {
+            // negative numbers are bytes, -1 is 0xFF
+            return Ok(format!("(0x100 - {}) % 0x100", number))
+        }
+        if word.text.starts_with(|c: char| c.is_ascii_digit()) || self.is_register(&word.text) {
+            return Ok(word.text.clone())
+        }
+        Ok(match word.text.as_str() {
+            "+" | "-" | "*" | "/" | "%" | "(" | ")" => word.text.clone(),
+            text => name(text),
+        })
+    }
+
+    // register reads the next word as a register, or an alias of one
+    fn register(&mut self, at: &Word) -> Result<String, Diagnostic> {
+        let word = self.next(at, "a register")?;
+        let register = self.operand(&word)?;
+        if !self.is_register(&register) {
+            return Err(self.error(Code::Operand, &format!("Expected a register, got {}", word.text), &word))
+        }
+        Ok(register)
+    }
+
+    fn is_register(&self, text: &str) -> bool {
+        self.aliases.contains_key(text) || Register::get_register(&text.to_lowercase()).is_ok()
+    }
+
+    fn next(&mut self, after: &Word, expected: &str) -> Result<Word, Diagnostic> {
+        match self.words.pop_front() {
+            Some(word) => Ok(word),
+            None => Err(self.error(Code::Syntax, &format!("Expected {} after {}", expected, after.text), after)),
+        }
+    }
+
+    fn expect(&mut self, after: &Word, expected: &str) -> Result<(), Diagnostic> {
+        let word = self.next(after, expected)?;
+        if word.text != expected {
+            return Err(self.error(Code::Syntax, &format!("Expected {}, got {}", expected, word.text), &word))
+        }
+        Ok(())
+    }
+
+    fn new_label(&mut self) -> String {
+        self.labels += 1;
+        format!("__octo_{}", self.labels)
+    }
+
+    fn label(&mut self, label: &str, at: &Word) {
+        self.push(label.to_string(), TokenType::Label, Vec::new(), at);
+    }
+
+    fn instruction(&mut self, instruction: &str, args: &[&str], at: &Word) {
+        self.push(instruction.to_string(), TokenType::Instruction, args.iter().map(|arg| arg.to_string()).collect(), at);
+    }
+
+    fn push(&mut self, name: String, token_type: TokenType, args: Vec<String>, at: &Word) {
+        self.tokens.push(Token {
+            name,
+            token_type,
+            args,
+            line: at.line,
+            file: self.file.to_string(),
+            spans: vec![at.span],
+        });
+    }
+
+    fn error(&self, code: Code, message: &str, at: &Word) -> Diagnostic {
+        Diagnostic::new(code, message).at(self.file, at.line, at.span)
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::diagnostic::messages;
+
+    #[test]
+    fn test_translate() {
+        struct TestCase {
+            name: &'static str,
+            source: &'static str,
+            expected: Result<Vec<&'static str>, String>,
+        }
+
+        let test_cases = [
+            TestCase {
+                name: "Assignments",
+                source: ": main v0 := 5 v1 += -1 v2 := random 0x0F i := hex v0 v3 -= v1 v4 <<= v4 delay := v0",
+                expected: Ok(vec![
+                    "main:", "ld v0 5", "add v1 (0x100 - 1) % 0x100", "rnd v2 0x0F", "ld f v0",
+                    "sub v3 v1", "shl v4 v4", "ld dt v0",
+                ]),
+            },
+            TestCase {
+                name: "SUPER-CHIP and XO-CHIP",
+                source: ": main hires scroll-down 4 i := bighex v1 i := long main save v1 - v3 saveflags v2 plane 3",
+                expected: Ok(vec![
+                    "main:", "high", "scd 4", "ld hf v1", "ld i long main", "save v1-v3", "ld r v2", "plane 3",
+                ]),
+            },
+            TestCase {
+                name: "Jump to main",
+                source: ": draw-player sprite v0 v1 8 ;\n: main draw-player",
+                expected: Ok(vec![
+                    "jmp main", "draw_player:", "drw v0 v1 8", "ret", "main:", "call draw_player",
+                ]),
+            },
+            TestCase {
+                name: "If then and comparisons",
+                source: ": main if v0 == 3 then clear if v1 key then ; if v0 > v1 then jump main",
+                expected: Ok(vec![
+                    "main:", "sne v0 3", "cls", "sknp v1", "ret",
+                    "sub v1 v0", "add v1 v0", "se vf 0", "jmp main",
+                ]),
+            },
+            TestCase {
+                name: "Comparison with a value keeps ve",
+                source: ": main if ve < 3 then clear if v1 >= ve then clear",
+                expected: Ok(vec![
+                    "main:", "ld vf 3", "subn vf ve", "se vf 1", "cls",
+                    "sub v1 ve", "add v1 ve", "se vf 1", "cls",
+                ]),
+            },
+            TestCase {
+                name: "If begin else end",
+                source: ": main if v0 != 0 begin v1 := 1 else v1 := 2 end",
+                expected: Ok(vec![
+                    "main:", "sne v0 0", "jmp __octo_1", "ld v1 1", "jmp __octo_2",
+                    "__octo_1:", "ld v1 2", "__octo_2:",
+                ]),
+            },
+            TestCase {
+                name: "Loop while again",
+                source: ": main loop v0 += 1 while v0 < 10 again",
+                expected: Ok(vec![
+                    "main:", "__octo_1:", "add v0 1", "ld vf 10", "subn vf v0", "se vf 0",
+                    "jmp __octo_2", "jmp __octo_1", "__octo_2:",
+                ]),
+            },
+            TestCase {
+                name: "Directives",
+                source: concat!(
+                    ":const SPEED 2 :alias x v4 :calc FAST { SPEED * 2 }\n",
+                    ":macro move reg { reg += SPEED }\n",
+                    ": main move x :next target v0 := 0 :byte 0x3C SPEED 255 # data\n"),
+                expected: Ok(vec![
+                    "SPEED = 2", "FAST = SPEED * 2", "main:", "add v4 SPEED",
+                    "__octo_1:", "target = __octo_1 + 1", "ld v0 0", "db 0x3C", "db SPEED", "db 255",
+                ]),
+            },
+            TestCase {
+                name: "Errors",
+                source: ": main\n  if v0 == 1 begin\n  v0 |= 3\n  loop\n  :unpack 1 main\n",
+                expected: Err(concat!(
+                    "test.8o:3:9: error[E003]: Expected a register after |=, got 3\n",
+                    "test.8o:5:3: error[E002]: Unsupported Octo directive :unpack\n",
+                    "test.8o:2:3: error[E007]: Missing end for if ... begin\n",
+                    "test.8o:4:3: error[E007]: Missing again for loop").into()),
+            },
+            TestCase {
+                name: "No main",
+                source: ": start clear",
+                expected: Err("test.8o: error[E001]: Octo programs start at main, which is not defined".into()),
+            },
+        ];
+
+        for case in test_cases.iter() {
+            let result = translate("test.8o", case.source)
+                .map(|tokens| tokens.iter().map(|token| match token.token_type {
+                    TokenType::Label => format!("{}:", token.name),
+                    TokenType::Constant => format!("{} = {}", token.name, token.args.join(" ")),
+                    _ => format!("{} {}", token.name, token.args.join(" ")).trim_end().to_string(),
+                }).collect::<Vec<String>>())
+                .map_err(|errors| messages(&errors));
+            let expected = case.expected.clone()
+                .map(|lines| lines.iter().map(|line| line.to_string()).collect::<Vec<String>>());
+            assert_eq!(result, expected, "Failed on test case: {}", case.name);
+        }
+    }
+
+}
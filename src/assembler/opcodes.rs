@@ -4,6 +4,48 @@ use super::utils::get_address;
 use super::registers::Register;
 use super::arg::ArgType;
 
+// Field
+// The immediate operand fields of an opcode
+//   N   - 4-bit nibble, DXYN
+//   NN  - 8-bit byte, 3XNN 4XNN 6XNN 7XNN CXNN
+//   NNN - 12-bit address, 1NNN 2NNN ANNN BNNN
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Field {
+    Nibble,
+    Byte,
+    Address,
+}
+
+impl Field {
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Field::Nibble => "N",
+            Field::Byte => "NN",
+            Field::Address => "NNN",
+        }
+    }
+
+    pub fn max(&self) -> u16 {
+        match self {
+            Field::Nibble => 0xF,
+            Field::Byte => 0xFF,
+            Field::Address => 0xFFF,
+        }
+    }
+
+    // check makes sure value fits in the field of the instruction
+    pub fn check(&self, instruction: &str, value: u16) -> Result<u16, String> {
+        if value > self.max() {
+            return Err(format!(
+                "Invalid {} for {}: expected 0x0 to 0x{:X}, got 0x{:X}",
+                self.name(), instruction, self.max(), value))
+        }
+        Ok(value)
+    }
+
+}
+
 // add
 //
 // fx1e - set I = I + vx
@@ -44,10 +86,7 @@ fn add_vy_or_address(reg_x: Register, arg: ArgType) -> Result<u16, String> {
             0x8004 | (reg_x as u16) << 8 | (reg_y as u16) << 4
         },
         ArgType::Number(byte) => {
-            if byte > 0xFF {
-                return Err("Invalid address for add: expected 0xFF or less".into())
-            }
-            0x7000 | (reg_x as u16) << 8 | byte
+            0x7000 | (reg_x as u16) << 8 | Field::Byte.check("add", byte)?
         },
         _ => {
             return Err("Invalid argument for add: expected register in second argument".into())
@@ -108,7 +147,7 @@ pub fn drw(args: &Vec<String>) -> Result<u16, String> {
     let reg_y = Register::get_register(args[1].as_str())?;
 
     let nibble = args[2].as_str().trim_start_matches("0x");
-    let nibble = match u8::from_str_radix(nibble, 16) {
+    let nibble = match u16::from_str_radix(nibble, 16) {
         Ok(nibble) => nibble,
         Err(_) => {
             return Err(
@@ -119,23 +158,17 @@ pub fn drw(args: &Vec<String>) -> Result<u16, String> {
         }
     };
 
-    if nibble > 0xF {
-        return Err(
-            format!(
-                "Invalid nibble for drw: expected 0xF or less, got {}",
-                nibble
-            ))
-    }
+    let nibble = Field::Nibble.check("drw", nibble)?;
 
-    opcode = 0xD000 | (reg_x as u16) << 8 | (reg_y as u16) << 4 | nibble as u16;
+    opcode = 0xD000 | (reg_x as u16) << 8 | (reg_y as u16) << 4 | nibble;
 
     Ok(opcode)
 }
 
 // ld
 //
-//
-pub fn ld(args: &Vec<String>) -> Result<u16, String> {
+// annn - ld i addr, addr may be a label
+pub fn ld(labels: &HashMap<String, u16>, args: &Vec<String>) -> Result<u16, String> {
 
     if args.len() != 2 {
         return Err(
@@ -146,13 +179,18 @@ pub fn ld(args: &Vec<String>) -> Result<u16, String> {
 
 
     let first_arg = ArgType::new(args[0].as_str())?;
-    let second_arg = ArgType::new(args[1].as_str())?;
+    let second_arg = match labels.get(args[1].as_str()) {
+        Some(address) => ArgType::Number(*address),
+        None => ArgType::new(args[1].as_str())?,
+    };
 
     let result = match first_arg {
         ArgType::IndexRegister(_) => ld_index(second_arg),
         ArgType::SoundTimer(_) => ld_st(second_arg),
         ArgType::DelayTimer(_) => ld_dt(second_arg),
         ArgType::Font(_) => ld_font(second_arg),
+        ArgType::HighFont(_) => ld_high_font(second_arg),
+        ArgType::Flags(_) => ld_flags(second_arg),
         ArgType::BCD(_) => ld_bcd(second_arg),
         ArgType::Register(reg_x) => ld_register(reg_x, second_arg),
         _ => {
@@ -171,15 +209,14 @@ fn ld_register(reg_x: Register, arg: ArgType) -> Result<u16, String> {
         ArgType::IndexRegister(_) => {
             0xF065 | (reg_x as u16) << 8
         },
+        ArgType::Flags(_) => {
+            0xF085 | (reg_x as u16) << 8
+        },
         ArgType::Register(reg_y) => {
             0x8000 | (reg_x as u16) << 8 | (reg_y as u16) << 4
         },
         ArgType::Number(num) => {
-            if num > 0xFF {
-                return Err(
-                    "Invalid second argument for ld: expected 0xFF or less".into())
-            }
-            0x6000 | (reg_x as u16) << 8 | num
+            0x6000 | (reg_x as u16) << 8 | Field::Byte.check("ld", num)?
         }
         _ => {
             return Err("Invalid second argument for ld".into())
@@ -215,16 +252,41 @@ fn ld_font(arg: ArgType) -> Result<u16, String> {
     Ok(result)
 }
 
+// FX30 - ld hf vx, SUPER-CHIP 10 row font character
+fn ld_high_font(arg: ArgType) -> Result<u16, String> {
+    let result = match arg {
+        ArgType::Register(reg) => {
+            0xF030 | (reg as u16) << 8
+        },
+        _ => {
+            return Err("Invalid second argument for ld".into())
+        }
+    };
+
+    Ok(result)
+}
+
+// FX75 - ld r vx, SUPER-CHIP save v0 to vx in the flag registers
+fn ld_flags(arg: ArgType) -> Result<u16, String> {
+    let result = match arg {
+        ArgType::Register(reg) => {
+            0xF075 | (reg as u16) << 8
+        },
+        _ => {
+            return Err("Invalid second argument for ld".into())
+        }
+    };
+
+    Ok(result)
+}
+
 fn ld_index(arg: ArgType) -> Result<u16, String> {
     let result = match arg {
         ArgType::Register(reg) => {
             0xF055 | (reg as u16) << 8
         },
         ArgType::Number(num) => {
-            if num > 0xFFF {
-                return Err("Invalid address for ld: expected 0xFFF or less".into())
-            }
-            0xA000 | num
+            0xA000 | Field::Address.check("ld", num)?
         },
         _ => {
             return Err("Invalid argument for ld".into())
@@ -261,6 +323,30 @@ fn ld_st(arg: ArgType) -> Result<u16, String> {
     Ok(result)
 }
 
+// is_long returns true for the operands of the XO-CHIP ld i long nnnn
+pub fn is_long(args: &[String]) -> bool {
+    args.len() == 3 && args[1].eq_ignore_ascii_case("long")
+}
+
+// F000 NNNN
+//
+// ld i long 0x1234 - XO-CHIP, set i to a 16-bit address, the instruction is
+// followed by the address
+pub fn ld_long(labels: &HashMap<String, u16>, args: &[String]) -> Result<[u16; 2], String> {
+    if !is_long(args) || !args[0].eq_ignore_ascii_case("i") {
+        return Err("Invalid arguments for ld long: expected i long address".into())
+    }
+
+    let address = match labels.get(args[2].as_str()) {
+        Some(address) => *address,
+        None => match ArgType::new(args[2].as_str())? {
+            ArgType::Number(address) => address,
+            _ => return Err("Invalid address for ld long".into()),
+        },
+    };
+
+    Ok([0xF000, address])
+}
 
 // jmp
 //
@@ -276,7 +362,7 @@ pub fn jmp(labels: &HashMap<String, u16>, args: &Vec<String>) -> Result<u16, Str
         1 => {
             let arg = args[0].as_str();
             let address = get_address(labels, arg)?;
-            opcode = 0x1000 | address;
+            opcode = 0x1000 | Field::Address.check("jmp", address)?;
         },
         2 => {
             let register = Register::get_register(args[0].as_str())?;
@@ -286,7 +372,7 @@ pub fn jmp(labels: &HashMap<String, u16>, args: &Vec<String>) -> Result<u16, Str
 
             let address = get_address(labels, args[1].as_str())?;
 
-            opcode = 0xB000 | address;
+            opcode = 0xB000 | Field::Address.check("jmp", address)?;
         },
         _ => {
             return Err(
@@ -326,9 +412,7 @@ pub fn rnd(args: &Vec<String>) -> Result<u16, String> {
         }
     };
 
-    if num > 0xFF {
-        return Err("Invalid address for rnd: expected 0xFF or less".into())
-    }
+    let num = Field::Byte.check("rnd", num)?;
 
     // let register = register.to_u16() << 8;
     let register = (reg_x as u16) << 8;
@@ -350,7 +434,7 @@ pub fn call(labels: &HashMap<String, u16>, args: &Vec<String>) -> Result<u16, St
 
     let address = get_address(labels, args[0].as_str())?;
 
-    let opcode = 0x2000 | address;
+    let opcode = 0x2000 | Field::Address.check("call", address)?;
     Ok(opcode)
 }
 
@@ -446,11 +530,7 @@ pub fn se(args: &Vec<String>) -> Result<u16, String> {
         ArgType::Number(num) => {
             // 3xnn
             // skip if nn is equal to vx
-            if num > 0xFF {
-                return Err(
-                    "Invalid address for se: expected 0xFF or less".into())
-            }   
-            0x3000 | (reg_x as u16) << 8 | num
+            0x3000 | (reg_x as u16) << 8 | Field::Byte.check("se", num)?
         },
         _ => {
             return Err("Invalid second argument for se".into())
@@ -541,11 +621,7 @@ pub fn sne(args: &Vec<String>) -> Result<u16, String> {
         ArgType::Number(num) => {
             // 4XNN
             // skip if nn is not equal to vx
-            if num > 0xFF {
-                return Err(
-                    "Invalid address for sne: expected 0xFF or less".into())
-            }
-            0x4000 | (reg_x as u16) << 8 | num
+            0x4000 | (reg_x as u16) << 8 | Field::Byte.check("sne", num)?
         },
         _ => {
             return Err("Invalid second argument for sne".into())
@@ -634,10 +710,120 @@ pub fn xor(args: &Vec<String>) -> Result<u16, String> {
     Ok(opcode)
 }
 
+// 00CN scd n, 00DN scu n
+//
+// scroll the display down (SUPER-CHIP) or up (XO-CHIP) n rows
+pub fn scroll(name: &str, args: &[String]) -> Result<u16, String> {
+    if args.len() != 1 {
+        return Err(
+            format!("Invalid number of arguments for {}: expected 1, got {}",
+                name, args.len()))
+    }
+
+    let base = if name.eq_ignore_ascii_case("scu") { 0x00D0 } else { 0x00C0 };
+    let rows = match ArgType::new(args[0].as_str())? {
+        ArgType::Number(rows) => Field::Nibble.check(name, rows)?,
+        _ => return Err(format!("Invalid argument for {}: expected a number of rows", name)),
+    };
+
+    Ok(base | rows)
+}
+
+// FN01
+//
+// plane 3 - XO-CHIP, select the bit planes drawn to, 0 to 3
+pub fn plane(args: &[String]) -> Result<u16, String> {
+    if args.len() != 1 {
+        return Err(
+            format!("Invalid number of arguments for plane: expected 1, got {}",
+                args.len()))
+    }
+
+    match ArgType::new(args[0].as_str())? {
+        ArgType::Number(mask) if mask <= 3 => Ok(0xF001 | mask << 8),
+        ArgType::Number(mask) => Err(format!("Invalid plane for plane: expected 0x0 to 0x3, got 0x{:X}", mask)),
+        _ => Err("Invalid argument for plane: expected a plane mask".into()),
+    }
+}
+
+// 5XY2 save vx-vy, 5XY3 load vx-vy
+//
+// XO-CHIP, save or load the registers vx to vy at i, without changing i. The
+// range may also be written as two registers, save vx vy.
+pub fn save_range(name: &str, args: &[String]) -> Result<u16, String> {
+    let (reg_x, reg_y) = match args {
+        [range] => Register::get_range(&range.to_lowercase())?,
+        [first, last] => (
+            Register::get_register(&first.to_lowercase())?,
+            Register::get_register(&last.to_lowercase())?,
+        ),
+        _ => return Err(
+            format!("Invalid number of arguments for {}: expected vx-vy, got {}",
+                name, args.len())),
+    };
+    let base = if name.eq_ignore_ascii_case("load") { 0x5003 } else { 0x5002 };
+
+    Ok(base | (reg_x as u16) << 8 | (reg_y as u16) << 4)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_field_check() {
+        struct TestCase {
+            name: &'static str,
+            field: Field,
+            value: u16,
+            expected: Result<u16, String>,
+        }
+
+        let test_cases = [
+            TestCase {
+                name: "Nibble",
+                field: Field::Nibble,
+                value: 0xF,
+                expected: Ok(0xF),
+            },
+            TestCase {
+                name: "Nibble out of range",
+                field: Field::Nibble,
+                value: 0x20,
+                expected: Err("Invalid N for test: expected 0x0 to 0xF, got 0x20".into()),
+            },
+            TestCase {
+                name: "Byte",
+                field: Field::Byte,
+                value: 0xFF,
+                expected: Ok(0xFF),
+            },
+            TestCase {
+                name: "Byte out of range",
+                field: Field::Byte,
+                value: 0x300,
+                expected: Err("Invalid NN for test: expected 0x0 to 0xFF, got 0x300".into()),
+            },
+            TestCase {
+                name: "Address",
+                field: Field::Address,
+                value: 0xFFF,
+                expected: Ok(0xFFF),
+            },
+            TestCase {
+                name: "Address out of range",
+                field: Field::Address,
+                value: 0x1000,
+                expected: Err("Invalid NNN for test: expected 0x0 to 0xFFF, got 0x1000".into()),
+            },
+        ];
+
+        for test_case in test_cases.iter() {
+            let result = test_case.field.check("test", test_case.value);
+            assert_eq!(result, test_case.expected, "{}", test_case.name);
+        }
+    }
+
     #[test]
     fn test_add() {
         struct TestCase {
@@ -674,7 +860,7 @@ mod tests {
             TestCase {
                 name: "Invalid number",
                 args: vec!["v0".to_string(), "0xFFF".to_string()],
-                expected: Err("Invalid address for add: expected 0xFF or less".into()),
+                expected: Err("Invalid NN for add: expected 0x0 to 0xFF, got 0xFFF".into()),
             },
         ];
 
@@ -734,7 +920,7 @@ mod tests {
             TestCase {
                 name: "Invalid nibble",
                 args: vec!["v0".to_string(), "v1".to_string(), "0x10".to_string()],
-                expected: Err("Invalid nibble for drw: expected 0xF or less, got 16".into()),
+                expected: Err("Invalid N for drw: expected 0x0 to 0xF, got 0x10".into()),
             },
         ];
 
@@ -776,7 +962,7 @@ mod tests {
             TestCase {
                 name: "Invalid number",
                 args: (Register::V0, ArgType::Number(0x1000)),
-                expected: Err("Invalid second argument for ld: expected 0xFF or less".into()),
+                expected: Err("Invalid NN for ld: expected 0x0 to 0xFF, got 0x1000".into()),
             },
         ];
 
@@ -872,6 +1058,72 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_ld() {
+        struct TestCase {
+            name: &'static str,
+            labels: HashMap<String, u16>,
+            args: Vec<String>,
+            expected: Result<u16, String>,
+        }
+
+        let test_cases = [
+            TestCase {
+                name: "Index from address",
+                labels: HashMap::new(),
+                args: vec!["i".into(), "0x2A0".into()],
+                expected: Ok(0xA2A0),
+            },
+            TestCase {
+                name: "Index from label",
+                labels: HashMap::from([("sprite".into(), 0x2A3)]),
+                args: vec!["i".into(), "sprite".into()],
+                expected: Ok(0xA2A3),
+            },
+            TestCase {
+                name: "Index out of range",
+                labels: HashMap::from([("sprite".into(), 0x1000)]),
+                args: vec!["i".into(), "sprite".into()],
+                expected: Err("Invalid NNN for ld: expected 0x0 to 0xFFF, got 0x1000".into()),
+            },
+            TestCase {
+                name: "Unknown label",
+                labels: HashMap::new(),
+                args: vec!["i".into(), "sprite".into()],
+                expected: Err("Error parsing number: invalid digit found in string".into()),
+            },
+            TestCase {
+                name: "Invalid number of arguments",
+                labels: HashMap::new(),
+                args: vec!["i".into()],
+                expected: Err("Invalid number of arguments for ld: expected 2, got 1".into()),
+            },
+            TestCase {
+                name: "High resolution font",
+                labels: HashMap::new(),
+                args: vec!["hf".into(), "v3".into()],
+                expected: Ok(0xF330),
+            },
+            TestCase {
+                name: "Save flags",
+                labels: HashMap::new(),
+                args: vec!["r".into(), "v7".into()],
+                expected: Ok(0xF775),
+            },
+            TestCase {
+                name: "Load flags",
+                labels: HashMap::new(),
+                args: vec!["v7".into(), "r".into()],
+                expected: Ok(0xF785),
+            },
+        ];
+
+        for test_case in test_cases.iter() {
+            let result = ld(&test_case.labels, &test_case.args);
+            assert_eq!(result, test_case.expected, "{}", test_case.name);
+        }
+    }
+
     #[test]
     fn test_jmp() {
         struct TestCase {
@@ -908,6 +1160,26 @@ mod tests {
                 args: vec![ "foo".to_string() ],
                 expected: Ok(0x12ff),
             },
+            TestCase {
+                name: "Label out of range",
+                labels: HashMap::from([
+                        ("foo".to_string(), 0x1002)
+                    ]),
+                args: vec!["v0".to_string(), "foo".to_string()],
+                expected: Err("Invalid NNN for jmp: expected 0x0 to 0xFFF, got 0x1002".into()),
+            },
+            TestCase {
+                name: "Address out of range",
+                labels: HashMap::new(),
+                args: vec!["0x1000".to_string()],
+                expected: Err("Invalid NNN for jmp: expected 0x0 to 0xFFF, got 0x1000".into()),
+            },
+            TestCase {
+                name: "Address out of range with v0",
+                labels: HashMap::new(),
+                args: vec!["v0".to_string(), "0x1000".to_string()],
+                expected: Err("Invalid NNN for jmp: expected 0x0 to 0xFFF, got 0x1000".into()),
+            },
             TestCase {
                 name: "Invalid number of arguments",
                 labels: HashMap::new(),
@@ -973,7 +1245,7 @@ mod tests {
             TestCase {
                 name: "Invalid address",
                 args: vec!["v0".to_string(), "0x100".to_string()],
-                expected: Err("Invalid address for rnd: expected 0xFF or less".into()),
+                expected: Err("Invalid NN for rnd: expected 0x0 to 0xFF, got 0x100".into()),
             },
         ];
 
@@ -1014,6 +1286,12 @@ mod tests {
                 args: vec![ "foo".to_string() ],
                 expected: Ok(0x22ff),
             },
+            TestCase {
+                name: "Address out of range",
+                labels: HashMap::new(),
+                args: vec!["0x1000".to_string()],
+                expected: Err("Invalid NNN for call: expected 0x0 to 0xFFF, got 0x1000".into()),
+            },
         ];
 
         for test_case in test_cases.iter() {
@@ -1119,7 +1397,7 @@ mod tests {
             TestCase {
                 name: "Invalid address - 0x100",
                 args: vec!["v0".to_string(), "0x100".to_string()],
-                expected: Err("Invalid address for se: expected 0xFF or less".into()),
+                expected: Err("Invalid NN for se: expected 0x0 to 0xFF, got 0x100".into()),
             },
         ];
 
@@ -1160,7 +1438,7 @@ mod tests {
             TestCase {
                 name: "Invalid address - 0x100",
                 args: vec!["v0".to_string(), "0x100".to_string()],
-                expected: Err("Invalid address for sne: expected 0xFF or less".into()),
+                expected: Err("Invalid NN for sne: expected 0x0 to 0xFF, got 0x100".into()),
             },
         ];
 
@@ -1346,6 +1624,91 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_ld_long() {
+        let labels = HashMap::from([("table".to_string(), 0x1234)]);
+        let args = |args: &[&str]| -> Vec<String> { args.iter().map(|arg| arg.to_string()).collect() };
+
+        assert_eq!(ld_long(&labels, &args(&["i", "long", "table"])), Ok([0xF000, 0x1234]));
+        assert_eq!(ld_long(&labels, &args(&["i", "long", "0xFFFF"])), Ok([0xF000, 0xFFFF]));
+        assert_eq!(ld_long(&labels, &args(&["v0", "long", "0x200"])),
+            Err("Invalid arguments for ld long: expected i long address".into()));
+    }
+
+    #[test]
+    fn test_schip_xochip() {
+        struct TestCase {
+            name: &'static str,
+            instruction: &'static str,
+            args: Vec<String>,
+            expected: Result<u16, String>,
+        }
+
+        let test_cases = [
+            TestCase {
+                name: "Scroll down",
+                instruction: "scd",
+                args: vec!["0x4".into()],
+                expected: Ok(0x00C4),
+            },
+            TestCase {
+                name: "Scroll up",
+                instruction: "scu",
+                args: vec!["0x2".into()],
+                expected: Ok(0x00D2),
+            },
+            TestCase {
+                name: "Scroll too far",
+                instruction: "scd",
+                args: vec!["0x10".into()],
+                expected: Err("Invalid N for scd: expected 0x0 to 0xF, got 0x10".into()),
+            },
+            TestCase {
+                name: "Plane",
+                instruction: "plane",
+                args: vec!["0x3".into()],
+                expected: Ok(0xF301),
+            },
+            TestCase {
+                name: "Invalid plane",
+                instruction: "plane",
+                args: vec!["0x4".into()],
+                expected: Err("Invalid plane for plane: expected 0x0 to 0x3, got 0x4".into()),
+            },
+            TestCase {
+                name: "Save range",
+                instruction: "save",
+                args: vec!["v1-v3".into()],
+                expected: Ok(0x5132),
+            },
+            TestCase {
+                name: "Load range",
+                instruction: "load",
+                args: vec!["v4 - v2".into()],
+                expected: Ok(0x5423),
+            },
+            TestCase {
+                name: "Save two registers",
+                instruction: "save",
+                args: vec!["v0".into(), "vf".into()],
+                expected: Ok(0x50F2),
+            },
+            TestCase {
+                name: "Save without registers",
+                instruction: "save",
+                args: vec![],
+                expected: Err("Invalid number of arguments for save: expected vx-vy, got 0".into()),
+            },
+        ];
 
+        for test_case in test_cases.iter() {
+            let result = match test_case.instruction {
+                "plane" => plane(&test_case.args),
+                "save" | "load" => save_range(test_case.instruction, &test_case.args),
+                _ => scroll(test_case.instruction, &test_case.args),
+            };
+            assert_eq!(result, test_case.expected, "{}", test_case.name);
+        }
+    }
 
 }
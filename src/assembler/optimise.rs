@@ -0,0 +1,270 @@
+use super::token::{Token, TokenType};
+use std::collections::HashSet;
+
+// Peephole optimisation
+//
+//   jmp next           ; removed, next: follows it
+// next:
+//   jmp step           ; becomes jmp done when step: is jmp done
+//   call draw          ; becomes jmp draw
+//   ret                ; removed
+//   jmp main
+//   cls                ; removed, nothing can reach it
+//
+// Instructions after a skip are left alone, removing or merging them would
+// change what the skip skips. So is the code from a label used by jmp v0 to
+// the next label, a jump table whose entries must keep their size. Removing
+// code moves what follows it, so nothing is removed when a jmp, call or ld i
+// goes to an address, or to a label with an offset, rather than a label. Code
+// that reads or writes its own instructions through i is not seen, so the pass
+// is only run when asked for.
+
+const SKIPS: [&str; 4] = ["se", "sne", "skp", "sknp"];
+
+// optimise rewrites the tokens until there is nothing left to improve, and
+// returns them with the number of bytes saved
+pub fn optimise(mut tokens: Vec<Token>) -> (Vec<Token>, usize) {
+    let mut saved = 0;
+    while let Some(bytes) = step(&mut tokens) {
+        saved += bytes;
+    }
+    (tokens, saved)
+}
+
+// step makes one improvement to tokens and returns the bytes it saved, None
+// when there is none to make
+fn step(tokens: &mut Vec<Token>) -> Option<usize> {
+    let tables = tables(tokens);
+    let fixed = fixed_layout(tokens);
+
+    for i in 0..tokens.len() {
+        if !matches!(tokens[i].token_type, TokenType::Instruction) {
+            continue;
+        }
+
+        let name = tokens[i].name.to_lowercase();
+        let target = match tokens[i].args.as_slice() {
+            [target] => target.clone(),
+            _ => String::new(),
+        };
+
+        // jmp to a jmp, which keeps the size of the program
+        if name == "jmp" && !target.is_empty() {
+            let last = last_target(tokens, &target);
+            if last != target {
+                tokens[i].args[0] = last;
+                return Some(0)
+            }
+        }
+
+        if fixed || after_skip(tokens, i) || in_table(tokens, i, &tables) {
+            continue;
+        }
+        let (labels, next) = following(tokens, i);
+
+        // jmp to the next instruction
+        if name == "jmp" && labels.contains(&target) {
+            tokens.remove(i);
+            return Some(2)
+        }
+
+        // call x; ret
+        if name == "call" && !target.is_empty() && labels.is_empty() {
+            if let Some(next) = next.filter(|next| tokens[*next].is_directive("ret")) {
+                tokens[i].name = "jmp".to_string();
+                tokens.remove(next);
+                return Some(2)
+            }
+        }
+
+        // code after a jmp, ret or exit that nothing jumps to
+        let unconditional = (name == "jmp" && !target.is_empty()) || name == "ret" || name == "exit";
+        if unconditional && labels.is_empty() {
+            if let Some(next) = next.filter(|next| matches!(tokens[*next].token_type, TokenType::Instruction)) {
+                let size = tokens[next].size().unwrap_or(2) as usize;
+                tokens.remove(next);
+                return Some(size)
+            }
+        }
+    }
+
+    None
+}
+
+// following returns the labels after tokens[i], and the index of the token
+// after them, constants take no room and are passed over
+fn following(tokens: &[Token], i: usize) -> (Vec<String>, Option<usize>) {
+    let mut labels: Vec<String> = Vec::new();
+    for (j, token) in tokens.iter().enumerate().skip(i + 1) {
+        match token.token_type {
+            TokenType::Label => labels.push(token.name.clone()),
+            TokenType::Constant => {},
+            _ => return (labels, Some(j)),
+        }
+    }
+    (labels, None)
+}
+
+// after_skip returns true if the instruction at i follows a skip
+fn after_skip(tokens: &[Token], i: usize) -> bool {
+    tokens[..i].iter().rev()
+        .find(|token| !matches!(token.token_type, TokenType::Label | TokenType::Constant))
+        .is_some_and(|token| SKIPS.iter().any(|skip| token.is_directive(skip)))
+}
+
+// fixed_layout returns true if a jmp, call or ld i has a target other than a
+// label, which would go somewhere else once code before it is removed
+fn fixed_layout(tokens: &[Token]) -> bool {
+    let labels: HashSet<&str> = tokens.iter()
+        .filter(|token| matches!(token.token_type, TokenType::Label))
+        .map(|token| token.name.as_str())
+        .collect();
+
+    tokens.iter()
+        .filter(|token| matches!(token.token_type, TokenType::Instruction))
+        .filter_map(|token| {
+            let jumps = token.is_directive("jmp") || token.is_directive("call");
+            let index = token.is_directive("ld") && token.args.first().is_some_and(|arg| arg.eq_ignore_ascii_case("i"));
+            if jumps || (index && token.args.len() > 1) { token.args.last() } else { None }
+        })
+        .any(|target| !labels.contains(target.as_str()))
+}
+
+// tables returns the names used by jmp v0, the labels of jump tables
+fn tables(tokens: &[Token]) -> HashSet<String> {
+    tokens.iter()
+        .filter(|token| token.is_directive("jmp") && token.args.len() == 2)
+        .flat_map(|token| token.args[1].split(|c: char| !(c.is_alphanumeric() || c == '_' || c == '.')))
+        .map(|name| name.to_string())
+        .collect()
+}
+
+// in_table returns true if tokens[i] comes after a jump table label, before
+// any other label
+fn in_table(tokens: &[Token], i: usize, tables: &HashSet<String>) -> bool {
+    tokens[..=i].iter().rev()
+        .find(|token| matches!(token.token_type, TokenType::Label))
+        .is_some_and(|label| tables.contains(&label.name))
+}
+
+// last_target follows a chain of jmps from label to where it ends
+fn last_target(tokens: &[Token], label: &str) -> String {
+    let mut target = label.to_string();
+    let mut seen: HashSet<String> = HashSet::new();
+    while seen.insert(target.clone()) {
+        let at = match tokens.iter().position(|token| matches!(token.token_type, TokenType::Label) && token.name == target) {
+            Some(at) => at,
+            None => break,
+        };
+        let next = match following(tokens, at).1 {
+            Some(next) => &tokens[next],
+            None => break,
+        };
+        match next.args.as_slice() {
+            [next_target] if next.is_directive("jmp") && !seen.contains(next_target) => target = next_target.clone(),
+            _ => break,
+        }
+    }
+    target
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::token::fixtures::{instruction, token};
+
+    // tokens reads name: as a label, db as data and anything else as an
+    // instruction
+    fn tokens(lines: &[&str]) -> Vec<Token> {
+        lines.iter().enumerate().map(|(i, line)| {
+            if let Some(label) = line.strip_suffix(':') {
+                return token(label, TokenType::Label, &[], i + 1)
+            }
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match words.split_first() {
+                Some((&"db", args)) => token("db", TokenType::Data, args, i + 1),
+                Some((name, args)) => instruction(name, args, i + 1),
+                None => instruction("", &[], i + 1),
+            }
+        }).collect()
+    }
+
+    #[test]
+    fn test_optimise() {
+        struct TestCase {
+            name: &'static str,
+            source: Vec<&'static str>,
+            expected: Vec<&'static str>,
+            saved: usize,
+        }
+
+        let test_cases = [
+            TestCase {
+                name: "Jump to the next instruction",
+                source: vec!["cls", "jmp next", "next:", "ret"],
+                expected: vec!["cls", "next:", "ret"],
+                saved: 2,
+            },
+            TestCase {
+                name: "Jump chain",
+                source: vec!["jmp a", "cls", "a:", "jmp b", "b:", "jmp c", "c:", "cls", "jmp c"],
+                expected: vec!["a:", "b:", "c:", "cls", "jmp c"],
+                saved: 8,
+            },
+            TestCase {
+                name: "Jump cycle",
+                source: vec!["a:", "jmp b", "b:", "jmp a"],
+                expected: vec!["a:", "jmp a", "b:", "jmp a"],
+                saved: 0,
+            },
+            TestCase {
+                name: "Tail call",
+                source: vec!["draw:", "call sprite", "ret", "main:", "jmp main", "sprite:", "ret"],
+                expected: vec!["draw:", "jmp sprite", "main:", "jmp main", "sprite:", "ret"],
+                saved: 2,
+            },
+            TestCase {
+                name: "Unreachable code",
+                source: vec!["main:", "jmp main", "cls", "ld i long main", "db 0x1", "ret"],
+                expected: vec!["main:", "jmp main", "db 0x1", "ret"],
+                saved: 6,
+            },
+            TestCase {
+                name: "After a skip",
+                source: vec!["se v0 1", "jmp next", "next:", "sne v1 2", "call draw", "ret", "draw:", "skp v2", "ret", "cls", "ret"],
+                expected: vec!["se v0 1", "jmp next", "next:", "sne v1 2", "call draw", "ret", "draw:", "skp v2", "ret", "cls", "ret"],
+                saved: 0,
+            },
+            TestCase {
+                name: "Jump table",
+                source: vec!["jmp v0 table", "table:", "jmp a", "jmp b", "a:", "jmp b", "cls", "b:", "ret"],
+                expected: vec!["jmp v0 table", "table:", "jmp b", "jmp b", "a:", "b:", "ret"],
+                saved: 4,
+            },
+            TestCase {
+                name: "Jump to an address",
+                source: vec!["jmp next", "next:", "draw:", "jmp 0x20A", "cls"],
+                expected: vec!["jmp 0x20A", "next:", "draw:", "jmp 0x20A", "cls"],
+                saved: 0,
+            },
+            TestCase {
+                name: "Label with an offset",
+                source: vec!["main:", "ld i main+4", "call draw", "ret", "draw:", "ret"],
+                expected: vec!["main:", "ld i main+4", "call draw", "ret", "draw:", "ret"],
+                saved: 0,
+            },
+        ];
+
+        for case in test_cases {
+            let (result, saved) = optimise(tokens(&case.source));
+            let result: Vec<String> = result.iter().map(|token| match token.token_type {
+                TokenType::Label => format!("{}:", token.name),
+                _ => format!("{} {}", token.name, token.args.join(" ")).trim_end().to_string(),
+            }).collect();
+            assert_eq!(result, case.expected, "Failed on test case: {}", case.name);
+            assert_eq!(saved, case.saved, "Failed on test case: {}", case.name);
+        }
+    }
+
+}
@@ -1,32 +1,38 @@
 use super::utils::address_from_string;
 use super::token::{Token, TokenType};
+use super::diagnostic::{Code, Diagnostic};
 
-pub fn get_origin(tokens: &Vec<Token>) -> Result<u16, String> {
+// get_origin returns the origin set by org, 0x200 without one, and the errors
+// in the org lines
+pub fn get_origin(tokens: &Vec<Token>) -> (u16, Vec<Diagnostic>) {
     let mut org_set = false;
     let mut origin: u16 = 0x200;
+    let mut errors: Vec<Diagnostic> = Vec::new();
     for token in tokens {
         match token.token_type {
             TokenType::Origin => {
                 // Make sure we do not have duplicate org settings
                 if org_set {
-                    return Err(
-                        format!("Error on line {}: More than one org set.",
-                        token.line))
+                    errors.push(
+                        token.error(Code::Origin, "More than one org set."));
+                    continue;
                 }
                 org_set = true;
 
                 // ensure we have exactly one value for org
                 if token.args.len() != 1 {
-                    return Err(
-                        format!("Error on line {}: incorrect number of args for org.", 
-                        token.line))
+                    errors.push(
+                        token.error(Code::Origin, "incorrect number of args for org."));
+                    continue;
                 }
 
                 // convert string to address
                 origin = match address_from_string(&token.args[0]) {
                     Ok(origin) => origin,
-                    Err(e) => return Err(
-                        format!("Error on line {}: {}", token.line, e))
+                    Err(e) => {
+                        errors.push(token.arg_error(0, Code::Origin, &e));
+                        continue;
+                    }
                 };
 
             }
@@ -35,14 +41,14 @@ pub fn get_origin(tokens: &Vec<Token>) -> Result<u16, String> {
         }
     }
 
-    Ok(origin)
-
+    (origin, errors)
 }
 
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use super::super::diagnostic::messages;
 
     #[test]
     fn test_get_origin() {
@@ -61,6 +67,8 @@ mod tests {
                         token_type: TokenType::Origin,
                         line: 0,
                         args: vec!["0x200".to_string()],
+                        file: "test.s".to_string(),
+                        spans: Vec::new(),
                     }
                 ],
                 expected: Result::Ok(0x200),
@@ -73,12 +81,16 @@ mod tests {
                         token_type: TokenType::Origin,
                         line: 0,
                         args: vec!["0x200".to_string()],
+                        file: "test.s".to_string(),
+                        spans: Vec::new(),
                     },
                     Token {
                         name: "foo".to_string(),
                         token_type: TokenType::Label,
                         line: 1,
                         args: Vec::new(),
+                        file: "test.s".to_string(),
+                        spans: Vec::new(),
                     }
                 ],
                 expected: Result::Ok(0x200),
@@ -91,12 +103,16 @@ mod tests {
                         token_type: TokenType::Label,
                         line: 0,
                         args: Vec::new(),
+                        file: "test.s".to_string(),
+                        spans: Vec::new(),
                     },
                     Token {
                         name: "org".to_string(),
                         token_type: TokenType::Origin,
                         line: 1,
                         args: vec!["0x200".to_string()],
+                        file: "test.s".to_string(),
+                        spans: Vec::new(),
                     }
                 ],
                 expected: Result::Ok(0x200),
@@ -107,12 +123,14 @@ mod tests {
                     Token {
                         name: "org".to_string(),
                         token_type: TokenType::Origin,
-                        line: 0,
+                        line: 1,
                         args: vec!["0x200".to_string(), "0x200".to_string()],
+                        file: "test.s".to_string(),
+                        spans: Vec::new(),
                     }
                 ],
                 expected: Result::Err(
-                    "Error on line 0: incorrect number of args for org.".to_string()),
+                    "test.s:1: error[E008]: incorrect number of args for org.".to_string()),
             },
             TestCase {
                 name: "Not enough args",
@@ -120,18 +138,23 @@ mod tests {
                     Token {
                         name: "org".to_string(),
                         token_type: TokenType::Origin,
-                        line: 0,
+                        line: 1,
                         args: Vec::new(),
+                        file: "test.s".to_string(),
+                        spans: Vec::new(),
                     }
                 ],
                 expected: Result::Err(
-                    "Error on line 0: incorrect number of args for org.".to_string()),
+                    "test.s:1: error[E008]: incorrect number of args for org.".to_string()),
             }
 
         ];
 
         for case in test_cases.iter() {
-            let result = get_origin(&case.tokens);
+            let result = match get_origin(&case.tokens) {
+                (origin, errors) if errors.is_empty() => Ok(origin),
+                (_, errors) => Err(messages(&errors)),
+            };
             assert_eq!(result, case.expected, "Failed on test case: {}", case.name);
         }
 
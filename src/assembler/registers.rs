@@ -44,6 +44,18 @@ impl Register {
         }
     }
 
+    // get_range reads a range of registers such as v1-v3 for the XO-CHIP
+    // save and load
+    pub fn get_range(name: &str) -> Result<(Register, Register), String> {
+        match name.split_once('-') {
+            Some((first, last)) => Ok((
+                Register::get_register(first.trim())?,
+                Register::get_register(last.trim())?,
+            )),
+            None => Err(format!("Invalid register range: {}, expected vx-vy", name)),
+        }
+    }
+
 }
 
 
@@ -80,4 +92,12 @@ mod tests {
 
     }
 
+    #[test]
+    fn test_get_range() {
+        assert_eq!(Register::get_range("v1-v3"), Ok((Register::V1, Register::V3)));
+        assert_eq!(Register::get_range("va - v2"), Ok((Register::VA, Register::V2)));
+        assert_eq!(Register::get_range("v1"), Err("Invalid register range: v1, expected vx-vy".into()));
+        assert_eq!(Register::get_range("v1-vg"), Err("Invalid register name: vg".into()));
+    }
+
 }
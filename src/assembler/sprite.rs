@@ -0,0 +1,210 @@
+use super::token::{Token, TokenType};
+use super::diagnostic::{Code, Diagnostic};
+
+// Sprites
+//
+// sprite player
+//   ..XX....
+//   .XXXX...
+//   ..XX....
+// endsprite
+//
+// A sprite block assembles to the bytes of the sprite, with a label pointing
+// at them. X or 1 is a set pixel, . or 0 is clear. Rows are 8 pixels wide, up
+// to 15 rows for DXYN, or 16 pixels wide and 16 rows for the SCHIP DXY0 16x16
+// sprite, two bytes a row.
+
+// expand_sprites replaces the sprite blocks in tokens with a label and the
+// sprite data
+pub fn expand_sprites(tokens: Vec<Token>) -> Result<Vec<Token>, Vec<Diagnostic>> {
+    let mut program: Vec<Token> = Vec::new();
+    let mut errors: Vec<Diagnostic> = Vec::new();
+
+    let mut tokens = tokens.into_iter();
+    while let Some(token) = tokens.next() {
+        if token.is_directive("endsprite") {
+            errors.push(token.error(Code::Block, "endsprite without sprite"));
+            continue;
+        }
+
+        if !token.is_directive("sprite") {
+            program.push(token);
+            continue;
+        }
+
+        let mut rows: Vec<Token> = Vec::new();
+        let mut closed = false;
+        for row in tokens.by_ref() {
+            if row.is_directive("endsprite") {
+                closed = true;
+                break;
+            }
+            rows.push(row);
+        }
+
+        if token.args.len() != 1 {
+            errors.push(token.error(Code::Operand, &format!(
+                "Invalid number of arguments for sprite: expected 1, got {}", token.args.len())));
+            continue;
+        }
+
+        if !closed {
+            errors.push(token.error(Code::Block, &format!("Missing endsprite for sprite {}", token.args[0])));
+            continue;
+        }
+
+        match sprite_bytes(&rows) {
+            Ok(bytes) => {
+                program.push(Token {
+                    name: token.args[0].clone(),
+                    token_type: TokenType::Label,
+                    line: token.line,
+                    args: Vec::new(),
+                    file: token.file.clone(),
+                    spans: vec![token.spans.get(1).copied().unwrap_or_default()],
+                });
+                program.push(Token {
+                    name: "db".to_string(),
+                    token_type: TokenType::Data,
+                    line: token.line,
+                    args: bytes.iter().map(|byte| format!("0x{:02X}", byte)).collect(),
+                    file: token.file,
+                    spans: token.spans,
+                });
+            },
+            Err(e) => errors.push(token.error(Code::Block, &format!("Invalid sprite {}: {}", token.args[0], e))),
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(errors)
+    }
+
+    Ok(program)
+}
+
+// sprite_bytes converts the rows of a sprite to bytes
+fn sprite_bytes(rows: &[Token]) -> Result<Vec<u8>, String> {
+    if rows.is_empty() {
+        return Err("no rows".into())
+    }
+
+    let width = rows[0].name.len();
+    match (width, rows.len()) {
+        (8, 1..=15) | (16, 16) => {},
+        (8, _) => return Err(format!("expected 1 to 15 rows 8 pixels wide, got {}", rows.len())),
+        (16, _) => return Err(format!("expected 16 rows 16 pixels wide, got {}", rows.len())),
+        _ => return Err(format!("expected rows 8 or 16 pixels wide, got {}", width)),
+    }
+
+    let mut bytes: Vec<u8> = Vec::new();
+    for row in rows {
+        if !row.args.is_empty() || row.name.len() != width {
+            return Err(format!("row on line {} is not {} pixels wide", row.line, width))
+        }
+
+        let mut pixels: u16 = 0;
+        for c in row.name.chars() {
+            pixels = pixels << 1 | match c {
+                'X' | 'x' | '1' => 1,
+                '.' | '0' => 0,
+                _ => return Err(format!("invalid pixel {} on line {}", c, row.line)),
+            };
+        }
+
+        if width == 16 {
+            bytes.push((pixels >> 8) as u8);
+        }
+        bytes.push(pixels as u8);
+    }
+
+    Ok(bytes)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::diagnostic::messages;
+    use super::super::token::fixtures::instruction;
+
+    #[test]
+    fn test_sprite_bytes() {
+        struct TestCase {
+            name: &'static str,
+            rows: Vec<Token>,
+            expected: Result<Vec<u8>, String>,
+        }
+
+        let test_cases = [
+            TestCase {
+                name: "8 wide",
+                rows: vec![
+                    instruction("..XX....", &[], 1),
+                    instruction(".XXXX...", &[], 2),
+                    instruction("11111111", &[], 3),
+                ],
+                expected: Ok(vec![0x30, 0x78, 0xFF]),
+            },
+            TestCase {
+                name: "16x16",
+                rows: (0..16).map(|i| instruction("X..............X", &[], i)).collect(),
+                expected: Ok([0x80, 0x01].repeat(16)),
+            },
+            TestCase {
+                name: "No rows",
+                rows: vec![],
+                expected: Err("no rows".into()),
+            },
+            TestCase {
+                name: "Too many rows",
+                rows: (0..16).map(|i| instruction("XXXXXXXX", &[], i)).collect(),
+                expected: Err("expected 1 to 15 rows 8 pixels wide, got 16".into()),
+            },
+            TestCase {
+                name: "Short row",
+                rows: vec![instruction("..XX....", &[], 1), instruction(".XX", &[], 2)],
+                expected: Err("row on line 2 is not 8 pixels wide".into()),
+            },
+            TestCase {
+                name: "Invalid width",
+                rows: vec![instruction("..XX", &[], 1)],
+                expected: Err("expected rows 8 or 16 pixels wide, got 4".into()),
+            },
+            TestCase {
+                name: "Invalid pixel",
+                rows: vec![instruction("..XX..o.", &[], 1)],
+                expected: Err("invalid pixel o on line 1".into()),
+            },
+        ];
+
+        for case in test_cases.iter() {
+            let result = sprite_bytes(&case.rows);
+            assert_eq!(result, case.expected, "Failed on test case: {}", case.name);
+        }
+    }
+
+    #[test]
+    fn test_expand_sprites() {
+        let tokens = vec![
+            instruction("cls", &[], 0),
+            instruction("sprite", &["player"], 1),
+            instruction("..XX....", &[], 2),
+            instruction(".XXXX...", &[], 3),
+            instruction("endsprite", &[], 4),
+        ];
+
+        let result: Vec<(String, Vec<String>)> = expand_sprites(tokens).unwrap().into_iter()
+            .map(|token| (token.name, token.args))
+            .collect();
+        assert_eq!(result, vec![
+            ("cls".to_string(), vec![]),
+            ("player".to_string(), vec![]),
+            ("db".to_string(), vec!["0x30".to_string(), "0x78".to_string()]),
+        ]);
+
+        let missing = expand_sprites(vec![instruction("endsprite", &[], 1)]).err().map(|errors| messages(&errors));
+        assert_eq!(missing, Some("test.s:1: error[E007]: endsprite without sprite".to_string()));
+    }
+
+}
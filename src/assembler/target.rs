@@ -0,0 +1,87 @@
+use super::arg::ArgType;
+use super::opcodes::is_long;
+
+// Target
+// The platform a program is assembled for, each has the instructions of the
+// ones before it
+//   chip8  - the original instruction set
+//   schip  - SUPER-CHIP: scd scr scl exit low high, drw vx vy 0, ld hf vx,
+//            ld r vx and ld vx r
+//   xochip - XO-CHIP: plane, audio, ld i long nnnn, save vx-vy, load vx-vy
+//            and scu
+#[derive(PartialEq, PartialOrd, Debug, Clone, Copy)]
+pub enum Target {
+    Chip8,
+    Schip,
+    XoChip,
+}
+
+impl Target {
+
+    pub fn get_target(name: &str) -> Result<Target, String> {
+        match name {
+            "chip8" => Ok(Target::Chip8),
+            "schip" => Ok(Target::Schip),
+            "xochip" => Ok(Target::XoChip),
+            _ => Err(format!("Invalid target: {}, expected chip8, schip or xochip", name)),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Target::Chip8 => "chip8",
+            Target::Schip => "schip",
+            Target::XoChip => "xochip",
+        }
+    }
+
+    // required returns the first target with the instruction, args are the
+    // resolved operands
+    pub fn required(instruction: &str, args: &[String]) -> Target {
+        let arg = |i: usize| args.get(i).map(|arg| arg.to_lowercase()).unwrap_or_default();
+        match instruction.to_lowercase().as_str() {
+            "scd" | "scr" | "scl" | "exit" | "low" | "high" => Target::Schip,
+            "plane" | "audio" | "scu" | "save" | "load" => Target::XoChip,
+            "drw" if ArgType::new(&arg(2)) == Ok(ArgType::Number(0)) => Target::Schip,
+            "ld" if is_long(args) => Target::XoChip,
+            "ld" if ["hf", "r"].contains(&arg(0).as_str()) || arg(1) == "r" => Target::Schip,
+            _ => Target::Chip8,
+        }
+    }
+
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_required() {
+        struct TestCase {
+            instruction: &'static str,
+            args: Vec<&'static str>,
+            expected: Target,
+        }
+
+        let test_cases = [
+            TestCase { instruction: "cls", args: vec![], expected: Target::Chip8 },
+            TestCase { instruction: "drw", args: vec!["v0", "v1", "0x5"], expected: Target::Chip8 },
+            TestCase { instruction: "drw", args: vec!["v0", "v1", "0x0"], expected: Target::Schip },
+            TestCase { instruction: "SCD", args: vec!["0x4"], expected: Target::Schip },
+            TestCase { instruction: "ld", args: vec!["hf", "v2"], expected: Target::Schip },
+            TestCase { instruction: "ld", args: vec!["v2", "r"], expected: Target::Schip },
+            TestCase { instruction: "ld", args: vec!["i", "long", "0x1234"], expected: Target::XoChip },
+            TestCase { instruction: "ld", args: vec!["i", "0x234"], expected: Target::Chip8 },
+            TestCase { instruction: "save", args: vec!["v1-v3"], expected: Target::XoChip },
+            TestCase { instruction: "plane", args: vec!["0x3"], expected: Target::XoChip },
+        ];
+
+        for case in test_cases.iter() {
+            let args: Vec<String> = case.args.iter().map(|arg| arg.to_string()).collect();
+            assert_eq!(Target::required(case.instruction, &args), case.expected,
+                "Failed on {} {}", case.instruction, case.args.join(" "));
+        }
+    }
+
+}
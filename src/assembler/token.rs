@@ -1,10 +1,15 @@
+use super::data::data_size;
+use super::opcodes::is_long;
+use super::diagnostic::{Code, Diagnostic, Span};
+
 #[derive(Clone)]
 pub struct Token {
     pub name: String,
     pub token_type: TokenType,
     pub args: Vec<String>,
-    pub line: usize,
-
+    pub line: usize,  // 1-based
+    pub file: String,
+    pub spans: Vec<Span>,  // columns of the name and of each argument, if known
 }
 
 impl Token {
@@ -14,10 +19,50 @@ impl Token {
             TokenType::Instruction => "Instruction",
             TokenType::Label => "Label",
             TokenType::Origin => "Origin",
+            TokenType::Data => "Data",
+            TokenType::Constant => "Constant",
         };
 
-        format!("Token {} of type {} on line {} with args {}", 
-            self.name, token_type, self.line, self.args.join(" "))
+        format!("Token {} of type {} in {} on line {} with args {}", 
+            self.name, token_type, self.file, self.line, self.args.join(" "))
+    }
+
+    // error places a diagnostic on the token
+    pub fn error(&self, code: Code, message: &str) -> Diagnostic {
+        Diagnostic::new(code, message).at(&self.file, self.line, self.span())
+    }
+
+    // arg_error places a diagnostic on one argument of the token
+    pub fn arg_error(&self, arg: usize, code: Code, message: &str) -> Diagnostic {
+        let span = self.spans.get(arg + 1).copied().unwrap_or_else(|| self.span());
+        Diagnostic::new(code, message).at(&self.file, self.line, span)
+    }
+
+    // span covers the name and arguments of the token
+    pub fn span(&self) -> Span {
+        match (self.spans.first(), self.spans.last()) {
+            (Some(first), Some(last)) if first.column > 0 => Span {
+                column: first.column,
+                length: last.column + last.length - first.column,
+            },
+            _ => Span::default(),
+        }
+    }
+
+    // is_directive returns true if the token is the directive, such as macro
+    // or endm, that the parser reads as an instruction
+    pub fn is_directive(&self, directive: &str) -> bool {
+        matches!(self.token_type, TokenType::Instruction) && self.name.eq_ignore_ascii_case(directive)
+    }
+
+    // size returns the number of bytes the token takes up in the program
+    pub fn size(&self) -> Result<u16, String> {
+        match self.token_type {
+            TokenType::Instruction if self.name.eq_ignore_ascii_case("ld") && is_long(&self.args) => Ok(4),
+            TokenType::Instruction => Ok(2),
+            TokenType::Data => data_size(&self.name, &self.args),
+            _ => Ok(0),
+        }
     }
 }
 
@@ -26,4 +71,26 @@ pub enum TokenType {
     Instruction,
     Label,
     Origin,
-}
\ No newline at end of file
+    Data,
+    Constant,
+}
+// Tokens for tests, from test.s and without columns
+#[cfg(test)]
+pub mod fixtures {
+    use super::{Token, TokenType};
+
+    pub fn token(name: &str, token_type: TokenType, args: &[&str], line: usize) -> Token {
+        Token {
+            name: name.to_string(),
+            token_type,
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+            line,
+            file: "test.s".to_string(),
+            spans: Vec::new(),
+        }
+    }
+
+    pub fn instruction(name: &str, args: &[&str], line: usize) -> Token {
+        token(name, TokenType::Instruction, args, line)
+    }
+}
@@ -2,14 +2,8 @@ use std::collections::HashMap;
 
 // address_from_string converts a string to a u16 address
 pub fn address_from_string(s: &str) -> Result<u16, String> {
+    let address = parse_address(s)?;
     let s = s.trim_start_matches("0x");
-    let address = match u16::from_str_radix(s, 16) {
-        Ok(address) => address,
-        Err(e) => {
-           return Err(
-            format!("Error parsing address {} with error: {}", s, e))
-        },
-    };
 
     if address > 0xFFF {
         return Err(
@@ -24,15 +18,29 @@ pub fn address_from_string(s: &str) -> Result<u16, String> {
     Ok(address)
 }
 
+// parse_address reads a hex address without checking its range, which is left
+// to the instruction it is used by
+fn parse_address(s: &str) -> Result<u16, String> {
+    let s = s.trim_start_matches("0x");
+    match u16::from_str_radix(s, 16) {
+        Ok(address) => Ok(address),
+        Err(e) => Err(format!("Error parsing address {} with error: {}", s, e)),
+    }
+}
+
+// get_address returns the address of a label or a hex address, the caller
+// checks it fits its instruction
 pub fn get_address(labels: &HashMap<String, u16>, arg: &str) -> Result<u16, String> {
     let address = match labels.get(arg) {
         Some(address) => *address,
         None => {
-            match address_from_string(arg) {
-                Ok(address) => address,
-                Err(e) => return Err(e),
+            let address = parse_address(arg)?;
+            if address % 2 != 0 {
+                return Err(
+                    format!("Error parsing address {}: address must be even", arg.trim_start_matches("0x")))
             }
-        }
+            address
+        },
     };
     Ok(address)
 }
@@ -68,10 +76,16 @@ mod tests {
                 expected: Ok(0x200),
             },
             TestCase {
-                name: "Invalid address",
+                name: "Address out of range",
                 labels: HashMap::new(),
-                arg: "0xFFFF",
-                expected: Err("Error parsing address FFFF: address out of range".into()),
+                arg: "0x1000",
+                expected: Ok(0x1000),
+            },
+            TestCase {
+                name: "Odd address",
+                labels: HashMap::new(),
+                arg: "0x201",
+                expected: Err("Error parsing address 201: address must be even".into()),
             },
             TestCase {
                 name: "Invalid address",
@@ -1,7 +1,7 @@
 extern crate sdl2;
 
 use std::collections::HashMap;
-use std::{fs, io};
+use std::fs;
 use std::time::{Duration, Instant};
 
 use sdl2::event::Event;
@@ -17,7 +17,13 @@ use sdl2::Sdl;
 
 use rand::Rng;
 
-use log::{info, debug};
+use super::romdb::{self, RomInfo};
+use super::error::{Chip8Error, ErrorPolicy, UnknownOpcode, UnknownOpcodePolicy};
+use super::profiler::Profiler;
+use super::coverage::Coverage;
+use super::symbols::Symbols;
+
+use log::{info, warn, error, debug};
 // use log::{info, warn, error, debug, trace};
 // use log::info;
 
@@ -25,12 +31,53 @@ const SCREEN_WIDTH: u32 = 64;
 const SCREEN_HEIGHT: u32 = 32;
 const KEY_COUNT: usize = 16;
 
-const SET_VX_FROM_VY_IN_SHIFT: bool = false;
+const FRAME_TIME: Duration = Duration::from_millis(16);
+
+// settings used when neither the config nor the rom database gives one
+const DEFAULT_TICKRATE: u32 = 30;
+const DEFAULT_FOREGROUND: u32 = 0xFFFFFF;
+const DEFAULT_BACKGROUND: u32 = 0x000000;
+
+// Quirks
+// Behaviours that differ between interpreters. Programs written for one
+// interpreter may rely on them, see the notes on 8XY6 and BNNN.
+#[derive(Debug, Clone, Copy)]
+pub struct Quirks {
+    pub set_vx_from_vy_in_shift: bool,  // 8XY6/8XYE copy VY into VX before shifting
+    pub jump_with_vx: bool,  // BNNN behaves as BXNN and jumps to XNN + VX
+}
+
+impl Quirks {
+    pub fn new() -> Self {
+        Quirks {
+            set_vx_from_vy_in_shift: false,
+            jump_with_vx: false,
+        }
+    }
+}
+
+// SysHandler
+// Emulates the machine code routine called by 0NNN. It is given the address of
+// the routine, the registers, the index register and memory, and returns false
+// if it does not know the routine.
+pub type SysHandler = fn(address: u16, v: &mut [u8; 16], i: &mut u16, memory: &mut [u8; 4096]) -> bool;
 
 // Chip8
 pub struct Chip8Config {
     pub display_scale: u32,
     pub program: String,
+    // quirks, tickrate and colours left as None come from the rom database if
+    // the program is known, and the defaults otherwise
+    pub quirks: Option<Quirks>,
+    pub tickrate: Option<u32>,  // instructions executed per 60hz frame
+    pub foreground: Option<u32>,  // 0xRRGGBB colour of pixels that are on
+    pub background: Option<u32>,  // 0xRRGGBB colour of pixels that are off
+    pub use_rom_database: bool,  // look the program up in the rom database
+    pub error_policy: ErrorPolicy,  // what to do when the program hits an error
+    pub unknown_opcode: Option<UnknownOpcodePolicy>,  // overrides error_policy for unknown opcodes
+    pub sys_handler: Option<SysHandler>,  // handles 0NNN when unknown_opcode is sys
+    pub profile: bool,  // collect execution counts while running
+    pub coverage: bool,  // record executed instructions and skips taken
 }
 
 impl Chip8Config {
@@ -40,6 +87,16 @@ impl Chip8Config {
             display_scale: 10,
             // program: "roms/ibm-logo.ch8".to_string(),
             program: "roms/test_opcode.ch8".to_string(),
+            quirks: None,
+            tickrate: None,
+            foreground: None,
+            background: None,
+            use_rom_database: true,
+            error_policy: ErrorPolicy::Halt,
+            unknown_opcode: None,
+            sys_handler: None,
+            profile: false,
+            coverage: false,
         }
     }
 
@@ -47,6 +104,15 @@ impl Chip8Config {
     pub fn log(&self) {
         info!("Chip8Config");
         info!("  display_scale: {}", self.display_scale);
+        info!("  quirks: {:?}", self.quirks);
+        info!("  tickrate: {:?}", self.tickrate);
+        info!("  foreground: {:?}", self.foreground.map(|colour| format!("0x{:06X}", colour)));
+        info!("  background: {:?}", self.background.map(|colour| format!("0x{:06X}", colour)));
+        info!("  use_rom_database: {}", self.use_rom_database);
+        info!("  error_policy: {:?}", self.error_policy);
+        info!("  unknown_opcode: {:?}", self.unknown_opcode);
+        info!("  profile: {}", self.profile);
+        info!("  coverage: {}", self.coverage);
     }
 
 }
@@ -55,9 +121,10 @@ pub struct Chip8 {
     memory: [u8; 4096],  // chip-8 has direct access to up to 4Kib of Ram
     display: [bool; (SCREEN_WIDTH * SCREEN_HEIGHT) as usize], // 64x32 pixels - monochrome 
     keys: Keys,  // 16 keys, 0-F
-    key_map: HashMap<Keycode, usize>,
     display_scale: u32,
     pc: u16,  // program counter which points at the current instruction in memory
+    opcode: u16,  // the instruction being executed, used to report errors
+    opcode_address: u16,  // the address the instruction being executed was fetched from
     stack: Stack,  // stack for 16-bit addresses which is used to call subroutines/functions
                          // and return from them
     i: u16,  // index register which is used to point at locations in memory
@@ -69,14 +136,34 @@ pub struct Chip8 {
     sound_timer: u8, // an 8 bit sound timer which functions like the delay timer, but which also
                      // gives off a beeping sound as long as its not 0
     program: String,
+    quirks: Quirks,
+    tickrate: u32,
+    foreground: Color,
+    background: Color,
+    rom: Option<&'static RomInfo>,  // the program's entry in the rom database
+    error_policy: ErrorPolicy,
+    unknown_opcode: Option<UnknownOpcodePolicy>,
+    sys_handler: Option<SysHandler>,
+    unknown_opcodes: Vec<UnknownOpcode>,  // distinct unknown opcodes executed so far
+    paused: bool,  // set when breaking on an unknown opcode
+    profiler: Option<Profiler>,
+    coverage: Option<Coverage>,
+    symbols: Symbols,  // labels and source lines used to name addresses in messages
+    frontend: Option<Frontend>,  // the window, sound and keys, None when running headless
+}
+
+// Frontend
+// The sdl window, sound and key mapping the emulator runs in
+struct Frontend {
     sdl_context: Sdl,
     canvas: Canvas<Window>,
     audio_device: AudioDevice<SquareWave>,
+    key_map: HashMap<Keycode, usize>,
 }
 
 impl Chip8 {
 
-    pub fn new(config: Option<Chip8Config>) -> Self {
+    pub fn new(config: Option<Chip8Config>) -> Result<Self, Chip8Error> {
 
         // if config is none, set a default config
         let config = match config {
@@ -87,15 +174,24 @@ impl Chip8 {
         config.log();
 
         // initialize sdl2
-        // TODO: this is maybe a bit much to be returning... Maybe a return type?
-        let (sdl_context, canvas, audio_device, key_map) = Chip8::init_sdl();
+        let frontend = Chip8::init_sdl();
 
+        Chip8::create(config, Some(frontend))
+    }
+
+    // headless
+    // A chip8 without a window or sound, which runs the program with step
+    #[cfg(test)]
+    pub fn headless(config: Chip8Config) -> Result<Self, Chip8Error> {
+        Chip8::create(config, None)
+    }
+
+    fn create(config: Chip8Config, frontend: Option<Frontend>) -> Result<Self, Chip8Error> {
         // Create chip 8 instance
         let mut chip8 = Chip8 {
             memory: [0; 4096],
-            program: config.program,
+            program: config.program.clone(),
             keys: Keys::new(),
-            key_map,
             stack: Stack::new(),  // stack for 16-bit addresses which is used to call subroutines/functions
             display: [false; (SCREEN_WIDTH * SCREEN_HEIGHT) as usize], // 64x32 pixels - monochrome -- super chip is 128*64
             display_scale: config.display_scale,
@@ -103,17 +199,30 @@ impl Chip8 {
                        // located in RAM, from address 000 to 1FF. It would expect a CHIP-8 program
                        // to be loaded into memory after it, starting at address 200 (512 in
                        // decimal).
+            opcode: 0,
+            opcode_address: 0x200,
             i: 0,
             v: [0; 16],
             delay_timer: 0,
             sound_timer: 0,
-            sdl_context,
-            canvas,
-            audio_device,
+            quirks: config.quirks.unwrap_or(Quirks::new()),
+            tickrate: config.tickrate.unwrap_or(DEFAULT_TICKRATE),
+            foreground: rgb(config.foreground.unwrap_or(DEFAULT_FOREGROUND)),
+            background: rgb(config.background.unwrap_or(DEFAULT_BACKGROUND)),
+            rom: None,
+            error_policy: config.error_policy,
+            unknown_opcode: config.unknown_opcode,
+            sys_handler: config.sys_handler,
+            unknown_opcodes: Vec::new(),
+            paused: false,
+            profiler: if config.profile { Some(Profiler::new()) } else { None },
+            coverage: if config.coverage { Some(Coverage::new()) } else { None },
+            symbols: Symbols::new(),
+            frontend,
         };
 
         chip8.set_fonts();
-        chip8.load_program().unwrap();
+        chip8.load_program(&config)?;
 
         debug!("printing memory");
         for (i, mem) in chip8.memory.iter().enumerate() {
@@ -123,12 +232,18 @@ impl Chip8 {
         // TODO: Display
 
 
-        chip8
+        Ok(chip8)
 
     }
 
-    pub fn run(&mut self) {
-        let mut event_pump = self.sdl_context.event_pump().unwrap();
+    // run
+    // Run the program until the window is closed, or until the program hits an
+    // error that the error policy says to halt on.
+    pub fn run(&mut self) -> Result<(), Chip8Error> {
+        let mut event_pump = match &self.frontend {
+            Some(frontend) => frontend.sdl_context.event_pump().unwrap(),
+            None => return Ok(()),
+        };
         let mut last_update = Instant::now();
 
         // Main loop
@@ -136,14 +251,18 @@ impl Chip8 {
             // Handle events for keyboard, window, etc.
             for event in event_pump.poll_iter() {
                 match event {
-                    Event::Quit {..} => return,
+                    Event::Quit {..} => return Ok(()),
+                    Event::KeyDown { keycode: Some(Keycode::F5), .. } if self.paused => {
+                        info!("Continuing");
+                        self.paused = false;
+                    },
                     Event::KeyDown { keycode: Some(keycode), .. } => {
-                        if let Some(&key) = self.key_map.get(&keycode) {
+                        if let Some(key) = self.key(keycode) {
                             self.keys.set_key(key, true);
                         }
                     },
                     Event::KeyUp { keycode: Some(keycode), .. } => {
-                        if let Some(&key) = self.key_map.get(&keycode) {
+                        if let Some(key) = self.key(keycode) {
                             self.keys.set_key(key, false);
                         }
                     },
@@ -153,46 +272,157 @@ impl Chip8 {
 
             // update display every frame (60hz)
             // this could be at dif
-            if last_update.elapsed() >= std::time::Duration::from_millis(16) {
-                self.update_timers(); // This may need to be seperate from the fetch/decode/execute cycle
+            if last_update.elapsed() >= FRAME_TIME {
+                if !self.paused {
+                    self.update_timers(); // This may need to be seperate from the fetch/decode/execute cycle
+                    let key_wait = self.waiting_for_keypress();
+                    if let Some(profiler) = &mut self.profiler {
+                        profiler.record_frame(key_wait);
+                    }
+                }
                 self.draw();
                 last_update = Instant::now();
             }
+
+            if self.paused {
+                std::thread::sleep(FRAME_TIME);
+                continue;
+            }
+
             // fetch, decode, execute
-            let opcode = self.fetch_opcode();
-            self.decode_and_execute(opcode);
+            if let Err(e) = self.step() {
+                error!("{}", self.describe_error(&e));
+                self.log();
+                return Err(e);
+            }
 
 
-            // delay to reduce cpu usage
-            std::thread::sleep(Duration::from_micros(500));
+            // delay to reduce cpu usage and run at tickrate instructions per frame
+            std::thread::sleep(FRAME_TIME / self.tickrate.max(1));
             // std::thread::sleep(Duration::from_millis(1));
         }
     }
 
+    // key
+    // The chip8 key a keyboard key is mapped to
+    fn key(&self, keycode: Keycode) -> Option<usize> {
+        self.frontend.as_ref().and_then(|frontend| frontend.key_map.get(&keycode).copied())
+    }
+
+    // registers
+    // The values of V0 through VF
+    #[cfg(test)]
+    pub fn registers(&self) -> &[u8; 16] {
+        &self.v
+    }
+
+    // rom
+    // The program's entry in the rom database, if it was found there
+    pub fn rom(&self) -> Option<&'static RomInfo> {
+        self.rom
+    }
+
+    // unknown_opcodes
+    // The distinct unknown opcodes executed so far, in the order first seen
+    pub fn unknown_opcodes(&self) -> &[UnknownOpcode] {
+        &self.unknown_opcodes
+    }
+
+    // profiler
+    // The execution counts collected so far, if profiling is on
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    // coverage
+    // The instructions executed so far, if coverage is on
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref()
+    }
+
+    // set_symbols
+    // Use a symbol file to name addresses in messages
+    pub fn set_symbols(&mut self, symbols: Symbols) {
+        self.symbols = symbols;
+    }
+
+    pub fn symbols(&self) -> &Symbols {
+        &self.symbols
+    }
+
+    // location
+    // Where an address is in the program source, like ` in loop+4 (game.s:12)`,
+    // or nothing without symbols
+    pub fn location(&self, address: u16) -> String {
+        if self.symbols.is_empty() {
+            return String::new();
+        }
+        format!(" in {}", self.symbols.describe(address))
+    }
+
+    // describe_error
+    // An error with where it happened in the program source
+    pub fn describe_error(&self, error: &Chip8Error) -> String {
+        match error.pc() {
+            Some(pc) => format!("{}{}", error, self.location(pc)),
+            None => error.to_string(),
+        }
+    }
+
+    // step
+    // Fetch, decode and execute a single instruction
+    pub fn step(&mut self) -> Result<(), Chip8Error> {
+        let opcode = self.fetch_opcode()?;
+        if let Some(profiler) = &mut self.profiler {
+            profiler.record(self.opcode_address, opcode);
+        }
+        self.decode_and_execute(opcode)?;
+        if let Some(coverage) = &mut self.coverage {
+            coverage.record(self.opcode_address, opcode, self.pc);
+        }
+        Ok(())
+    }
+
     /*
      * Read the instruction that PC is currently pointing at from memory. An
      * instruction is two bytes, so you will need to read two successive bytes
      * from memory and combine them into one 16-bit instruction.
      */
-    fn fetch_opcode(&mut self) -> u16 {
+    fn fetch_opcode(&mut self) -> Result<u16, Chip8Error> {
         // let opcode = (self.memory[self.pc as usize] as u16) << 8 
         //     | self.memory[self.pc as usize + 1] as u16;
         // let pc = self.pc as usize;
         // let byte1 = self.memory[pc] as u16;
         // let byte2 = self.memory[pc + 1] as u16;
 
+        // The pc can only leave memory through a jump, there is no instruction
+        // to skip so ignore wraps it around as well.
+        if self.pc as usize + 1 >= self.memory.len() {
+            let error = Chip8Error::OutOfBounds {
+                pc: self.pc,
+                opcode: self.opcode,
+                address: self.pc as usize,
+            };
+            if self.error_policy == ErrorPolicy::Halt {
+                return Err(error);
+            }
+            warn!("{}, wrapping pc", self.describe_error(&error));
+            self.pc %= self.memory.len() as u16;
+        }
 
         let pc = self.pc as usize;
 
         let byte1 = self.memory[pc] as u16;
-        let byte2  = self.memory[pc + 1] as u16;
+        let byte2  = self.memory[(pc + 1) % self.memory.len()] as u16;
 
+        self.opcode_address = self.pc;
         self.pc += 2;
+        self.opcode = byte1 << 8 | byte2;
 
-        byte1 << 8 | byte2
+        Ok(self.opcode)
     }
 
-    fn decode_and_execute(&mut self, opcode: u16) {
+    fn decode_and_execute(&mut self, opcode: u16) -> Result<(), Chip8Error> {
         
         // Mask off (with a “binary AND”) the first number in the instruction,
         // and have one case per number. Some of these cases will need separate
@@ -232,11 +462,11 @@ impl Chip8 {
         match opcode & 0xF000 {
             0x0000 => match opcode & nn {
                 0x00E0 => self.clear_screen(),
-                0x00EE => self.return_from_subroutine(),
-                _ => info!("Unknown opcode: 0x{:04X}", opcode),
+                0x00EE => return self.return_from_subroutine(),
+                _ => return self.invalid_opcode(opcode),
             }
             0x1000 => self.jump(opcode),
-            0x2000 => self.subroutine(opcode),
+            0x2000 => return self.subroutine(opcode),
             0x3000 => self.skip_if_nn_is_equal(opcode),
             0x4000 => self.skip_if_nn_is_not_equal(opcode),
             0x5000 => self.skip_if_vx_and_vy_are_equal(opcode),
@@ -249,20 +479,20 @@ impl Chip8 {
                 0x0003 => self.vx_binary_xor_vy(opcode),
                 0x0004 => self.vx_add_vy(opcode),
                 0x0005 => self.vx_subtract_vy(opcode),
-                0x0006 => self.vx_shift_right(opcode, SET_VX_FROM_VY_IN_SHIFT),
+                0x0006 => self.vx_shift_right(opcode, self.quirks.set_vx_from_vy_in_shift),
                 0x0007 => self.vx_subtract_from_vy(opcode),
-                0x000E => self.vx_shift_left(opcode, SET_VX_FROM_VY_IN_SHIFT),
-                _ => info!("Unknown opcode: 0x{:04X}", opcode),
+                0x000E => self.vx_shift_left(opcode, self.quirks.set_vx_from_vy_in_shift),
+                _ => return self.invalid_opcode(opcode),
             },
             0x9000 => self.skip_if_vx_and_vy_are_not_equal(opcode),
             0xA000 => self.set_index_register(opcode),
             0xB000 => self.jump_with_offset(opcode),
             0xC000 => self.random(opcode),
-            0xD000 => self.draw_sprite(opcode),
+            0xD000 => return self.draw_sprite(opcode),
             0xE000 => match opcode & 0x00FF {
                 0x009E => self.skip_if_key_is_pressed(opcode),
                 0x00A1 => self.skip_if_key_is_not_pressed(opcode),
-                _ => info!("Unknown opcode: 0x{:04X}", opcode),
+                _ => return self.invalid_opcode(opcode),
             },
             0xF000 => match opcode & 0x00FF {
                 0x0007 => self.set_vx_to_delay_timer(opcode),
@@ -271,15 +501,136 @@ impl Chip8 {
                 0x0018 => self.set_sound_timer(opcode),
                 0x001E => self.add_vx_to_index_register(opcode),
                 0x0029 => self.set_index_to_font(opcode),
-                0x0033 => self.store_bcd(opcode),
-                0x0055 => self.store_registers(opcode),
-                0x0065 => self.load_registers(opcode),
-                _ => info!("Unknown opcode: 0x{:04X}", opcode),
+                0x0033 => return self.store_bcd(opcode),
+                0x0055 => return self.store_registers(opcode),
+                0x0065 => return self.load_registers(opcode),
+                _ => return self.invalid_opcode(opcode),
             },
-            _ => info!("Unknown opcode: 0x{:04X}", opcode),
+            _ => return self.invalid_opcode(opcode),
         }
         
-        
+        Ok(())
+    }
+
+    // fault
+    // Apply the error policy to an error. Halt returns the error, wrap and
+    // ignore log it and let the caller recover.
+    fn fault(&self, error: Chip8Error) -> Result<(), Chip8Error> {
+        match self.error_policy {
+            ErrorPolicy::Halt => Err(error),
+            ErrorPolicy::Wrap => {
+                warn!("{}, wrapping", self.describe_error(&error));
+                Ok(())
+            },
+            ErrorPolicy::Ignore => {
+                warn!("{}, ignoring", self.describe_error(&error));
+                Ok(())
+            },
+        }
+    }
+
+    // invalid_opcode
+    // Handle an opcode the emulator does not know with the unknown opcode
+    // policy, or the error policy if there is none. With wrap or ignore the
+    // opcode is skipped.
+    fn invalid_opcode(&mut self, opcode: u16) -> Result<(), Chip8Error> {
+        let error = Chip8Error::InvalidOpcode { pc: self.opcode_address, opcode };
+
+        let policy = match self.unknown_opcode {
+            Some(policy) => policy,
+            None => {
+                self.record_unknown_opcode(opcode);
+                return self.fault(error);
+            }
+        };
+
+        if policy == UnknownOpcodePolicy::Sys && opcode & 0xF000 == 0x0000 {
+            return self.sys(opcode);
+        }
+
+        self.record_unknown_opcode(opcode);
+        match policy {
+            UnknownOpcodePolicy::Nop => {
+                debug!("{}, skipping", self.describe_error(&error));
+                Ok(())
+            },
+            UnknownOpcodePolicy::Break => {
+                warn!("{}, paused, press F5 to continue", self.describe_error(&error));
+                self.log();
+                self.paused = true;
+                Ok(())
+            },
+            _ => Err(error),
+        }
+    }
+
+    // 0NNN
+    // Calls the machine code routine at NNN on the original COSMAC VIP. These
+    // are passed to the sys handler, and skipped if there is none.
+    fn sys(&mut self, opcode: u16) -> Result<(), Chip8Error> {
+        let address = opcode & 0x0FFF;
+        let handled = match self.sys_handler {
+            Some(handler) => handler(address, &mut self.v, &mut self.i, &mut self.memory),
+            None => false,
+        };
+
+        if !handled {
+            warn!("SYS 0x{:03X} at pc 0x{:04X}{} not handled, skipping",
+                address, self.opcode_address, self.location(self.opcode_address));
+            self.record_unknown_opcode(opcode);
+        }
+        Ok(())
+    }
+
+    fn record_unknown_opcode(&mut self, opcode: u16) {
+        match self.unknown_opcodes.iter_mut().find(|unknown| unknown.opcode == opcode) {
+            Some(unknown) => unknown.count += 1,
+            None => self.unknown_opcodes.push(UnknownOpcode {
+                opcode,
+                address: self.opcode_address,
+                count: 1,
+            }),
+        }
+    }
+
+    // read_memory
+    // Read a byte, an ignored out of bounds read returns 0
+    fn read_memory(&self, address: usize) -> Result<u8, Chip8Error> {
+        if address < self.memory.len() {
+            return Ok(self.memory[address]);
+        }
+
+        self.fault(Chip8Error::OutOfBounds {
+            pc: self.opcode_address,
+            opcode: self.opcode,
+            address,
+        })?;
+
+        match self.error_policy {
+            ErrorPolicy::Wrap => Ok(self.memory[address % self.memory.len()]),
+            _ => Ok(0),
+        }
+    }
+
+    // write_memory
+    // Write a byte, an ignored out of bounds write is dropped
+    fn write_memory(&mut self, address: usize, value: u8) -> Result<(), Chip8Error> {
+        if address < self.memory.len() {
+            self.memory[address] = value;
+            return Ok(());
+        }
+
+        self.fault(Chip8Error::OutOfBounds {
+            pc: self.opcode_address,
+            opcode: self.opcode,
+            address,
+        })?;
+
+        if self.error_policy == ErrorPolicy::Wrap {
+            let len = self.memory.len();
+            self.memory[address % len] = value;
+        }
+        Ok(())
     }
 
     // FX55
@@ -288,20 +639,22 @@ impl Chip8 {
     // starting with the one that’s stored in I. V0 will be stored at the
     // address in I, V1 will be stored in I + 1, and so on, until VX is stored
     // in I + X.
-    fn store_registers(&mut self, opcode: u16) {
+    fn store_registers(&mut self, opcode: u16) -> Result<(), Chip8Error> {
         let x = ((opcode & 0x0F00) >> 8) as usize;
         for i in 0..=x {
-            self.memory[self.i as usize + i] = self.v[i];
+            self.write_memory(self.i as usize + i, self.v[i])?;
         }
+        Ok(())
     }
 
     // FX65 does the opposite; it takes the value stored at the memory addresses and loads them
     // into the variable registers instead.
-    fn load_registers(&mut self, opcode: u16) {
+    fn load_registers(&mut self, opcode: u16) -> Result<(), Chip8Error> {
         let x = ((opcode & 0x0F00) >> 8) as usize;
         for i in 0..=x {
-            self.v[i] = self.memory[self.i as usize + i];
+            self.v[i] = self.read_memory(self.i as usize + i)?;
         }
+        Ok(())
     }
     
     // FX33
@@ -313,15 +666,15 @@ impl Chip8 {
     // the index register I. For example, if VX contains 156 (or 9C in
     // hexadecimal), it would put the number 1 at the address in I, 5 in address
     // I + 1, and 6 in address I + 2.
-    fn store_bcd(&mut self, opcode: u16) {
+    fn store_bcd(&mut self, opcode: u16) -> Result<(), Chip8Error> {
         let x = ((opcode & 0x0F00) >> 8) as usize;
         let value = self.v[x];
         let hundreds = value / 100;
         let tens = (value % 100) / 10;
         let ones = value % 10;
-        self.memory[self.i as usize] = hundreds;
-        self.memory[self.i as usize + 1] = tens;
-        self.memory[self.i as usize + 2] = ones;
+        self.write_memory(self.i as usize, hundreds)?;
+        self.write_memory(self.i as usize + 1, tens)?;
+        self.write_memory(self.i as usize + 2, ones)
     }
     
     // FX29
@@ -354,6 +707,16 @@ impl Chip8 {
             self.pc -= 2;
         }
     }
+
+    // waiting_for_keypress returns the address of the FX0A instruction the
+    // program is blocked on, if any
+    fn waiting_for_keypress(&self) -> Option<u16> {
+        if self.opcode & 0xF0FF == 0xF00A && self.pc == self.opcode_address {
+            Some(self.opcode_address)
+        } else {
+            None
+        }
+    }
     
     
     // FX1E
@@ -415,15 +778,14 @@ impl Chip8 {
     }
     
     // CXNN
-    // Generate a random number from 0 to NN, and then BINARY AND it with NN
-    // then put the value in VX.
+    // Generate a random byte, and then BINARY AND it with NN then put the
+    // value in VX.
     fn random(&mut self, opcode: u16) {
         let x = ((opcode & 0x0F00) >> 8) as usize;
         let nn = (opcode & 0x00FF) as u8;
 
         let mut rng = rand::thread_rng();
-        let random_number: u8 = rng.gen_range(0..nn);
-        self.v[x] = random_number & nn;
+        self.v[x] = rng.gen::<u8>() & nn;
     }
     
     // 0xBNNN
@@ -442,10 +804,16 @@ impl Chip8 {
     // to go with). If you want to support a wide range of CHIP-8 programs, make
     // this “quirk” configurable.
     // 
-    // This just implements the original COSMAC VIP behavior
+    // The original COSMAC VIP behavior is the default, the jump_with_vx quirk
+    // switches to BXNN.
     fn jump_with_offset(&mut self, opcode: u16) {
         let address = opcode & 0x0FFF;
-        self.pc = self.v[0] as u16 + address;
+        let x = if self.quirks.jump_with_vx {
+            ((opcode & 0x0F00) >> 8) as usize
+        } else {
+            0
+        };
+        self.pc = self.v[x] as u16 + address;
     }
 
     // 8XYE
@@ -486,11 +854,13 @@ impl Chip8 {
     // TODO: Not sure what to do if they are same size, currently 
     // setting it as so
     // If VX is greater than or equal VY, VF is set to 1, otherwise 0.
+    // VF is written last, so with X = F it holds the flag, as Octo expects.
     fn vx_subtract_from_vy(&mut self, opcode: u16) {
         let x = ((opcode & 0x0F00) >> 8) as usize;
         let y = ((opcode & 0x00F0) >> 4) as usize;
-        self.v[0xF] = if self.v[y] >= self.v[x] { 1 } else { 0 };
+        let flag = if self.v[y] >= self.v[x] { 1 } else { 0 };
         self.v[x] = self.v[y].wrapping_sub(self.v[x]);
+        self.v[0xF] = flag;
     }
 
     // 8XY5
@@ -498,12 +868,14 @@ impl Chip8 {
     // TODO: Not sure what to do if they are same size, currently 
     // setting it as so
     // If VX is greater than or equal VY, VF is set to 1, otherwise 0.
+    // VF is written last, so with X = F it holds the flag.
     fn vx_subtract_vy(&mut self, opcode: u16) {
         let x = ((opcode & 0x0F00) >> 8) as usize;
         let y = ((opcode & 0x00F0) >> 4) as usize;
-        self.v[0xF] = if self.v[x] >= self.v[y] { 1 } else { 0 };
+        let flag = if self.v[x] >= self.v[y] { 1 } else { 0 };
         // wrapping sub is a function on u8
         self.v[x] = self.v[x].wrapping_sub(self.v[y]);
+        self.v[0xF] = flag;
     }
 
     // 8XY4
@@ -600,19 +972,45 @@ impl Chip8 {
     // like 1NNN, you should set PC to NNN. However, the difference between a
     // jump and a call is that this instruction should first push the current PC
     // to the stack, so the subroutine can return later.
-    fn subroutine(&mut self, opcode: u16) {
+    //
+    // On overflow wrap overwrites the oldest return address, ignore skips the
+    // call.
+    fn subroutine(&mut self, opcode: u16) -> Result<(), Chip8Error> {
         debug!("Subroutine with opcode {}", opcode);
         let address = opcode & 0x0FFF;
-        self.stack.push(self.pc).unwrap();
+        if self.stack.push(self.pc).is_err() {
+            self.fault(Chip8Error::StackOverflow { pc: self.opcode_address, opcode })?;
+            if self.error_policy == ErrorPolicy::Ignore {
+                return Ok(());
+            }
+            self.stack.wrap();
+            let _ = self.stack.push(self.pc);
+        }
         self.pc = address;
+        Ok(())
     }
 
     // return_from_subroutine
     // Returning from a subroutine is done with 00EE, and it does this by
     // removing (“popping”) the last address from the stack and setting the PC
     // to it.
-    fn return_from_subroutine(&mut self) {
-        self.pc = self.stack.pop().unwrap();
+    //
+    // On underflow wrap returns to the address at the top of the stack, ignore
+    // skips the return.
+    fn return_from_subroutine(&mut self) -> Result<(), Chip8Error> {
+        let address = match self.stack.pop() {
+            Ok(address) => address,
+            Err(_) => {
+                self.fault(Chip8Error::StackUnderflow { pc: self.opcode_address, opcode: self.opcode })?;
+                if self.error_policy == ErrorPolicy::Ignore {
+                    return Ok(());
+                }
+                self.stack.wrap();
+                self.stack.pop().unwrap_or(self.pc)
+            }
+        };
+        self.pc = address;
+        Ok(())
     }
 
     // 0xDXYN
@@ -624,7 +1022,7 @@ impl Chip8 {
     // to right, from most to least significant bit). If any pixels on the
     // screen were turned “off” by this, the VF flag register is set to 1.
     // Otherwise, it’s set to 0.
-    fn draw_sprite(&mut self, opcode: u16) {
+    fn draw_sprite(&mut self, opcode: u16) -> Result<(), Chip8Error> {
         let  x_index = ((opcode & 0x0F00) >> 8) as usize;
         let  y_index = ((opcode & 0x00F0) >> 4) as usize;
 
@@ -645,15 +1043,15 @@ impl Chip8 {
         // for N rows (how tall)
         for row in 0..height {
 
-            // Get the Nth byte of sprite data, counting from the memory address
-            // in the I register (I is not incremented)
-            let sprite_byte = self.memory[(self.i + row as u16) as usize];
-
             // stop if Y + row exceeds the screen height (don't wrap vertically)
             if y + row as u32 >= SCREEN_HEIGHT {
                 break;
             }
 
+            // Get the Nth byte of sprite data, counting from the memory address
+            // in the I register (I is not incremented)
+            let sprite_byte = self.read_memory(self.i as usize + row)?;
+
             // For each of the 8 pixels/bits in this sprite row (from left to
             // right, ie. from most to least significant bit):
             for col in 0..8 {
@@ -697,6 +1095,7 @@ impl Chip8 {
 
         }
 
+        Ok(())
     }
 
     fn set_index_register(&mut self, opcode: u16) {
@@ -735,8 +1134,8 @@ impl Chip8 {
         self.display.fill(false);
     }
 
-    // Initialize sdl2 with result sdl and canvas
-    fn init_sdl() -> (Sdl, Canvas<Window>, AudioDevice<SquareWave>, HashMap<Keycode, usize>) {
+    // Initialize sdl2 with the window, sound and keys
+    fn init_sdl() -> Frontend {
         let sdl_context = sdl2::init().unwrap();
         let video_subsystem = sdl_context.video().unwrap();
 
@@ -791,15 +1190,41 @@ impl Chip8 {
         key_map.insert(Keycode::C, 0xB);
         key_map.insert(Keycode::V, 0xF);
 
-        (sdl_context, canvas, audio_device, key_map)
+        Frontend { sdl_context, canvas, audio_device, key_map }
     }
 
-    fn load_program(&mut self) -> Result<(), io::Error> {
+    // load_program
+    // Load the program at 0x200. A file the size of memory is an image of all
+    // of memory, as written by assemble --full-image, and is loaded from
+    // address 0 with the fonts put back over it.
+    fn load_program(&mut self, config: &Chip8Config) -> Result<(), Chip8Error> {
         // read in binary file into a byte vector
-        let program = fs::read(&self.program)?;
+        let program = match fs::read(&self.program) {
+            Ok(program) => program,
+            Err(e) => return Err(Chip8Error::Io(format!("{}: {}", self.program, e))),
+        };
 
-        if 0x200 + program.len() > self.memory.len() {
-            return Err(io::Error::new(io::ErrorKind::InvalidData, "Program is too large to fin in memory"));
+        let image = program.len() == self.memory.len();
+        if !image && 0x200 + program.len() > self.memory.len() {
+            return Err(Chip8Error::RomTooLarge {
+                size: program.len(),
+                max: self.memory.len() - 0x200,
+            });
+        }
+
+        let sha1 = romdb::sha1_hex(&program);
+        info!("Program sha1: {}", sha1);
+        if config.use_rom_database {
+            match romdb::lookup(&sha1) {
+                Some(rom) => self.apply_rom_info(rom, config),
+                None => info!("Program not found in rom database, using config"),
+            }
+        }
+
+        if image {
+            self.memory.copy_from_slice(&program);
+            self.set_fonts();
+            return Ok(());
         }
 
         self.memory[0x200..(0x200 + program.len())]
@@ -808,13 +1233,47 @@ impl Chip8 {
         Ok(())
     }
 
+    // apply_rom_info
+    // Use the quirks, speed and colours recommended for a known rom, where
+    // config does not set them
+    fn apply_rom_info(&mut self, rom: &'static RomInfo, config: &Chip8Config) {
+        info!("Found {} by {} in rom database", rom.title, rom.author);
+        info!("  platform: {}", rom.platform);
+        info!("  quirks: {:?}", rom.quirks);
+        info!("  tickrate: {}", rom.tickrate);
+        info!("  keys: {}", rom.keys);
+
+        if rom.platform != romdb::CHIP8 {
+            warn!("{} programs are not supported, running as CHIP-8", rom.platform);
+        }
+
+        self.rom = Some(rom);
+        if config.quirks.is_none() {
+            self.quirks = rom.quirks;
+        }
+        if config.tickrate.is_none() {
+            self.tickrate = rom.tickrate;
+        }
+        if config.foreground.is_none() {
+            self.foreground = rgb(rom.foreground);
+        }
+        if config.background.is_none() {
+            self.background = rgb(rom.background);
+        }
+    }
+
     fn draw(&mut self) {
+        let canvas = match &mut self.frontend {
+            Some(frontend) => &mut frontend.canvas,
+            None => return,
+        };
+
         // clear screen
-        self.canvas.set_draw_color(Color::BLACK);
-        self.canvas.clear();
+        canvas.set_draw_color(self.background);
+        canvas.clear();
 
         // set draw color for pixels that are "on"
-        self.canvas.set_draw_color(Color::WHITE);
+        canvas.set_draw_color(self.foreground);
 
         // draw pixels
         for y in 0..SCREEN_HEIGHT {
@@ -827,12 +1286,12 @@ impl Chip8 {
                         self.display_scale,
                         self.display_scale,
                     );
-                    self.canvas.fill_rect(rect).unwrap();
+                    canvas.fill_rect(rect).unwrap();
                 }
             }
 
         }
-        self.canvas.present();
+        canvas.present();
     }
 
     fn update_timers(&mut self) {
@@ -853,11 +1312,13 @@ impl Chip8 {
 
     pub fn log(&self) {
         info!("Chip8 info");
-        info!("  pc: 0x{:04X}", self.pc);
+        info!("  pc: 0x{:04X}{}", self.pc, self.location(self.pc));
         info!("  i: 0x{:04X}", self.i);
         info!("  delay_timer: 0x{:02X}", self.delay_timer);
         info!("  sound_timer: 0x{:02X}", self.sound_timer);
         info!("  display_scale: {}", self.display_scale);
+        info!("  quirks: {:?}", self.quirks);
+        info!("  tickrate: {}", self.tickrate);
         debug!("  display: {:?}", self.display);
         debug!("  stack: {:?}", self.stack.stack);
         info!("  v: {:?}", self.v);
@@ -903,15 +1364,24 @@ impl Chip8 {
     }
 
     fn play_sound(&mut self) {
-        self.audio_device.resume();
+        if let Some(frontend) = &self.frontend {
+            frontend.audio_device.resume();
+        }
     }
 
     fn stop_sound(&mut self) {
-        self.audio_device.pause();
+        if let Some(frontend) = &self.frontend {
+            frontend.audio_device.pause();
+        }
     }
 
 }
 
+// rgb converts a 0xRRGGBB value into an sdl colour
+fn rgb(colour: u32) -> Color {
+    Color::RGB((colour >> 16) as u8, (colour >> 8) as u8, colour as u8)
+}
+
 struct Stack {
     stack: [u16; 32],
     i: usize, // index to track top of stack
@@ -941,6 +1411,16 @@ impl Stack {
         self.i -= 1;
         Ok(self.stack[self.i])
     }
+
+    // wrap moves the top of a full stack to the bottom, and the top of an
+    // empty stack to the end so the stack can be used as a ring
+    fn wrap(&mut self) {
+        if self.i == 0 {
+            self.i = self.stack.len();
+        } else {
+            self.i = 0;
+        }
+    }
 }
 
 struct Keys {
@@ -989,3 +1469,287 @@ impl AudioCallback for SquareWave {
     }
 
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // headless returns a chip8 running program with config
+    fn headless(name: &str, program: &[u8], mut config: Chip8Config) -> Chip8 {
+        let dir = std::env::temp_dir().join(format!("chip8-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("test.ch8");
+        fs::write(&path, program).unwrap();
+
+        config.program = path.to_string_lossy().to_string();
+        let chip8 = Chip8::headless(config).unwrap();
+
+        fs::remove_dir_all(&dir).unwrap();
+        chip8
+    }
+
+    #[test]
+    fn test_error_policy() {
+        struct TestCase {
+            name: &'static str,
+            program: &'static [u8],
+            steps: usize,
+            policy: ErrorPolicy,
+            expected: Result<(), Chip8Error>,  // of the last step
+            pc: u16,
+            depth: usize,  // of the stack
+            memory: u8,  // at address 0
+        }
+
+        // call 0x200, calling itself until the stack is full
+        let overflow = &[0x22, 0x00];
+        // ret with nothing to return to
+        let underflow = &[0x00, 0xEE];
+        // ld i 0xFFF, ld v1 7, ld [i] v0-v1, writing v1 to 0x1000
+        let write = &[0xAF, 0xFF, 0x61, 0x07, 0xF1, 0x55];
+        // jmp 0xFFF, the last byte of memory
+        let jump = &[0x1F, 0xFF];
+
+        let test_cases = [
+            TestCase {
+                name: "Stack overflow halts",
+                program: overflow, steps: 33, policy: ErrorPolicy::Halt,
+                expected: Err(Chip8Error::StackOverflow { pc: 0x200, opcode: 0x2200 }),
+                pc: 0x202, depth: 32, memory: 0,
+            },
+            TestCase {
+                name: "Stack overflow wraps",
+                program: overflow, steps: 33, policy: ErrorPolicy::Wrap,
+                expected: Ok(()),
+                pc: 0x200, depth: 1, memory: 0,
+            },
+            TestCase {
+                name: "Stack overflow is ignored",
+                program: overflow, steps: 33, policy: ErrorPolicy::Ignore,
+                expected: Ok(()),
+                pc: 0x202, depth: 32, memory: 0,
+            },
+            TestCase {
+                name: "Stack underflow halts",
+                program: underflow, steps: 1, policy: ErrorPolicy::Halt,
+                expected: Err(Chip8Error::StackUnderflow { pc: 0x200, opcode: 0x00EE }),
+                pc: 0x202, depth: 0, memory: 0,
+            },
+            TestCase {
+                name: "Stack underflow wraps",
+                program: underflow, steps: 1, policy: ErrorPolicy::Wrap,
+                expected: Ok(()),
+                pc: 0x000, depth: 31, memory: 0,
+            },
+            TestCase {
+                name: "Stack underflow is ignored",
+                program: underflow, steps: 1, policy: ErrorPolicy::Ignore,
+                expected: Ok(()),
+                pc: 0x202, depth: 0, memory: 0,
+            },
+            TestCase {
+                name: "Write out of bounds halts",
+                program: write, steps: 3, policy: ErrorPolicy::Halt,
+                expected: Err(Chip8Error::OutOfBounds { pc: 0x204, opcode: 0xF155, address: 0x1000 }),
+                pc: 0x206, depth: 0, memory: 0,
+            },
+            TestCase {
+                name: "Write out of bounds wraps",
+                program: write, steps: 3, policy: ErrorPolicy::Wrap,
+                expected: Ok(()),
+                pc: 0x206, depth: 0, memory: 7,
+            },
+            TestCase {
+                name: "Write out of bounds is ignored",
+                program: write, steps: 3, policy: ErrorPolicy::Ignore,
+                expected: Ok(()),
+                pc: 0x206, depth: 0, memory: 0,
+            },
+            TestCase {
+                name: "Fetch past the end of memory halts",
+                program: jump, steps: 2, policy: ErrorPolicy::Halt,
+                expected: Err(Chip8Error::OutOfBounds { pc: 0xFFF, opcode: 0x1FFF, address: 0xFFF }),
+                pc: 0xFFF, depth: 0, memory: 0,
+            },
+            TestCase {
+                name: "Fetch past the end of memory wraps",
+                program: jump, steps: 2, policy: ErrorPolicy::Wrap,
+                expected: Ok(()),
+                pc: 0x1001, depth: 0, memory: 0,
+            },
+            TestCase {
+                name: "Fetch past the end of memory is ignored",
+                program: jump, steps: 2, policy: ErrorPolicy::Ignore,
+                expected: Ok(()),
+                pc: 0x1001, depth: 0, memory: 0,
+            },
+        ];
+
+        for case in test_cases {
+            let mut config = Chip8Config::new();
+            config.error_policy = case.policy;
+            let mut chip8 = headless("policy", case.program, config);
+            for _ in 1..case.steps {
+                chip8.step().unwrap_or_else(|e| panic!("{}: {}", case.name, e));
+            }
+            assert_eq!(chip8.step(), case.expected, "Failed on test case: {}", case.name);
+            assert_eq!((chip8.pc, chip8.stack.i, chip8.memory[0]), (case.pc, case.depth, case.memory),
+                "Failed on test case: {}", case.name);
+        }
+    }
+
+    #[test]
+    fn test_unknown_opcode_policy() {
+        struct TestCase {
+            name: &'static str,
+            policy: Option<UnknownOpcodePolicy>,
+            sys_handler: Option<SysHandler>,
+            steps: usize,
+            expected: Result<(), Chip8Error>,  // of the last step
+            paused: bool,
+            v0: u8,
+            unknown: Vec<UnknownOpcode>,
+        }
+
+        // sys 0x123, an unknown E opcode, sys 0x123 again
+        let program = &[0x01, 0x23, 0xE0, 0xFF, 0x01, 0x23];
+        let unknown = |opcode: u16, address: u16, count: u32| UnknownOpcode { opcode, address, count };
+        fn handler(address: u16, v: &mut [u8; 16], _: &mut u16, _: &mut [u8; 4096]) -> bool {
+            v[0] += 1;
+            address == 0x123
+        }
+
+        let test_cases = [
+            TestCase {
+                name: "Halt",
+                policy: Some(UnknownOpcodePolicy::Halt), sys_handler: None, steps: 1,
+                expected: Err(Chip8Error::InvalidOpcode { pc: 0x200, opcode: 0x0123 }),
+                paused: false, v0: 0,
+                unknown: vec![unknown(0x0123, 0x200, 1)],
+            },
+            TestCase {
+                name: "Nop",
+                policy: Some(UnknownOpcodePolicy::Nop), sys_handler: None, steps: 3,
+                expected: Ok(()),
+                paused: false, v0: 0,
+                unknown: vec![unknown(0x0123, 0x200, 2), unknown(0xE0FF, 0x202, 1)],
+            },
+            TestCase {
+                name: "Break",
+                policy: Some(UnknownOpcodePolicy::Break), sys_handler: None, steps: 1,
+                expected: Ok(()),
+                paused: true, v0: 0,
+                unknown: vec![unknown(0x0123, 0x200, 1)],
+            },
+            TestCase {
+                name: "Sys with a handler",
+                policy: Some(UnknownOpcodePolicy::Sys), sys_handler: Some(handler), steps: 1,
+                expected: Ok(()),
+                paused: false, v0: 1,
+                unknown: vec![],
+            },
+            TestCase {
+                name: "Sys without a handler",
+                policy: Some(UnknownOpcodePolicy::Sys), sys_handler: None, steps: 1,
+                expected: Ok(()),
+                paused: false, v0: 0,
+                unknown: vec![unknown(0x0123, 0x200, 1)],
+            },
+            TestCase {
+                name: "Sys halts on other opcodes",
+                policy: Some(UnknownOpcodePolicy::Sys), sys_handler: Some(handler), steps: 2,
+                expected: Err(Chip8Error::InvalidOpcode { pc: 0x202, opcode: 0xE0FF }),
+                paused: false, v0: 1,
+                unknown: vec![unknown(0xE0FF, 0x202, 1)],
+            },
+            TestCase {
+                name: "No policy uses the error policy",
+                policy: None, sys_handler: Some(handler), steps: 1,
+                expected: Err(Chip8Error::InvalidOpcode { pc: 0x200, opcode: 0x0123 }),
+                paused: false, v0: 0,
+                unknown: vec![unknown(0x0123, 0x200, 1)],
+            },
+        ];
+
+        for case in test_cases {
+            let mut config = Chip8Config::new();
+            config.unknown_opcode = case.policy;
+            config.sys_handler = case.sys_handler;
+            let mut chip8 = headless("unknown", program, config);
+            for _ in 1..case.steps {
+                chip8.step().unwrap_or_else(|e| panic!("{}: {}", case.name, e));
+            }
+            assert_eq!(chip8.step(), case.expected, "Failed on test case: {}", case.name);
+            assert_eq!((chip8.paused, chip8.v[0]), (case.paused, case.v0), "Failed on test case: {}", case.name);
+            assert_eq!(chip8.unknown_opcodes(), case.unknown.as_slice(), "Failed on test case: {}", case.name);
+        }
+    }
+
+    #[test]
+    fn test_rom_database() {
+        struct TestCase {
+            name: &'static str,
+            use_rom_database: bool,
+            tickrate: Option<u32>,
+            foreground: Option<u32>,
+            expected: (Option<&'static str>, u32, Color),
+        }
+
+        let ibm_logo = fs::read("roms/ibm-logo.ch8").unwrap();
+        let test_cases = [
+            TestCase {
+                name: "Settings from the rom database",
+                use_rom_database: true, tickrate: None, foreground: None,
+                expected: (Some("IBM Logo"), 30, rgb(0xFFFFFF)),
+            },
+            TestCase {
+                name: "Settings from the config are kept",
+                use_rom_database: true, tickrate: Some(5), foreground: Some(0x00FF00),
+                expected: (Some("IBM Logo"), 5, rgb(0x00FF00)),
+            },
+            TestCase {
+                name: "Rom database off",
+                use_rom_database: false, tickrate: None, foreground: None,
+                expected: (None, DEFAULT_TICKRATE, rgb(DEFAULT_FOREGROUND)),
+            },
+        ];
+
+        for case in test_cases {
+            let mut config = Chip8Config::new();
+            config.use_rom_database = case.use_rom_database;
+            config.tickrate = case.tickrate;
+            config.foreground = case.foreground;
+            let chip8 = headless("romdb", &ibm_logo, config);
+            let result = (chip8.rom().map(|rom| rom.title), chip8.tickrate, chip8.foreground);
+            assert_eq!(result, case.expected, "Failed on test case: {}", case.name);
+        }
+    }
+
+    #[test]
+    fn test_full_image() {
+        // ld v1 7 at 0x200 and a byte of data at the end of memory
+        let mut image = vec![0; 4096];
+        image[0x200..0x202].copy_from_slice(&[0x61, 0x07]);
+        image[0xFFF] = 0xAB;
+
+        let mut chip8 = headless("image", &image, Chip8Config::new());
+        chip8.step().unwrap();
+        assert_eq!((chip8.v[1], chip8.memory[0xFFF]), (7, 0xAB));
+        assert_eq!(&chip8.memory[0x50..0x55], &[0xF0, 0x90, 0x90, 0x90, 0xF0]);
+    }
+
+    #[test]
+    fn test_random() {
+        // rnd v1 0x00, rnd v2 0x3C, jmp 0x200
+        let mut chip8 = headless("random", &[0xC1, 0x00, 0xC2, 0x3C, 0x12, 0x00], Chip8Config::new());
+        for _ in 0..100 {
+            for _ in 0..3 {
+                chip8.step().unwrap();
+            }
+            assert_eq!(chip8.v[1], 0);
+            assert_eq!(chip8.v[2] & !0x3C, 0);
+        }
+    }
+
+}
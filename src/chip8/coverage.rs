@@ -0,0 +1,214 @@
+use std::collections::HashMap;
+
+// Coverage
+// Records which instructions a program executed, and for the skip
+// instructions (3XNN, 4XNN, 5XY0, 9XY0, EX9E, EXA1) how often the skip was
+// taken and not taken.
+pub struct Coverage {
+    executed: HashMap<u16, u64>,
+    branches: HashMap<u16, Branch>,
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct Branch {
+    pub taken: u64,
+    pub not_taken: u64,
+}
+
+// SourceLine
+// An instruction in the assembler source, used to map coverage back to it
+#[derive(PartialEq, Debug, Clone)]
+pub struct SourceLine {
+    pub address: u16,
+    pub line: usize,  // 1-based
+    pub instruction: String,
+}
+
+impl Coverage {
+
+    pub fn new() -> Self {
+        Coverage {
+            executed: HashMap::new(),
+            branches: HashMap::new(),
+        }
+    }
+
+    // record an instruction executed at address, next_pc is the pc after it
+    // was executed
+    pub fn record(&mut self, address: u16, opcode: u16, next_pc: u16) {
+        *self.executed.entry(address).or_insert(0) += 1;
+
+        if is_skip(opcode) {
+            let branch = self.branches.entry(address)
+                .or_insert(Branch { taken: 0, not_taken: 0 });
+            if next_pc == address.wrapping_add(4) {
+                branch.taken += 1;
+            } else {
+                branch.not_taken += 1;
+            }
+        }
+    }
+
+    // report_lcov writes an lcov tracefile for the assembler source, with a
+    // branch pair (taken, not taken) for every skip instruction
+    pub fn report_lcov(&self, source: &str, lines: &[SourceLine]) -> String {
+        let mut report = String::new();
+        report.push_str("TN:\n");
+        report.push_str(&format!("SF:{}\n", source));
+
+        let mut lines_hit = 0;
+        let mut branches_found = 0;
+        let mut branches_hit = 0;
+
+        for line in lines {
+            let count = self.executed.get(&line.address).copied().unwrap_or(0);
+            if count > 0 {
+                lines_hit += 1;
+            }
+
+            if is_skip_instruction(&line.instruction) {
+                let (taken, not_taken) = match self.branches.get(&line.address) {
+                    Some(branch) => (branch.taken.to_string(), branch.not_taken.to_string()),
+                    None => ("-".to_string(), "-".to_string()),
+                };
+                report.push_str(&format!("BRDA:{},0,0,{}\n", line.line, taken));
+                report.push_str(&format!("BRDA:{},0,1,{}\n", line.line, not_taken));
+
+                branches_found += 2;
+                if let Some(branch) = self.branches.get(&line.address) {
+                    branches_hit += (branch.taken > 0) as usize + (branch.not_taken > 0) as usize;
+                }
+            }
+
+            report.push_str(&format!("DA:{},{}\n", line.line, count));
+        }
+
+        report.push_str(&format!("BRF:{}\n", branches_found));
+        report.push_str(&format!("BRH:{}\n", branches_hit));
+        report.push_str(&format!("LF:{}\n", lines.len()));
+        report.push_str(&format!("LH:{}\n", lines_hit));
+        report.push_str("end_of_record\n");
+
+        report
+    }
+
+    // report_annotated returns the source with execution counts in front of
+    // every instruction, ##### marks instructions that never ran
+    pub fn report_annotated(&self, source: &str, lines: &[SourceLine]) -> String {
+        let by_line: HashMap<usize, &SourceLine> = lines.iter()
+            .map(|line| (line.line, line))
+            .collect();
+
+        let mut report = String::new();
+        for (i, text) in source.lines().enumerate() {
+            let annotation = match by_line.get(&(i + 1)) {
+                Some(line) => {
+                    let count = self.executed.get(&line.address).copied().unwrap_or(0);
+                    let count = if count > 0 { count.to_string() } else { "#####".to_string() };
+                    match self.branches.get(&line.address) {
+                        Some(branch) => format!("{:>10} {:04X}  {}  [skip taken {}, not taken {}]",
+                            count, line.address, text, branch.taken, branch.not_taken),
+                        None => format!("{:>10} {:04X}  {}", count, line.address, text),
+                    }
+                },
+                None => format!("{:>10} {:4}  {}", "", "", text),
+            };
+            report.push_str(annotation.trim_end());
+            report.push('\n');
+        }
+
+        report
+    }
+
+}
+
+// is_skip returns true for the opcodes that conditionally skip the next
+// instruction
+fn is_skip(opcode: u16) -> bool {
+    match opcode & 0xF000 {
+        0x3000 | 0x4000 => true,
+        0x5000 | 0x9000 => opcode & 0x000F == 0,
+        0xE000 => opcode & 0x00FF == 0x009E || opcode & 0x00FF == 0x00A1,
+        _ => false,
+    }
+}
+
+fn is_skip_instruction(name: &str) -> bool {
+    matches!(name.to_lowercase().as_str(), "se" | "sne" | "skp" | "sknp")
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source_lines() -> Vec<SourceLine> {
+        vec![
+            SourceLine { address: 0x200, line: 2, instruction: "se".to_string() },
+            SourceLine { address: 0x202, line: 3, instruction: "jmp".to_string() },
+            SourceLine { address: 0x204, line: 4, instruction: "skp".to_string() },
+            SourceLine { address: 0x206, line: 5, instruction: "cls".to_string() },
+        ]
+    }
+
+    #[test]
+    fn test_is_skip() {
+        struct TestCase {
+            opcode: u16,
+            expected: bool,
+        }
+
+        let test_cases = [
+            TestCase { opcode: 0x3012, expected: true },
+            TestCase { opcode: 0x4012, expected: true },
+            TestCase { opcode: 0x5120, expected: true },
+            TestCase { opcode: 0x5121, expected: false },
+            TestCase { opcode: 0x9120, expected: true },
+            TestCase { opcode: 0xE19E, expected: true },
+            TestCase { opcode: 0xE1A1, expected: true },
+            TestCase { opcode: 0xE1A2, expected: false },
+            TestCase { opcode: 0x1200, expected: false },
+        ];
+
+        for case in test_cases.iter() {
+            assert_eq!(is_skip(case.opcode), case.expected, "Failed on opcode: 0x{:04X}", case.opcode);
+        }
+    }
+
+    #[test]
+    fn test_report_lcov() {
+        let mut coverage = Coverage::new();
+        // se v0 0x00 taken, then not taken
+        coverage.record(0x200, 0x3000, 0x204);
+        coverage.record(0x200, 0x3000, 0x202);
+        coverage.record(0x202, 0x1200, 0x200);
+
+        let expected = "TN:\nSF:game.s\n\
+            BRDA:2,0,0,1\nBRDA:2,0,1,1\nDA:2,2\n\
+            DA:3,1\n\
+            BRDA:4,0,0,-\nBRDA:4,0,1,-\nDA:4,0\n\
+            DA:5,0\n\
+            BRF:4\nBRH:2\nLF:4\nLH:2\nend_of_record\n";
+
+        assert_eq!(coverage.report_lcov("game.s", &source_lines()), expected);
+    }
+
+    #[test]
+    fn test_report_annotated() {
+        let mut coverage = Coverage::new();
+        coverage.record(0x200, 0x3000, 0x204);
+
+        let source = "main:\n  se v0 0x00\n  jmp main\n  skp v0\n  cls";
+        let expected = [
+            "                 main:",
+            "         1 0200    se v0 0x00  [skip taken 1, not taken 0]",
+            "     ##### 0202    jmp main",
+            "     ##### 0204    skp v0",
+            "     ##### 0206    cls",
+            "",
+        ].join("\n");
+
+        assert_eq!(coverage.report_annotated(source, &source_lines()), expected);
+    }
+
+}
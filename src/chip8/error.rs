@@ -0,0 +1,261 @@
+use std::fmt;
+
+// Chip8Error
+// Errors raised while loading or running a program. Runtime errors carry the
+// address of the instruction (pc) and the opcode that caused them.
+#[derive(PartialEq, Debug, Clone)]
+pub enum Chip8Error {
+    StackOverflow { pc: u16, opcode: u16 },
+    StackUnderflow { pc: u16, opcode: u16 },
+    OutOfBounds { pc: u16, opcode: u16, address: usize },
+    InvalidOpcode { pc: u16, opcode: u16 },
+    RomTooLarge { size: usize, max: usize },
+    Io(String),
+}
+
+impl fmt::Display for Chip8Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Chip8Error::StackOverflow { pc, opcode } => write!(f,
+                "Stack overflow at pc 0x{:04X} (opcode 0x{:04X})", pc, opcode),
+            Chip8Error::StackUnderflow { pc, opcode } => write!(f,
+                "Stack underflow at pc 0x{:04X} (opcode 0x{:04X})", pc, opcode),
+            Chip8Error::OutOfBounds { pc, opcode, address } => write!(f,
+                "Memory access out of bounds at address 0x{:04X} at pc 0x{:04X} (opcode 0x{:04X})",
+                address, pc, opcode),
+            Chip8Error::InvalidOpcode { pc, opcode } => write!(f,
+                "Invalid opcode at pc 0x{:04X} (opcode 0x{:04X})", pc, opcode),
+            Chip8Error::RomTooLarge { size, max } => write!(f,
+                "Program is too large to fit in memory: {} bytes, at most {} bytes allowed",
+                size, max),
+            Chip8Error::Io(e) => write!(f, "Error reading program: {}", e),
+        }
+    }
+}
+
+impl Chip8Error {
+
+    // pc returns the address of the instruction that caused a runtime error
+    pub fn pc(&self) -> Option<u16> {
+        match self {
+            Chip8Error::StackOverflow { pc, .. }
+            | Chip8Error::StackUnderflow { pc, .. }
+            | Chip8Error::OutOfBounds { pc, .. }
+            | Chip8Error::InvalidOpcode { pc, .. } => Some(*pc),
+            Chip8Error::RomTooLarge { .. } | Chip8Error::Io(_) => None,
+        }
+    }
+
+}
+
+// ErrorPolicy
+// What to do when a running program hits an error
+//   halt   - stop the emulator and report the error
+//   wrap   - wrap memory addresses and the stack around and keep going
+//   ignore - skip the faulting instruction and keep going
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum ErrorPolicy {
+    Halt,
+    Wrap,
+    Ignore,
+}
+
+impl ErrorPolicy {
+
+    pub fn get_policy(name: &str) -> Result<ErrorPolicy, String> {
+        match name {
+            "halt" => Ok(ErrorPolicy::Halt),
+            "wrap" => Ok(ErrorPolicy::Wrap),
+            "ignore" => Ok(ErrorPolicy::Ignore),
+            _ => Err(format!("Invalid error policy: {}, expected halt, wrap or ignore", name)),
+        }
+    }
+
+}
+
+// UnknownOpcodePolicy
+// What to do when the program executes an opcode the emulator does not know,
+// overriding the error policy
+//   halt  - stop the emulator and report the opcode
+//   break - pause the emulator and dump its state, F5 continues
+//   nop   - skip the opcode
+//   sys   - hand 0NNN machine code calls to the sys handler, halt on others
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum UnknownOpcodePolicy {
+    Halt,
+    Break,
+    Nop,
+    Sys,
+}
+
+impl UnknownOpcodePolicy {
+
+    pub fn get_policy(name: &str) -> Result<UnknownOpcodePolicy, String> {
+        match name {
+            "halt" => Ok(UnknownOpcodePolicy::Halt),
+            "break" => Ok(UnknownOpcodePolicy::Break),
+            "nop" => Ok(UnknownOpcodePolicy::Nop),
+            "sys" => Ok(UnknownOpcodePolicy::Sys),
+            _ => Err(format!(
+                "Invalid unknown opcode policy: {}, expected halt, break, nop or sys", name)),
+        }
+    }
+
+}
+
+// UnknownOpcode
+// A distinct unknown opcode seen while running, with where it was first seen
+#[derive(PartialEq, Debug, Clone)]
+pub struct UnknownOpcode {
+    pub opcode: u16,
+    pub address: u16,
+    pub count: u32,
+}
+
+impl fmt::Display for UnknownOpcode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "0x{:04X} first at pc 0x{:04X}, executed {} time(s)",
+            self.opcode, self.address, self.count)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display() {
+        struct TestCase {
+            name: &'static str,
+            error: Chip8Error,
+            expected: &'static str,
+        }
+
+        let test_cases = [
+            TestCase {
+                name: "Stack overflow",
+                error: Chip8Error::StackOverflow { pc: 0x204, opcode: 0x2204 },
+                expected: "Stack overflow at pc 0x0204 (opcode 0x2204)",
+            },
+            TestCase {
+                name: "Stack underflow",
+                error: Chip8Error::StackUnderflow { pc: 0x200, opcode: 0x00EE },
+                expected: "Stack underflow at pc 0x0200 (opcode 0x00EE)",
+            },
+            TestCase {
+                name: "Out of bounds",
+                error: Chip8Error::OutOfBounds { pc: 0x2A0, opcode: 0xF255, address: 0x1001 },
+                expected: "Memory access out of bounds at address 0x1001 at pc 0x02A0 (opcode 0xF255)",
+            },
+            TestCase {
+                name: "Invalid opcode",
+                error: Chip8Error::InvalidOpcode { pc: 0x200, opcode: 0xFFFF },
+                expected: "Invalid opcode at pc 0x0200 (opcode 0xFFFF)",
+            },
+            TestCase {
+                name: "Rom too large",
+                error: Chip8Error::RomTooLarge { size: 4000, max: 3584 },
+                expected: "Program is too large to fit in memory: 4000 bytes, at most 3584 bytes allowed",
+            },
+        ];
+
+        for case in test_cases.iter() {
+            assert_eq!(case.error.to_string(), case.expected, "Failed on test case: {}", case.name);
+        }
+    }
+
+    #[test]
+    fn test_pc() {
+        assert_eq!(Chip8Error::StackOverflow { pc: 0x204, opcode: 0x2204 }.pc(), Some(0x204));
+        assert_eq!(Chip8Error::InvalidOpcode { pc: 0x200, opcode: 0xFFFF }.pc(), Some(0x200));
+        assert_eq!(Chip8Error::RomTooLarge { size: 4000, max: 3584 }.pc(), None);
+    }
+
+    #[test]
+    fn test_get_policy() {
+        struct TestCase {
+            name: &'static str,
+            policy: &'static str,
+            expected: Result<ErrorPolicy, String>,
+        }
+
+        let test_cases = [
+            TestCase {
+                name: "Halt",
+                policy: "halt",
+                expected: Ok(ErrorPolicy::Halt),
+            },
+            TestCase {
+                name: "Wrap",
+                policy: "wrap",
+                expected: Ok(ErrorPolicy::Wrap),
+            },
+            TestCase {
+                name: "Ignore",
+                policy: "ignore",
+                expected: Ok(ErrorPolicy::Ignore),
+            },
+            TestCase {
+                name: "Invalid policy",
+                policy: "panic",
+                expected: Err("Invalid error policy: panic, expected halt, wrap or ignore".into()),
+            },
+        ];
+
+        for case in test_cases.iter() {
+            let result = ErrorPolicy::get_policy(case.policy);
+            assert_eq!(result, case.expected, "Failed on test case: {}", case.name);
+        }
+    }
+
+    #[test]
+    fn test_get_unknown_opcode_policy() {
+        struct TestCase {
+            name: &'static str,
+            policy: &'static str,
+            expected: Result<UnknownOpcodePolicy, String>,
+        }
+
+        let test_cases = [
+            TestCase {
+                name: "Halt",
+                policy: "halt",
+                expected: Ok(UnknownOpcodePolicy::Halt),
+            },
+            TestCase {
+                name: "Break",
+                policy: "break",
+                expected: Ok(UnknownOpcodePolicy::Break),
+            },
+            TestCase {
+                name: "Nop",
+                policy: "nop",
+                expected: Ok(UnknownOpcodePolicy::Nop),
+            },
+            TestCase {
+                name: "Sys",
+                policy: "sys",
+                expected: Ok(UnknownOpcodePolicy::Sys),
+            },
+            TestCase {
+                name: "Invalid policy",
+                policy: "wrap",
+                expected: Err(
+                    "Invalid unknown opcode policy: wrap, expected halt, break, nop or sys".into()),
+            },
+        ];
+
+        for case in test_cases.iter() {
+            let result = UnknownOpcodePolicy::get_policy(case.policy);
+            assert_eq!(result, case.expected, "Failed on test case: {}", case.name);
+        }
+    }
+
+    #[test]
+    fn test_unknown_opcode_display() {
+        let unknown = UnknownOpcode { opcode: 0x0123, address: 0x204, count: 3 };
+        assert_eq!(unknown.to_string(), "0x0123 first at pc 0x0204, executed 3 time(s)");
+    }
+
+}
@@ -1,4 +1,11 @@
 pub mod chip8;
+pub mod coverage;
+pub mod error;
+pub mod profiler;
+pub mod romdb;
+pub mod symbols;
 
 pub use self::chip8::Chip8;
 pub use self::chip8::Chip8Config;
+pub use self::error::{ErrorPolicy, UnknownOpcodePolicy};
+pub use self::symbols::Symbols;
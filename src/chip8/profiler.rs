@@ -0,0 +1,343 @@
+use std::collections::HashMap;
+
+use super::symbols::Symbols;
+
+const HOT_SPOTS: usize = 20;
+
+// Profiler
+// Counts what a program spends its instructions on while it runs: executions
+// per address and per opcode class, instructions spent inside subroutines
+// (from 2NNN to the matching 00EE) and frames spent blocked in FX0A.
+pub struct Profiler {
+    instructions: u64,
+    frames: u64,
+    addresses: HashMap<u16, u64>,
+    classes: HashMap<&'static str, u64>,
+    subroutines: HashMap<u16, Subroutine>,
+    calls: Vec<(u16, u64)>,  // subroutine address and instruction count when it was called
+    key_waits: HashMap<u16, u64>,  // frames blocked per FX0A address
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct Subroutine {
+    pub calls: u64,
+    pub instructions: u64,  // including the instructions of nested calls
+}
+
+impl Profiler {
+
+    pub fn new() -> Self {
+        Profiler {
+            instructions: 0,
+            frames: 0,
+            addresses: HashMap::new(),
+            classes: HashMap::new(),
+            subroutines: HashMap::new(),
+            calls: Vec::new(),
+            key_waits: HashMap::new(),
+        }
+    }
+
+    // record an instruction fetched from address
+    pub fn record(&mut self, address: u16, opcode: u16) {
+        self.instructions += 1;
+        *self.addresses.entry(address).or_insert(0) += 1;
+        *self.classes.entry(opcode_class(opcode)).or_insert(0) += 1;
+
+        if opcode & 0xF000 == 0x2000 {
+            self.calls.push((opcode & 0x0FFF, self.instructions));
+        } else if opcode == 0x00EE {
+            if let Some((subroutine, start)) = self.calls.pop() {
+                let stats = self.subroutines.entry(subroutine)
+                    .or_insert(Subroutine { calls: 0, instructions: 0 });
+                stats.calls += 1;
+                stats.instructions += self.instructions - start;
+            }
+        }
+    }
+
+    // record_frame counts a 60hz frame, and whether the program spent it
+    // waiting for a key press at address
+    pub fn record_frame(&mut self, key_wait: Option<u16>) {
+        self.frames += 1;
+        if let Some(address) = key_wait {
+            *self.key_waits.entry(address).or_insert(0) += 1;
+        }
+    }
+
+    pub fn report_text(&self, symbols: &Symbols) -> String {
+        let mut report = String::new();
+
+        report.push_str("Profile\n");
+        report.push_str(&format!("  instructions: {}\n", self.instructions));
+        report.push_str(&format!("  frames: {}\n", self.frames));
+        if self.frames > 0 {
+            report.push_str(&format!("  instructions per frame: {:.1}\n",
+                self.instructions as f64 / self.frames as f64));
+        }
+
+        report.push_str(&format!("\nHot spots (top {})\n", HOT_SPOTS));
+        report.push_str(&format!("  {:>10} {:>7}  {}\n", "count", "percent", "address"));
+        for (address, count) in self.hot_spots().iter().take(HOT_SPOTS) {
+            report.push_str(&format!("  {:>10} {:>6.2}%  0x{:04X} {}\n",
+                count, self.percent(*count), address, symbols.name(*address)));
+        }
+
+        report.push_str("\nOpcode classes\n");
+        report.push_str(&format!("  {:>10} {:>7}  {}\n", "count", "percent", "class"));
+        for (class, count) in self.opcode_classes() {
+            report.push_str(&format!("  {:>10} {:>6.2}%  {}\n",
+                count, self.percent(count), class));
+        }
+
+        report.push_str("\nSubroutines\n");
+        report.push_str(&format!("  {:>10} {:>12} {:>10}  {}\n",
+            "calls", "instructions", "average", "address"));
+        for (address, stats) in self.subroutines() {
+            report.push_str(&format!("  {:>10} {:>12} {:>10.1}  0x{:04X} {}\n",
+                stats.calls, stats.instructions,
+                stats.instructions as f64 / stats.calls as f64,
+                address, symbols.name(address)));
+        }
+
+        report.push_str("\nWaiting for key press\n");
+        report.push_str(&format!("  {:>10}  {}\n", "frames", "address"));
+        for (address, frames) in self.key_waits() {
+            report.push_str(&format!("  {:>10}  0x{:04X} {}\n",
+                frames, address, symbols.name(address)));
+        }
+
+        report
+    }
+
+    pub fn report_json(&self, symbols: &Symbols) -> String {
+        let addresses: Vec<String> = self.hot_spots().iter()
+            .map(|(address, count)| format!(
+                "{{\"address\": {}, \"name\": {}, \"count\": {}}}",
+                address, json_string(&symbols.name(*address)), count))
+            .collect();
+
+        let classes: Vec<String> = self.opcode_classes().iter()
+            .map(|(class, count)| format!(
+                "{{\"class\": {}, \"count\": {}}}", json_string(class), count))
+            .collect();
+
+        let subroutines: Vec<String> = self.subroutines().iter()
+            .map(|(address, stats)| format!(
+                "{{\"address\": {}, \"name\": {}, \"calls\": {}, \"instructions\": {}}}",
+                address, json_string(&symbols.name(*address)), stats.calls, stats.instructions))
+            .collect();
+
+        let key_waits: Vec<String> = self.key_waits().iter()
+            .map(|(address, frames)| format!(
+                "{{\"address\": {}, \"name\": {}, \"frames\": {}}}",
+                address, json_string(&symbols.name(*address)), frames))
+            .collect();
+
+        format!(
+            "{{\n  \"instructions\": {},\n  \"frames\": {},\n  \"addresses\": [{}],\n  \
+             \"opcode_classes\": [{}],\n  \"subroutines\": [{}],\n  \"key_waits\": [{}]\n}}\n",
+            self.instructions, self.frames,
+            addresses.join(", "), classes.join(", "),
+            subroutines.join(", "), key_waits.join(", "))
+    }
+
+    // hot_spots returns the executed addresses, most executed first
+    fn hot_spots(&self) -> Vec<(u16, u64)> {
+        let mut addresses: Vec<(u16, u64)> = self.addresses.iter()
+            .map(|(address, count)| (*address, *count))
+            .collect();
+        addresses.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        addresses
+    }
+
+    fn opcode_classes(&self) -> Vec<(&'static str, u64)> {
+        let mut classes: Vec<(&'static str, u64)> = self.classes.iter()
+            .map(|(class, count)| (*class, *count))
+            .collect();
+        classes.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        classes
+    }
+
+    // subroutines returns the called subroutines, most expensive first
+    fn subroutines(&self) -> Vec<(u16, Subroutine)> {
+        let mut subroutines: Vec<(u16, Subroutine)> = self.subroutines.iter()
+            .map(|(address, stats)| (*address, *stats))
+            .collect();
+        subroutines.sort_by(|a, b| b.1.instructions.cmp(&a.1.instructions).then(a.0.cmp(&b.0)));
+        subroutines
+    }
+
+    fn key_waits(&self) -> Vec<(u16, u64)> {
+        let mut key_waits: Vec<(u16, u64)> = self.key_waits.iter()
+            .map(|(address, frames)| (*address, *frames))
+            .collect();
+        key_waits.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        key_waits
+    }
+
+    fn percent(&self, count: u64) -> f64 {
+        if self.instructions == 0 {
+            return 0.0;
+        }
+        count as f64 * 100.0 / self.instructions as f64
+    }
+
+}
+
+// opcode_class names the kind of instruction an opcode is, by its pattern and
+// assembler mnemonic
+pub fn opcode_class(opcode: u16) -> &'static str {
+    match opcode & 0xF000 {
+        0x0000 => match opcode {
+            0x00E0 => "00E0 cls",
+            0x00EE => "00EE ret",
+            _ => "0NNN sys",
+        },
+        0x1000 => "1NNN jmp",
+        0x2000 => "2NNN call",
+        0x3000 => "3XNN se",
+        0x4000 => "4XNN sne",
+        0x5000 => "5XY0 se",
+        0x6000 => "6XNN ld",
+        0x7000 => "7XNN add",
+        0x8000 => match opcode & 0x000F {
+            0x0000 => "8XY0 ld",
+            0x0001 => "8XY1 or",
+            0x0002 => "8XY2 and",
+            0x0003 => "8XY3 xor",
+            0x0004 => "8XY4 add",
+            0x0005 => "8XY5 sub",
+            0x0006 => "8XY6 shr",
+            0x0007 => "8XY7 subn",
+            0x000E => "8XYE shl",
+            _ => "unknown",
+        },
+        0x9000 => "9XY0 sne",
+        0xA000 => "ANNN ld i",
+        0xB000 => "BNNN jmp v0",
+        0xC000 => "CXNN rnd",
+        0xD000 => "DXYN drw",
+        0xE000 => match opcode & 0x00FF {
+            0x009E => "EX9E skp",
+            0x00A1 => "EXA1 sknp",
+            _ => "unknown",
+        },
+        _ => match opcode & 0x00FF {
+            0x0007 => "FX07 ld vx dt",
+            0x000A => "FX0A wkp",
+            0x0015 => "FX15 ld dt vx",
+            0x0018 => "FX18 ld st vx",
+            0x001E => "FX1E add i vx",
+            0x0029 => "FX29 ld f vx",
+            0x0033 => "FX33 ld b vx",
+            0x0055 => "FX55 ld i vx",
+            0x0065 => "FX65 ld vx i",
+            _ => "unknown",
+        },
+    }
+}
+
+// json_string quotes and escapes a string for json
+fn json_string(s: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_opcode_class() {
+        struct TestCase {
+            opcode: u16,
+            expected: &'static str,
+        }
+
+        let test_cases = [
+            TestCase { opcode: 0x00E0, expected: "00E0 cls" },
+            TestCase { opcode: 0x00EE, expected: "00EE ret" },
+            TestCase { opcode: 0x0123, expected: "0NNN sys" },
+            TestCase { opcode: 0x2300, expected: "2NNN call" },
+            TestCase { opcode: 0x8AB4, expected: "8XY4 add" },
+            TestCase { opcode: 0x8AB8, expected: "unknown" },
+            TestCase { opcode: 0xE29E, expected: "EX9E skp" },
+            TestCase { opcode: 0xF30A, expected: "FX0A wkp" },
+            TestCase { opcode: 0xF3FF, expected: "unknown" },
+        ];
+
+        for case in test_cases.iter() {
+            assert_eq!(opcode_class(case.opcode), case.expected,
+                "Failed on opcode: 0x{:04X}", case.opcode);
+        }
+    }
+
+    #[test]
+    fn test_record() {
+        let mut profiler = Profiler::new();
+
+        // main: call sub; jmp main
+        // sub: add v0 1; ret
+        let program = [
+            (0x200, 0x2204),
+            (0x204, 0x7001),
+            (0x206, 0x00EE),
+            (0x202, 0x1200),
+            (0x200, 0x2204),
+            (0x204, 0x7001),
+            (0x206, 0x00EE),
+        ];
+        for (address, opcode) in program.iter() {
+            profiler.record(*address, *opcode);
+        }
+        profiler.record_frame(None);
+        profiler.record_frame(Some(0x208));
+
+        assert_eq!(profiler.instructions, 7);
+        assert_eq!(profiler.frames, 2);
+        assert_eq!(profiler.hot_spots()[0], (0x200, 2));
+        assert_eq!(profiler.addresses.get(&0x202), Some(&1));
+        assert_eq!(profiler.classes.get("2NNN call"), Some(&2));
+        assert_eq!(profiler.subroutines(),
+            vec![(0x204, Subroutine { calls: 2, instructions: 4 })]);
+        assert_eq!(profiler.key_waits(), vec![(0x208, 1)]);
+    }
+
+    #[test]
+    fn test_report_json() {
+        let mut profiler = Profiler::new();
+        profiler.record(0x200, 0x2204);
+        profiler.record(0x204, 0x00EE);
+        profiler.record_frame(Some(0x202));
+
+        let symbols = Symbols::parse("0x0200 main\n0x0204 sub\n").unwrap();
+
+        let expected = "{\n  \"instructions\": 2,\n  \"frames\": 1,\n  \
+            \"addresses\": [{\"address\": 512, \"name\": \"main\", \"count\": 1}, \
+            {\"address\": 516, \"name\": \"sub\", \"count\": 1}],\n  \
+            \"opcode_classes\": [{\"class\": \"00EE ret\", \"count\": 1}, \
+            {\"class\": \"2NNN call\", \"count\": 1}],\n  \
+            \"subroutines\": [{\"address\": 516, \"name\": \"sub\", \"calls\": 1, \"instructions\": 1}],\n  \
+            \"key_waits\": [{\"address\": 514, \"name\": \"main+2\", \"frames\": 1}]\n}\n";
+
+        assert_eq!(profiler.report_json(&symbols), expected);
+    }
+
+    #[test]
+    fn test_json_string() {
+        assert_eq!(json_string("main"), "\"main\"");
+        assert_eq!(json_string("a\"b\\c\n"), "\"a\\\"b\\\\c\\u000a\"");
+    }
+
+}
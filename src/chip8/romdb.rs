@@ -0,0 +1,117 @@
+// romdb
+//
+// A small offline database of well known ROMs. Programs are identified by the
+// SHA-1 of the file contents, so that `chip8 emulate` can pick the platform,
+// quirks, speed and colours a ROM expects without any flags. Settings given in
+// the config are kept, and emulate --no-romdb turns the lookup off.
+
+use super::chip8::Quirks;
+
+pub const CHIP8: &str = "CHIP-8";
+
+#[derive(Debug)]
+pub struct RomInfo {
+    pub sha1: &'static str,
+    pub title: &'static str,
+    pub author: &'static str,
+    pub platform: &'static str,  // CHIP-8, SUPER-CHIP or XO-CHIP
+    pub quirks: Quirks,
+    pub tickrate: u32,  // instructions executed per 60hz frame
+    pub keys: &'static str,  // human readable description of the controls
+    pub foreground: u32,  // 0xRRGGBB
+    pub background: u32,  // 0xRRGGBB
+}
+
+// To add a ROM, run `sha1sum` on the file and add an entry below.
+const ROMS: &[RomInfo] = &[
+    RomInfo {
+        sha1: "1ba58656810b67fd131eb9af3e3987863bf26c90",
+        title: "IBM Logo",
+        author: "unknown",
+        platform: CHIP8,
+        quirks: Quirks {
+            set_vx_from_vy_in_shift: false,
+            jump_with_vx: false,
+        },
+        tickrate: 30,
+        keys: "none",
+        foreground: 0xFFFFFF,
+        background: 0x000000,
+    },
+];
+
+// sha1_hex returns the lowercase hex SHA-1 digest of a program
+pub fn sha1_hex(program: &[u8]) -> String {
+    sha1_smol::Sha1::from(program).digest().to_string()
+}
+
+// lookup finds a ROM in the database by its SHA-1 digest
+pub fn lookup(sha1: &str) -> Option<&'static RomInfo> {
+    let sha1 = sha1.to_lowercase();
+    ROMS.iter().find(|rom| rom.sha1 == sha1)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sha1_hex() {
+        struct TestCase {
+            name: &'static str,
+            program: &'static [u8],
+            expected: &'static str,
+        }
+
+        let test_cases = [
+            TestCase {
+                name: "Empty program",
+                program: b"",
+                expected: "da39a3ee5e6b4b0d3255bfef95601890afd80709",
+            },
+            TestCase {
+                name: "abc",
+                program: b"abc",
+                expected: "a9993e364706816aba3e25717850c26c9cd0d89d",
+            },
+        ];
+
+        for case in test_cases.iter() {
+            assert_eq!(sha1_hex(case.program), case.expected, "Failed on test case: {}", case.name);
+        }
+    }
+
+    #[test]
+    fn test_lookup() {
+        struct TestCase {
+            name: &'static str,
+            sha1: &'static str,
+            expected: Option<&'static str>,
+        }
+
+        let test_cases = [
+            TestCase {
+                name: "Known rom",
+                sha1: "1ba58656810b67fd131eb9af3e3987863bf26c90",
+                expected: Some("IBM Logo"),
+            },
+            TestCase {
+                name: "Known rom upper case digest",
+                sha1: "1BA58656810B67FD131EB9AF3E3987863BF26C90",
+                expected: Some("IBM Logo"),
+            },
+            TestCase {
+                name: "Unknown rom",
+                sha1: "da39a3ee5e6b4b0d3255bfef95601890afd80709",
+                expected: None,
+            },
+        ];
+
+        for case in test_cases.iter() {
+            let result = lookup(case.sha1).map(|rom| rom.title);
+            assert_eq!(result, case.expected, "Failed on test case: {}", case.name);
+        }
+    }
+
+}
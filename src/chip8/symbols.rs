@@ -0,0 +1,212 @@
+use std::collections::BTreeMap;
+use std::fs;
+
+// Symbols
+// Maps addresses back to assembler labels so reports can show `loop+4`
+// instead of raw addresses. A symbol file has one label or source line per
+// line, as written by `assemble --symbols`:
+//
+//   0x0200 main
+//   0x0200 game.s:3
+//   0x020A loop
+pub struct Symbols {
+    labels: BTreeMap<u16, String>,
+    lines: BTreeMap<u16, String>,  // file:line of the instruction at an address
+}
+
+impl Symbols {
+
+    pub fn new() -> Self {
+        Symbols {
+            labels: BTreeMap::new(),
+            lines: BTreeMap::new(),
+        }
+    }
+
+    pub fn load(path: &str) -> Result<Symbols, String> {
+        let source = match fs::read_to_string(path) {
+            Ok(source) => source,
+            Err(e) => return Err(format!("Error reading symbol file {}: {}", path, e)),
+        };
+        Symbols::parse(&source)
+    }
+
+    pub fn parse(source: &str) -> Result<Symbols, String> {
+        let mut symbols = Symbols::new();
+
+        for (i, line) in source.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            if parts.len() != 2 {
+                return Err(format!(
+                    "Error on line {}: expected an address and a label or file:line", i + 1));
+            }
+
+            let address = match u16::from_str_radix(parts[0].trim_start_matches("0x"), 16) {
+                Ok(address) => address,
+                Err(e) => return Err(format!(
+                    "Error on line {}: error parsing address {}: {}", i + 1, parts[0], e)),
+            };
+
+            // labels can not hold a colon, source lines always do
+            if parts[1].contains(':') {
+                symbols.lines.insert(address, parts[1].to_string());
+            } else {
+                symbols.labels.insert(address, parts[1].to_string());
+            }
+        }
+
+        Ok(symbols)
+    }
+
+    // name returns the closest label at or before an address, with the offset
+    // from it, or the address itself if there is no such label
+    pub fn name(&self, address: u16) -> String {
+        match self.labels.range(..=address).next_back() {
+            Some((&label_address, label)) if label_address == address => label.clone(),
+            Some((&label_address, label)) => format!("{}+{}", label, address - label_address),
+            None => format!("0x{:04X}", address),
+        }
+    }
+
+    // describe names an address like name does, followed by the source line of
+    // the instruction at it if there is one, e.g. `player_move+4 (game.s:120)`
+    pub fn describe(&self, address: u16) -> String {
+        match self.lines.get(&address) {
+            Some(line) => format!("{} ({})", self.name(address), line),
+            None => self.name(address),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty() && self.lines.is_empty()
+    }
+
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        struct TestCase {
+            name: &'static str,
+            source: &'static str,
+            expected: Result<Vec<(u16, &'static str)>, String>,
+        }
+
+        let test_cases = [
+            TestCase {
+                name: "Empty file",
+                source: "",
+                expected: Ok(vec![]),
+            },
+            TestCase {
+                name: "Labels and comments",
+                source: "# symbols\n0x0200 main\n\n0x20A loop\n",
+                expected: Ok(vec![(0x200, "main"), (0x20A, "loop")]),
+            },
+            TestCase {
+                name: "Source lines are not labels",
+                source: "0x0200 main\n0x0200 game.s:3\n0x0202 game.s:4\n",
+                expected: Ok(vec![(0x200, "main")]),
+            },
+            TestCase {
+                name: "Missing label",
+                source: "0x0200 main\n0x0202\n",
+                expected: Err("Error on line 2: expected an address and a label or file:line".into()),
+            },
+            TestCase {
+                name: "Invalid address",
+                source: "main 0x0200\n",
+                expected: Err(
+                    "Error on line 1: error parsing address main: invalid digit found in string".into()),
+            },
+        ];
+
+        for case in test_cases.iter() {
+            let result = Symbols::parse(case.source).map(|symbols| {
+                symbols.labels.into_iter().collect::<Vec<(u16, String)>>()
+            });
+            let expected = case.expected.clone().map(|labels| {
+                labels.into_iter()
+                    .map(|(address, label)| (address, label.to_string()))
+                    .collect::<Vec<(u16, String)>>()
+            });
+            assert_eq!(result, expected, "Failed on test case: {}", case.name);
+        }
+    }
+
+    #[test]
+    fn test_name() {
+        struct TestCase {
+            name: &'static str,
+            address: u16,
+            expected: &'static str,
+        }
+
+        let symbols = Symbols::parse("0x0200 main\n0x020A loop\n").unwrap();
+
+        let test_cases = [
+            TestCase {
+                name: "Before any label",
+                address: 0x1FE,
+                expected: "0x01FE",
+            },
+            TestCase {
+                name: "On a label",
+                address: 0x20A,
+                expected: "loop",
+            },
+            TestCase {
+                name: "After a label",
+                address: 0x204,
+                expected: "main+4",
+            },
+        ];
+
+        for case in test_cases.iter() {
+            assert_eq!(symbols.name(case.address), case.expected, "Failed on test case: {}", case.name);
+        }
+    }
+
+    #[test]
+    fn test_describe() {
+        struct TestCase {
+            name: &'static str,
+            address: u16,
+            expected: &'static str,
+        }
+
+        let symbols = Symbols::parse("0x0200 main\n0x0200 game.s:3\n0x0204 game.s:120\n").unwrap();
+
+        let test_cases = [
+            TestCase {
+                name: "Label and line",
+                address: 0x200,
+                expected: "main (game.s:3)",
+            },
+            TestCase {
+                name: "Offset and line",
+                address: 0x204,
+                expected: "main+4 (game.s:120)",
+            },
+            TestCase {
+                name: "No line",
+                address: 0x206,
+                expected: "main+6",
+            },
+        ];
+
+        for case in test_cases.iter() {
+            assert_eq!(symbols.describe(case.address), case.expected, "Failed on test case: {}", case.name);
+        }
+    }
+
+}
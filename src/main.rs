@@ -9,25 +9,34 @@ fn main() {
 
     if args.len() < 3 {
         eprintln!("Invalid number of arguments");
-        eprintln!("Usage: chip8 <emulate|assemble> <program>");
+        eprintln!("Usage: chip8 <emulate|assemble|lint|fmt> <program> [options]");
         std::process::exit(1);
     }
 
     match args[1].as_str() {
         "assemble" => {
 
-            let source_path = args[2].clone();
-            let mut target_path = "output.ch8".to_string();
-            if  args.len() > 3 {
-                target_path = args[3].clone();
+            let mut assembler_config = assembler::AssemblerConfig::new();
+            assembler_config.source = args[2].clone();
+            if let Err(e) = parse_assemble_options(&args[3..], &mut assembler_config) {
+                eprintln!("{}", e);
+                std::process::exit(1);
             }
-            println!("Assembling program {} to {}", source_path, target_path);
-            match assembler::assemble(source_path, target_path) {
-                Ok(_) => {
+
+            println!("Assembling program {} to {}", assembler_config.source, assembler_config.target);
+            let optimise = assembler_config.optimise;
+            match assembler::assemble(assembler_config) {
+                Ok((warnings, saved)) => {
+                    if !warnings.is_empty() {
+                        eprint!("{}", assembler::report(&warnings));
+                    }
+                    if optimise {
+                        println!("Optimised away {} bytes", saved);
+                    }
                     println!("Assembled successfully");
                 },
-                Err(e) => {
-                    eprintln!("{}", e);
+                Err(diagnostics) => {
+                    eprint!("{}", assembler::report(&diagnostics));
                     std::process::exit(1);
                 }
             }
@@ -36,19 +45,315 @@ fn main() {
             // let mut ass = assembler::Chip8Assembler::new(source_path, target_path);
             // ass.assemble().unwrap()
         },
+        "lint" => {
+            let mut assembler_config = assembler::AssemblerConfig::new();
+            assembler_config.source = args[2].clone();
+            if let Err(e) = parse_lint_options(&args[3..], &mut assembler_config) {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+
+            match assembler::lint(&assembler_config) {
+                Ok(warnings) if warnings.is_empty() => println!("No problems found in {}", assembler_config.source),
+                Ok(warnings) | Err(warnings) => {
+                    eprint!("{}", assembler::report(&warnings));
+                    std::process::exit(1);
+                }
+            }
+        },
+        "fmt" => {
+            let check = args[2..].iter().any(|arg| arg == "--check");
+            let mut failed = false;
+            for file in args[2..].iter().filter(|arg| *arg != "--check") {
+                if file.starts_with("--") {
+                    eprintln!("Unknown option for fmt: {}", file);
+                    std::process::exit(1);
+                }
+                match format_file(file, check) {
+                    Ok(true) if check => {
+                        println!("{} is not formatted", file);
+                        failed = true;
+                    },
+                    Ok(true) => println!("Formatted {}", file),
+                    Ok(false) => {},
+                    Err(e) => {
+                        eprint!("{}", e);
+                        failed = true;
+                    },
+                }
+            }
+            if failed {
+                std::process::exit(1);
+            }
+        },
         "emulate" => {
             println!("Emulating program: {}", args[2]);
             let mut chip8_config = chip8::Chip8Config::new();
             chip8_config.program = args[2].clone();
-            let mut chip8 = chip8::Chip8::new(Some(chip8_config));
+            let options = match parse_emulate_options(&args[3..], &mut chip8_config) {
+                Ok(options) => options,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }
+            };
+
+            let symbols = match &options.symbols {
+                Some(path) => match chip8::Symbols::load(path) {
+                    Ok(symbols) => symbols,
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        std::process::exit(1);
+                    }
+                },
+                None => chip8::Symbols::new(),
+            };
+
+            let mut chip8 = match chip8::Chip8::new(Some(chip8_config)) {
+                Ok(chip8) => chip8,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }
+            };
+            if let Some(rom) = chip8.rom() {
+                println!("Found {} by {} in the rom database", rom.title, rom.author);
+                println!("Controls: {}", rom.keys);
+            }
+            chip8.set_symbols(symbols);
             chip8.log();
-            chip8.run();
+            let result = chip8.run();
+
+            let unknown_opcodes = chip8.unknown_opcodes();
+            if !unknown_opcodes.is_empty() {
+                eprintln!("Unknown opcodes encountered:");
+                for unknown in unknown_opcodes {
+                    eprintln!("  {}{}", unknown, chip8.location(unknown.address));
+                }
+            }
+
+            if let (Some(path), Some(profiler)) = (&options.profile, chip8.profiler()) {
+                let report = if path.ends_with(".json") {
+                    profiler.report_json(chip8.symbols())
+                } else {
+                    profiler.report_text(chip8.symbols())
+                };
+                match std::fs::write(path, report) {
+                    Ok(_) => println!("Wrote profile to {}", path),
+                    Err(e) => eprintln!("Error writing profile {}: {}", path, e),
+                }
+            }
+
+            if let (Some(path), Some(coverage)) = (&options.coverage, chip8.coverage()) {
+                match coverage_report(coverage, path, &options.source) {
+                    Ok(_) => println!("Wrote coverage to {}", path),
+                    Err(e) => eprintln!("{}", e),
+                }
+            }
+
+            if let Err(e) = result {
+                eprintln!("{}", chip8.describe_error(&e));
+                std::process::exit(1);
+            }
 
         },
         _ => {
-            println!("Usage: chip8 <emulate|assemble> <program>");
+            println!("Usage: chip8 <emulate|assemble|lint|fmt> <program>");
             std::process::exit(1);
         }
     }
 
 }
+
+// parse_assemble_options reads the target and options following
+// `assemble <source>`, a source ending in .8o is read as Octo
+//
+//   --full-image  write all 4KiB of memory instead of just the program,
+//                     emulate loads such an image from address 0
+//   -I, --include <dir>  search dir for include, incbin and incpng files
+//   --listing <file>  write a listing of addresses, bytes and source lines
+//   --symbols <file>  write the labels and source lines of the program for
+//                     emulate --symbols
+//   --target <chip8|schip|xochip>  the platform, SUPER-CHIP and XO-CHIP
+//                     instructions are rejected on chip8 (the default)
+//   -D, --define <NAME[=value]>  define a constant for if and ifdef, 1
+//                     when there is no value
+//   -O, --optimise  remove jumps to the next instruction, unreachable code
+//                     and call x; ret pairs, and shorten jmp chains
+fn parse_assemble_options(args: &[String], config: &mut assembler::AssemblerConfig) -> Result<(), String> {
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--full-image" => config.full_image = true,
+            "-I" | "--include" => {
+                config.include_paths.push(option_value(arg, args.next())?.to_string());
+            },
+            "--listing" => config.listing = Some(option_value(arg, args.next())?.to_string()),
+            "--symbols" => config.symbols = Some(option_value(arg, args.next())?.to_string()),
+            "-D" | "--define" => {
+                config.defines.push(assembler::parse_define(option_value(arg, args.next())?));
+            },
+            "-O" | "--optimise" => config.optimise = true,
+            "--target" => config.platform = assembler::Target::get_target(option_value(arg, args.next())?)?,
+            option if option.starts_with("--") => {
+                return Err(format!("Unknown option for assemble: {}", option))
+            },
+            target => config.target = target.to_string(),
+        }
+    }
+    Ok(())
+}
+
+// parse_lint_options reads the options following `lint <source>`
+//
+//   -I, --include <dir>  search dir for include, incbin and incpng files
+//   --target <chip8|schip|xochip>  the platform the program is for, chip8
+//                     (the default) has a 16 level stack and the others 32
+//   -D, --define <NAME[=value]>  define a constant for if and ifdef
+fn parse_lint_options(args: &[String], config: &mut assembler::AssemblerConfig) -> Result<(), String> {
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-I" | "--include" => {
+                config.include_paths.push(option_value(arg, args.next())?.to_string());
+            },
+            "-D" | "--define" => {
+                config.defines.push(assembler::parse_define(option_value(arg, args.next())?));
+            },
+            "--target" => config.platform = assembler::Target::get_target(option_value(arg, args.next())?)?,
+            _ => return Err(format!("Unknown option for lint: {}", arg)),
+        }
+    }
+    Ok(())
+}
+
+// format_file formats an assembler source in place and returns whether it
+// changed, with check it is only compared
+//
+//   chip8 fmt <file>... [--check]
+fn format_file(path: &str, check: bool) -> Result<bool, String> {
+    if path.ends_with(".8o") {
+        return Err(format!("fmt formats assembler sources, {} is Octo\n", path))
+    }
+    let text = std::fs::read_to_string(path).map_err(|e| format!("Error reading {}: {}\n", path, e))?;
+    let formatted = assembler::format_source(path, &text).map_err(|diagnostics| assembler::report(&diagnostics))?;
+    if formatted == text {
+        return Ok(false)
+    }
+    if !check {
+        std::fs::write(path, formatted).map_err(|e| format!("Error writing {}: {}\n", path, e))?;
+    }
+    Ok(true)
+}
+
+// Options for emulate that are handled outside of the emulator
+struct EmulateOptions {
+    symbols: Option<String>,
+    profile: Option<String>,
+    coverage: Option<String>,
+    source: assembler::AssemblerConfig,  // how the program was assembled, for coverage
+}
+
+// parse_emulate_options reads the options following `emulate <program>`
+//
+//   --on-error <halt|wrap|ignore>  what to do when the program hits an error
+//   --unknown-opcode <halt|break|nop|sys>  what to do on an unknown opcode
+//   --no-romdb  do not look the program up in the rom database
+//   --symbols <file>  symbol file used to name addresses in reports and errors
+//   --profile <file>  write a profile on exit, as json if the file ends in .json
+//   --coverage <file>  write coverage of --source on exit, as lcov if the file
+//                      ends in .info and as annotated source otherwise
+//   --source <file>  the assembler source the program was built from
+//   -I, --include <dir>, -D, --define <NAME[=value]>, --target <platform>,
+//   -O, --optimise  the assemble options the source was built with
+fn parse_emulate_options(args: &[String], config: &mut chip8::Chip8Config) -> Result<EmulateOptions, String> {
+    let mut options = EmulateOptions {
+        symbols: None,
+        profile: None,
+        coverage: None,
+        source: assembler::AssemblerConfig::new(),
+    };
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--on-error" => {
+                let value = option_value(arg, args.next())?;
+                config.error_policy = chip8::ErrorPolicy::get_policy(value)?;
+            },
+            "--unknown-opcode" => {
+                let value = option_value(arg, args.next())?;
+                config.unknown_opcode = Some(chip8::UnknownOpcodePolicy::get_policy(value)?);
+            },
+            "--no-romdb" => config.use_rom_database = false,
+            "--symbols" => {
+                options.symbols = Some(option_value(arg, args.next())?.to_string());
+            },
+            "--profile" => {
+                options.profile = Some(option_value(arg, args.next())?.to_string());
+                config.profile = true;
+            },
+            "--coverage" => {
+                options.coverage = Some(option_value(arg, args.next())?.to_string());
+                config.coverage = true;
+            },
+            "--source" => {
+                options.source.source = option_value(arg, args.next())?.to_string();
+            },
+            "-I" | "--include" => {
+                options.source.include_paths.push(option_value(arg, args.next())?.to_string());
+            },
+            "-D" | "--define" => {
+                options.source.defines.push(assembler::parse_define(option_value(arg, args.next())?));
+            },
+            "--target" => options.source.platform = assembler::Target::get_target(option_value(arg, args.next())?)?,
+            "-O" | "--optimise" => options.source.optimise = true,
+            _ => return Err(format!("Unknown option for emulate: {}", arg)),
+        }
+    }
+    if options.coverage.is_some() && options.source.source.is_empty() {
+        return Err("--coverage needs the program source, set it with --source".to_string());
+    }
+
+    Ok(options)
+}
+
+// coverage_report maps coverage back to the assembler source of config and
+// writes it
+fn coverage_report(coverage: &chip8::coverage::Coverage, path: &str, config: &assembler::AssemblerConfig) -> Result<(), String> {
+    let source = config.source.as_str();
+    let lines: Vec<chip8::coverage::SourceLine> = assembler::source_lines(config)
+        .map_err(|diagnostics| assembler::messages(&diagnostics))?
+        .into_iter()
+        // included files are not part of the report
+        .filter(|(_, token)| token.file == source)
+        .map(|(address, token)| chip8::coverage::SourceLine {
+            address,
+            line: token.line,
+            instruction: token.name,
+        })
+        .collect();
+
+    let report = if path.ends_with(".info") {
+        coverage.report_lcov(source, &lines)
+    } else {
+        let text = match std::fs::read_to_string(source) {
+            Ok(text) => text,
+            Err(e) => return Err(format!("Error reading source {}: {}", source, e)),
+        };
+        coverage.report_annotated(&text, &lines)
+    };
+
+    match std::fs::write(path, report) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Error writing coverage {}: {}", path, e)),
+    }
+}
+
+// option_value returns the value following an option
+fn option_value<'a>(option: &str, value: Option<&'a String>) -> Result<&'a str, String> {
+    match value {
+        Some(value) => Ok(value.as_str()),
+        None => Err(format!("Missing value for option {}", option)),
+    }
+}